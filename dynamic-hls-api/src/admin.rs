@@ -0,0 +1,60 @@
+// Probes of the orchestration and the routes of the operators
+use crate::errors;
+use crate::routes::BasePath;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// Liveness probe, the server answers as long as it is up
+pub async fn get_healthz() -> &'static str {
+    "OK"
+}
+
+/// Readiness probe, the server is ready once `BASE_PATH` can be listed. Until the data volume is
+/// mounted every log would be `404 Not Found`.
+pub async fn get_readyz(State(base_path): State<BasePath>) -> (StatusCode, String) {
+    let path = base_path.get();
+    match fs::read_dir(&path) {
+        Ok(_) => (StatusCode::OK, "OK".to_string()),
+        Err(err) => {
+            warn!(
+                "Not ready, `BASE_PATH` {} can't be read: {}",
+                path.display(),
+                err
+            );
+            let message = format!("`BASE_PATH` can't be read: {err}");
+            (StatusCode::SERVICE_UNAVAILABLE, message)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BasePathBody {
+    base_path: PathBuf,
+}
+
+pub async fn get_base_path(State(base_path): State<BasePath>) -> Json<BasePathBody> {
+    Json(BasePathBody {
+        base_path: base_path.get(),
+    })
+}
+
+/// Points the routes to another directory of logs. The requests being served finish with the
+/// old one. Cached segments and lookups are of the logs under the old path, so they aren't used
+/// for the new one.
+pub async fn put_base_path(
+    State(base_path): State<BasePath>,
+    Json(body): Json<BasePathBody>,
+) -> errors::Result<Json<BasePathBody>> {
+    let previous = base_path.replace(body.base_path.clone())?;
+    info!(
+        "`BASE_PATH` is replaced with {}, it was {}",
+        body.base_path.display(),
+        previous.display()
+    );
+    Ok(Json(body))
+}
@@ -0,0 +1,94 @@
+// Bearer token of the `/v1` routes, RFC 6750
+use std::env;
+
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use lazy_static::lazy_static;
+use tracing::{info, warn};
+
+use crate::errors;
+
+lazy_static! {
+    /// Token the requests have to send as `Authorization: Bearer <token>`, anyone can request
+    /// the logs without it
+    static ref API_TOKEN: Option<String> = {
+        match env::var("API_TOKEN") {
+            Ok(v) if !v.is_empty() => {
+                // The token itself stays out of the logs
+                info!("`API_TOKEN` env variable is set, requests need the bearer token");
+                Some(v)
+            }
+            Ok(_) => {
+                warn!("`API_TOKEN` env variable is ignored, it is empty");
+                None
+            }
+            Err(_) => None,
+        }
+    };
+    /// Token of the admin routes, they are off without it
+    static ref ADMIN_TOKEN: Option<String> = {
+        match env::var("ADMIN_TOKEN") {
+            Ok(v) if !v.is_empty() => {
+                info!("`ADMIN_TOKEN` env variable is set, the admin routes are on");
+                Some(v)
+            }
+            Ok(_) => {
+                warn!("`ADMIN_TOKEN` env variable is ignored, it is empty");
+                None
+            }
+            Err(_) => None,
+        }
+    };
+}
+
+/// Whether there is an `ADMIN_TOKEN` for the admin routes
+pub fn admin_enabled() -> bool {
+    ADMIN_TOKEN.is_some()
+}
+
+/// Takes as long whatever bytes differ, so the token can't be guessed byte by byte from the
+/// response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Middleware answering `401 Unauthorized` to the requests without the `API_TOKEN`
+pub async fn require_token(request: Request, next: Next) -> errors::Result<Response> {
+    if let Some(expected) = API_TOKEN.as_deref() {
+        check_bearer_token(&request, expected)?;
+    }
+    Ok(next.run(request).await)
+}
+
+/// Like `require_token` with the `ADMIN_TOKEN`, the requests are refused when there is none
+pub async fn require_admin_token(request: Request, next: Next) -> errors::Result<Response> {
+    let Some(expected) = ADMIN_TOKEN.as_deref() else {
+        Err(errors::ErrorKind::UnauthorizedError(
+            "The admin routes are off".to_string(),
+        ))?
+    };
+    check_bearer_token(&request, expected)?;
+    Ok(next.run(request).await)
+}
+
+fn check_bearer_token(request: &Request, expected: &str) -> errors::Result<()> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        // The scheme is case-insensitive, RFC 9110 section 11.1
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim());
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        Some(_) => Err(errors::ErrorKind::UnauthorizedError(
+            "Invalid bearer token".to_string(),
+        ))?,
+        None => Err(errors::ErrorKind::UnauthorizedError(
+            "Missing bearer token".to_string(),
+        ))?,
+    }
+}
@@ -0,0 +1,190 @@
+// DASH manifest of the fragmented MP4 segments
+use crate::errors;
+use crate::h264::Sps;
+use crate::lookups::get_parameter_sets;
+use crate::mux::{check_mp4_codec, MP4_TIMESCALE, VIDEO_CODEC};
+use crate::playlist::{estimate_bandwidth, split_into_segments, PlaylistSegment};
+use crate::routes::{
+    check_fps, check_segment_length, default_fps, default_segment_length_ms, encode_log_name,
+    frame_positions, frames_to_ms, get_frames, BasePath, LogName,
+};
+use axum::extract::State;
+use axum::http::{header, HeaderName};
+use axum::response::IntoResponse;
+use axum::{debug_handler, extract::Query};
+use serde::Deserialize;
+use tracing::warn;
+
+const MPD_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "application/dash+xml")];
+
+#[derive(Debug, Deserialize)]
+pub struct DashManifestQuery {
+    /// Frame rate of the camera, passed on to the segments
+    #[serde(default = "default_fps")]
+    fps: u32,
+    /// Length of the segments in ms, see `PlaylistQuery::segment_length_ms`
+    #[serde(rename = "segment_length", default = "default_segment_length_ms")]
+    segment_length_ms: usize,
+}
+
+/// `xs:duration` of the MPD, ISO 8601
+fn dash_duration(ms: usize) -> String {
+    format!("PT{}.{:03}S", ms / 1000, ms % 1000)
+}
+
+/// Text in an attribute value of the MPD, XML 1.0 section 2.4
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Static MPD of the fragmented MP4 segments, ISO/IEC 23009-1. The segments are the ones of the
+/// HLS playlist. Dropped frames leave a hole in the media timeline, so every discontinuity of the
+/// playlist starts a new period at the time of its first frame.
+fn dash_manifest(
+    log_name: &str,
+    files: &[String],
+    segments: &[PlaylistSegment],
+    sps: Option<&Sps>,
+    bandwidth: u64,
+    fps: u32,
+) -> String {
+    let positions = frame_positions(files);
+    let duration_ms = frames_to_ms(positions.last().map_or(0, |last| last + 1), fps);
+    let max_segment_ms = segments
+        .iter()
+        .map(|segment| segment.duration_ms)
+        .max()
+        .unwrap_or(0);
+    let frame_time = |frame: usize| frame as u64 * MP4_TIMESCALE as u64 / fps as u64;
+    // Relative to `/v1/manifest.mpd/{log_name}`
+    let log_path = encode_log_name(log_name);
+    let init_url = xml_escape(&format!("../init/{log_path}"));
+    let mut representation = format!("id=\"video\" bandwidth=\"{bandwidth}\" frameRate=\"{fps}\"");
+    if let Some(sps) = sps {
+        representation += format!(
+            " codecs=\"{}\" width=\"{}\" height=\"{}\"",
+            sps.codecs(),
+            sps.width,
+            sps.height
+        )
+        .as_str();
+    }
+
+    let mut mpd = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-main:2011\" \
+type=\"static\" mediaPresentationDuration=\"{}\" minBufferTime=\"{}\">
+",
+        dash_duration(duration_ms),
+        dash_duration(max_segment_ms)
+    );
+    // Consecutive segments without a discontinuity between them
+    let mut periods: Vec<&[PlaylistSegment]> = Vec::new();
+    let mut period_start = 0;
+    for idx in 1..=segments.len() {
+        if idx == segments.len() || segments[idx].discontinuity {
+            periods.push(&segments[period_start..idx]);
+            period_start = idx;
+        }
+    }
+    for (id, period) in periods.into_iter().enumerate() {
+        let first_frame = positions[period[0].offset_frames];
+        // The segments keep the timestamps of the recording, without the dropped frames
+        let start_ms = frames_to_ms(first_frame, fps);
+        mpd += format!(
+            "<Period id=\"{id}\" start=\"{}\">
+<AdaptationSet mimeType=\"video/mp4\" segmentAlignment=\"true\" startWithSAP=\"1\">
+<Representation {representation}>
+<SegmentList timescale=\"{MP4_TIMESCALE}\" presentationTimeOffset=\"{}\">
+<Initialization sourceURL=\"{init_url}\"/>
+<SegmentTimeline>
+",
+            dash_duration(start_ms),
+            frame_time(first_frame)
+        )
+        .as_str();
+        for segment in period {
+            // A gap ends a segment, there are no dropped frames within the segments
+            let (offset_frames, frames) = (segment.offset_frames, segment.frames);
+            let start = frame_time(positions[offset_frames]);
+            let duration = frame_time(positions[offset_frames] + frames) - start;
+            mpd += format!("<S t=\"{start}\" d=\"{duration}\"/>\n").as_str();
+        }
+        mpd += "</SegmentTimeline>\n";
+        for segment in period {
+            let url = format!(
+                "../segment/{log_path}?offset={}&length={}&fps={fps}&video_type=FragmentedMp4",
+                segment.offset_ms, segment.length_ms
+            );
+            mpd += format!("<SegmentURL media=\"{}\"/>\n", xml_escape(&url)).as_str();
+        }
+        mpd += "</SegmentList>\n</Representation>\n</AdaptationSet>\n</Period>\n";
+    }
+    mpd += "</MPD>\n";
+    mpd
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_dash_manifest(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<DashManifestQuery>,
+) -> errors::Result<impl IntoResponse> {
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    check_fps(query.fps)?;
+    check_mp4_codec()?;
+    check_segment_length(query.segment_length_ms)?;
+    let manifest = tokio::task::spawn_blocking(move || {
+        let files = get_frames(&path_to_h264_frames)?;
+        let segments = split_into_segments(&files, query.segment_length_ms, query.fps, None);
+
+        let sps = match get_parameter_sets(&log_name, &path_to_h264_frames) {
+            Ok(params) => Sps::parse_for(*VIDEO_CODEC, &params.sps),
+            Err(err) => {
+                warn!("MPD of {log_name} is without codecs, width and height: {err}");
+                None
+            }
+        };
+        // Of the MPEG-TS segments, fragmented MP4 ones are a bit smaller
+        let bandwidth = estimate_bandwidth(&path_to_h264_frames, &files, &segments)?;
+        Ok::<_, errors::AppError>(dash_manifest(
+            &log_name,
+            &files,
+            &segments,
+            sps.as_ref(),
+            bandwidth,
+            query.fps,
+        ))
+    })
+    .await??;
+    Ok((MPD_CONTENT_TYPE, manifest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_names_are_escaped_in_the_urls() {
+        let files: Vec<String> = (0..20).map(|idx| format!("{idx}.ts")).collect();
+        let segments = split_into_segments(&files, 1000, 20, None);
+        let mpd = dash_manifest("cam 1&<\"'#", &files, &segments, None, 1000, 20);
+        assert!(mpd.contains("<Initialization sourceURL=\"../init/cam%201%26%3C%22%27%23\"/>"));
+        assert!(mpd.contains(
+            "<SegmentURL media=\"../segment/cam%201%26%3C%22%27%23?offset=0&amp;length=1000\
+&amp;fps=20&amp;video_type=FragmentedMp4\"/>"
+        ));
+    }
+}
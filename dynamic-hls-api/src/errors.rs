@@ -1,5 +1,8 @@
 use crate::mpegts;
-use axum::http::StatusCode;
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::{Deserialize, Serialize};
@@ -11,6 +14,7 @@ pub struct AppError(Box<ErrorKind>);
 
 #[derive(Error, Debug)]
 #[error(transparent)]
+#[allow(clippy::enum_variant_names)]
 pub enum ErrorKind {
     #[error("SerdeJsonError: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
@@ -20,6 +24,22 @@ pub enum ErrorKind {
     Mp4Error(#[from] mp4::Error),
     #[error("TsError: {0}")]
     TsError(#[from] mpegts::TsError),
+    #[error("BadRequestError: {0}")]
+    BadRequestError(String),
+    #[error("SegmentOutOfRangeError: {0}")]
+    SegmentOutOfRangeError(String),
+    #[error("UnauthorizedError: {0}")]
+    UnauthorizedError(String),
+    #[error("NotFoundError: {0}")]
+    NotFoundError(String),
+    #[error("NoMediaError: {0}")]
+    NoMediaError(String),
+    #[error("JoinError: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+    #[error("TimeoutError: {0}")]
+    TimeoutError(String),
+    #[error("PreviewError: {0}")]
+    PreviewError(String),
 }
 
 impl<E> From<E> for AppError
@@ -38,11 +58,23 @@ impl AppError {
             ErrorKind::IoError(_) => (StatusCode::BAD_REQUEST, 40002),
             ErrorKind::Mp4Error(_) => (StatusCode::BAD_REQUEST, 40003),
             ErrorKind::TsError(_) => (StatusCode::BAD_REQUEST, 40004),
+            ErrorKind::BadRequestError(_) => (StatusCode::BAD_REQUEST, 40005),
+            ErrorKind::SegmentOutOfRangeError(_) => (StatusCode::BAD_REQUEST, 40006),
+            ErrorKind::UnauthorizedError(_) => (StatusCode::UNAUTHORIZED, 40101),
+            ErrorKind::NotFoundError(_) => (StatusCode::NOT_FOUND, 40401),
+            // The log is there, without frames yet or anymore
+            ErrorKind::NoMediaError(_) => (StatusCode::NOT_FOUND, 40402),
+            ErrorKind::JoinError(_) => (StatusCode::INTERNAL_SERVER_ERROR, 50001),
+            ErrorKind::TimeoutError(_) => (StatusCode::GATEWAY_TIMEOUT, 50401),
+            ErrorKind::PreviewError(_) => (StatusCode::INTERNAL_SERVER_ERROR, 50002),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Numeric code of the error, it is there whatever the format of the body
+pub const ERROR_CODE_HEADER: HeaderName = HeaderName::from_static("x-error-code");
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ErrorCode {
     pub code: u16,
     pub message: String,
@@ -52,8 +84,61 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status_code, code) = self.get_codes();
         let message = self.to_string();
-        let body = Json(ErrorCode { code, message });
-        (status_code, body).into_response()
+        let error = ErrorCode { code, message };
+        let code_header = [(ERROR_CODE_HEADER, HeaderValue::from(code))];
+        let mut response = if status_code == StatusCode::UNAUTHORIZED {
+            // The scheme the client has to authenticate with, RFC 9110 section 11.6.1
+            let challenge = [(header::WWW_AUTHENTICATE, "Bearer")];
+            (status_code, code_header, challenge, Json(error.clone())).into_response()
+        } else {
+            (status_code, code_header, Json(error.clone())).into_response()
+        };
+        // For `negotiate_error_body`
+        response.extensions_mut().insert(error);
+        response
+    }
+}
+
+/// Whether the client takes a JSON body, the ones without `Accept` or with a wildcard do
+fn accepts_json(headers: &HeaderMap) -> bool {
+    let mut media_ranges = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|range| {
+            range
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .filter(|range| !range.is_empty())
+        .peekable();
+    if media_ranges.peek().is_none() {
+        return true;
+    }
+    media_ranges.any(|range| matches!(range.as_str(), "application/json" | "application/*" | "*/*"))
+}
+
+/// Middleware answering the errors with the message in plain text when the client doesn't take
+/// JSON, players expecting video choke on a JSON body. The code stays in `x-error-code`.
+pub async fn negotiate_error_body(request: Request, next: Next) -> Response {
+    let accepts_json = accepts_json(request.headers());
+    let mut response = next.run(request).await;
+    let Some(error) = response.extensions_mut().remove::<ErrorCode>() else {
+        return response;
+    };
+    if accepts_json {
+        return response;
     }
+    let (mut parts, _) = response.into_parts();
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(error.message))
 }
 pub type Result<T> = std::result::Result<T, AppError>;
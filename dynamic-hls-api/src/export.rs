@@ -0,0 +1,130 @@
+// Offline export of a whole recording as numbered TS segments and a VOD playlist
+use crate::errors;
+use crate::lookups::get_keyframes;
+use crate::mux;
+use crate::playlist::{split_into_segments, PlaylistSegment};
+use crate::routes::{self, frame_positions};
+use std::fs;
+use tracing::info;
+
+const PLAYLIST_FILE_NAME: &str = "playlist.m3u8";
+
+/// Writes `0.ts`, `1.ts`, ... and `playlist.m3u8` to `output_dir`. A segment is cut at the first
+/// keyframe after `segment_duration_ms`, so every segment but the first starts with a keyframe.
+/// Gaps cut the segments too and start a discontinuity, like in the playlists of the server.
+pub fn export(
+    path_to_h264_frames: &str,
+    output_dir: &str,
+    segment_duration_ms: usize,
+    fps: u32,
+) -> errors::Result<()> {
+    let files = routes::get_frames(path_to_h264_frames)?;
+    fs::create_dir_all(output_dir)?;
+    info!(
+        "Exporting {} frames from {path_to_h264_frames} to {output_dir}",
+        files.len()
+    );
+
+    let keyframes = get_keyframes(path_to_h264_frames)?;
+    let segments = split_into_segments(&files, segment_duration_ms, fps, Some(&keyframes));
+    // Timestamps continue across the segments and skip the dropped frames, like the server does
+    let positions = frame_positions(&files);
+    for (idx, segment) in segments.iter().enumerate() {
+        let end = segment.offset_frames + segment.frames;
+        let ts = mux::h264streams_to_mpegts(
+            path_to_h264_frames,
+            &files[segment.offset_frames..end],
+            fps,
+            positions[segment.offset_frames] as u64,
+            segment.discontinuity,
+            false,
+        )?;
+        fs::write(format!("{}/{}.ts", output_dir, idx), ts)?;
+        info!("Exported segment {idx}, {end}/{} frames", files.len());
+    }
+
+    fs::write(
+        format!("{}/{}", output_dir, PLAYLIST_FILE_NAME),
+        vod_playlist(&segments),
+    )?;
+    info!(
+        "Exported {} segments and {PLAYLIST_FILE_NAME} to {output_dir}",
+        segments.len()
+    );
+    Ok(())
+}
+
+/// Playlist of the exported segments, they are referred relative to it
+fn vod_playlist(segments: &[PlaylistSegment]) -> String {
+    let target_duration_secs = segments
+        .iter()
+        .map(|segment| segment.duration_ms.div_ceil(1000))
+        .max()
+        .unwrap_or(0);
+
+    let mut playlist = "#EXTM3U\n#EXT-X-VERSION:3\n".to_string();
+    playlist += format!("#EXT-X-TARGETDURATION:{target_duration_secs}\n").as_str();
+    playlist += "#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MEDIA-SEQUENCE:0\n";
+    for (idx, segment) in segments.iter().enumerate() {
+        if segment.discontinuity {
+            playlist += "#EXT-X-DISCONTINUITY\n";
+        }
+        let duration_secs = segment.duration_ms as f64 / 1000.0;
+        playlist += format!("#EXTINF:{duration_secs:.3},\n{idx}.ts\n").as_str();
+    }
+    playlist += "#EXT-X-ENDLIST\n";
+    playlist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::tests::write_log;
+    use mpeg2ts::ts::{ReadTsPacket, TsPacketReader, TsPayload};
+
+    /// DTS of the first frame of the segment in 90 kHz
+    fn first_dts(segment: Vec<u8>) -> u64 {
+        let mut reader = TsPacketReader::new(segment.as_slice());
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pes(pes)) = packet.payload {
+                return pes.header.dts.or(pes.header.pts).unwrap().as_u64();
+            }
+        }
+        panic!("No PES in the segment");
+    }
+
+    #[test]
+    fn segments_after_a_gap_start_a_discontinuity_at_their_time() {
+        // Keyframes at 0, 5, 15 and 20, the frames 10 to 14 are dropped
+        let base_path = write_log("export", (0..10).chain(15..25), 100);
+        let output_dir = base_path.get().join("out").display().to_string();
+        export(&base_path.log_path("export"), &output_dir, 250, 20).unwrap();
+
+        let playlist = fs::read_to_string(format!("{output_dir}/{PLAYLIST_FILE_NAME}")).unwrap();
+        let segments: Vec<&str> = playlist
+            .lines()
+            .filter(|line| line.starts_with("#EXTINF") || line.starts_with("#EXT-X-DISC"))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                "#EXTINF:0.250,",
+                "#EXTINF:0.250,",
+                "#EXT-X-DISCONTINUITY",
+                "#EXTINF:0.250,",
+                "#EXTINF:0.250,",
+            ]
+        );
+        // 50 ms frames, the segment after the gap starts at the frame 15
+        let dts: Vec<u64> = (0..4)
+            .map(|idx| first_dts(fs::read(format!("{output_dir}/{idx}.ts")).unwrap()))
+            .collect();
+        assert_eq!(
+            dts.iter()
+                .map(|frame_dts| frame_dts - dts[0])
+                .collect::<Vec<_>>(),
+            [0, 5, 15, 20].map(|frame| frame * 4500)
+        );
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+// Runs ffmpeg on muxed frames, for the previews the service doesn't decode itself. The service
+// has no H264 decoder of its own, so `/v1/thumbnail` and `/v1/gif` need an `ffmpeg` executable
+// at runtime, on the `PATH` or at `FFMPEG_PATH`. Without it they fail, the other routes don't
+// need it.
+use std::env;
+use std::io;
+use std::process::Stdio;
+
+use lazy_static::lazy_static;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::errors;
+
+const DEFAULT_FFMPEG_PATH: &str = "ffmpeg";
+
+lazy_static! {
+    static ref FFMPEG_PATH: String = {
+        match env::var("FFMPEG_PATH") {
+            Ok(p) => {
+                info!("`FFMPEG_PATH` env variable is set to {}", p);
+                p
+            }
+            Err(_) => DEFAULT_FFMPEG_PATH.to_string(),
+        }
+    };
+}
+
+/// Warns at startup when `ffmpeg` can't be run, rather than on the first preview
+pub async fn check_available() {
+    let status = Command::new(FFMPEG_PATH.as_str())
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => info!("Previews are made with {}", *FFMPEG_PATH),
+        Ok(status) => warn!("{} -version failed with {status}", *FFMPEG_PATH),
+        Err(err) => warn!(
+            "Failed to run {}, `/v1/thumbnail` and `/v1/gif` need ffmpeg: {err}",
+            *FFMPEG_PATH
+        ),
+    }
+}
+
+/// Pipes the MPEG-TS to ffmpeg with the output `args` and returns what it writes to stdout.
+/// ffmpeg is killed when the request is dropped, the request timeout bounds it.
+pub async fn run(ts: Vec<u8>, args: &[&str]) -> errors::Result<Vec<u8>> {
+    let mut child = Command::new(FFMPEG_PATH.as_str())
+        .args([
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-f",
+            "mpegts",
+            "-i",
+            "pipe:0",
+        ])
+        .args(args)
+        .arg("pipe:1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => errors::ErrorKind::PreviewError(format!(
+                "{} is not installed, previews need ffmpeg on the `PATH` or at `FFMPEG_PATH`",
+                *FFMPEG_PATH
+            )),
+            _ => errors::ErrorKind::PreviewError(format!("Failed to run {}: {err}", *FFMPEG_PATH)),
+        })?;
+    // Written while the output is read, ffmpeg stops reading once its stdout pipe is full
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let write_ts = async move {
+        // ffmpeg closes stdin early after `-frames:v`, the output is there regardless
+        let _ = stdin.write_all(&ts).await;
+    };
+    let (_, output) = tokio::join!(write_ts, child.wait_with_output());
+    let output = output?;
+    if !output.status.success() {
+        Err(errors::ErrorKind::PreviewError(format!(
+            "ffmpeg failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))?
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,134 @@
+// Animated GIF previews of a part of a log, ffmpeg decodes the frames and encodes the GIF. ffmpeg
+// must be installed where the service runs, see `ffmpeg.rs`.
+use crate::errors;
+use crate::ffmpeg;
+use crate::mux;
+use crate::routes::{
+    check_fps, content_length, default_fps, get_frames, ms_to_frames, BasePath, LogName,
+};
+use axum::debug_handler;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderName};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use tracing::debug;
+
+/// Longest part of a log a preview is made of
+pub const MAX_GIF_LENGTH_MS: usize = 30_000;
+/// Frames of the previews per second, the frames in between are dropped
+pub const DEFAULT_GIF_FPS: u32 = 5;
+pub const MAX_GIF_FPS: u32 = 10;
+/// Frames of a preview, `MAX_GIF_LENGTH_MS` at `MAX_GIF_FPS` is cut to it
+const MAX_GIF_FRAMES: u32 = 150;
+/// Wider frames are scaled down to it, the height keeps the aspect ratio
+const MAX_GIF_WIDTH: u32 = 480;
+
+/// A palette of the preview's own colors, GIF has 256 of them
+fn filter_graph(gif_fps: u32) -> String {
+    format!(
+        "fps={gif_fps},scale='min({MAX_GIF_WIDTH},iw)':-2:flags=lanczos,\
+         split[frames][palette_frames];[palette_frames]palettegen[palette];\
+         [frames][palette]paletteuse"
+    )
+}
+
+/// Muxes the frames to MPEG-TS for ffmpeg and returns the GIF it makes of them
+pub async fn preview(
+    path_to_h264_frames: String,
+    frame_files: Vec<String>,
+    fps: u32,
+    gif_fps: u32,
+) -> errors::Result<Vec<u8>> {
+    let frames = frame_files.len();
+    let ts = tokio::task::spawn_blocking(move || {
+        mux::h264streams_to_mpegts(&path_to_h264_frames, &frame_files, fps, 0, false, true)
+    })
+    .await??;
+
+    let max_frames = MAX_GIF_FRAMES.to_string();
+    let gif = ffmpeg::run(
+        ts,
+        &[
+            "-vf",
+            &filter_graph(gif_fps),
+            "-frames:v",
+            &max_frames,
+            "-loop",
+            "0",
+            "-f",
+            "gif",
+        ],
+    )
+    .await?;
+    debug!("Made a GIF of {} bytes of {} frames", gif.len(), frames);
+    Ok(gif)
+}
+
+const GIF_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "image/gif")];
+
+#[derive(Debug, Deserialize)]
+pub struct GifQuery {
+    #[serde(rename = "offset")]
+    offset_ms: usize,
+    #[serde(rename = "length")]
+    length_ms: usize,
+    /// Frame rate of the GIF, up to `MAX_GIF_FPS`
+    #[serde(default = "default_gif_fps")]
+    fps: u32,
+    /// Frame rate of the camera, `fps` of the segments
+    #[serde(default = "default_fps")]
+    camera_fps: u32,
+}
+
+fn default_gif_fps() -> u32 {
+    DEFAULT_GIF_FPS
+}
+
+/// Animated preview of `length` ms of the log from `offset`, for a quick look at a log
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_gif(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<GifQuery>,
+) -> errors::Result<impl IntoResponse> {
+    check_fps(query.camera_fps)?;
+    if !(1..=MAX_GIF_FPS).contains(&query.fps) {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "`fps` must be between 1 and {}, got {}",
+            MAX_GIF_FPS, query.fps
+        )))?
+    }
+    if query.length_ms > MAX_GIF_LENGTH_MS {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "`length` must be at most {} ms, got {}",
+            MAX_GIF_LENGTH_MS, query.length_ms
+        )))?
+    }
+    let offset_frames = ms_to_frames(query.offset_ms, query.camera_fps)?;
+    let frames = ms_to_frames(query.length_ms, query.camera_fps)?;
+    if frames == 0 {
+        Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+            "`length` {} ms is less than a frame at {} fps",
+            query.length_ms, query.camera_fps
+        )))?
+    }
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    let path = path_to_h264_frames.clone();
+    let files = tokio::task::spawn_blocking(move || get_frames(&path)).await??;
+    if offset_frames >= files.len() {
+        Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+            "Preview starts at frame {offset_frames}, {log_name} has {} frames",
+            files.len()
+        )))?
+    }
+    let frame_files = files.into_iter().skip(offset_frames).take(frames).collect();
+    let gif = preview(
+        path_to_h264_frames,
+        frame_files,
+        query.camera_fps,
+        query.fps,
+    )
+    .await?;
+    Ok((GIF_CONTENT_TYPE, content_length(gif.len()), gif))
+}
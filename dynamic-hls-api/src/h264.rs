@@ -0,0 +1,867 @@
+// SPS and SEI parsing, just enough to describe and mux the camera frames
+use h264_util::nal::to_rbsp;
+pub use h264_util::nal::{nal_unit_type, nal_units, NalUnitType, VideoCodec};
+use serde::Serialize;
+
+/// `user_data_registered_itu_t_t35` SEI payload type, ITU-T H.264 section D.1
+const SEI_USER_DATA_REGISTERED_ITU_T_T35: u32 = 4;
+
+/// ATSC A/53 `cc_data` header: USA country code, ATSC provider code, `GA94` user identifier
+/// and `user_data_type_code` 3
+const ATSC_CC_DATA_HEADER: [u8; 8] = [0xB5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+
+/// Whether the SEI NAL unit carries CEA-608/708 closed captions, `nal` starts with the NAL
+/// header byte
+pub fn is_caption_sei(nal: &[u8]) -> bool {
+    if nal_unit_type(nal) != NalUnitType::Sei {
+        return false;
+    }
+    let rbsp = to_rbsp(&nal[1..]);
+    let mut data = rbsp.as_slice();
+    // sei_message() until rbsp_trailing_bits()
+    while data.first().is_some_and(|b| *b != 0x80) {
+        let Some(payload_type) = read_sei_value(&mut data) else {
+            return false;
+        };
+        let Some(payload_size) = read_sei_value(&mut data) else {
+            return false;
+        };
+        let Some(payload) = data.get(..payload_size as usize) else {
+            return false;
+        };
+        if payload_type == SEI_USER_DATA_REGISTERED_ITU_T_T35
+            && payload.starts_with(&ATSC_CC_DATA_HEADER)
+        {
+            return true;
+        }
+        data = &data[payload.len()..];
+    }
+    false
+}
+
+/// SEI payload type and size are coded as a run of 0xFF bytes plus the last byte
+fn read_sei_value(data: &mut &[u8]) -> Option<u32> {
+    let mut value = 0;
+    loop {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value += *byte as u32;
+        if *byte != 0xFF {
+            return Some(value);
+        }
+    }
+}
+
+/// Whether the frame is NAL units each after its 4 bytes size, ISO/IEC 14496-15 section 5.3.2.
+/// The sizes have to add up to the frame exactly.
+fn is_length_prefixed(frame: &[u8]) -> bool {
+    let mut rest = frame;
+    while let Some((size, tail)) = rest.split_first_chunk::<4>() {
+        let size = u32::from_be_bytes(*size) as usize;
+        if size == 0 || size > tail.len() {
+            return false;
+        }
+        rest = &tail[size..];
+    }
+    !frame.is_empty() && rest.is_empty()
+}
+
+/// Whether the frame starts with a start code and a NAL unit header after it. The size of a
+/// length-prefixed NAL unit of 256 to 511 bytes is `00 00 01 xx` too, so a frame whose sizes
+/// add up to it is taken for length-prefixed.
+fn is_annex_b(frame: &[u8]) -> bool {
+    let header = match frame {
+        [0, 0, 0, 1, header, ..] | [0, 0, 1, header, ..] => *header,
+        _ => return false,
+    };
+    // forbidden_zero_bit and a `nal_unit_type` of ITU-T H.264 table 7-1
+    header & 0x80 == 0 && matches!(header & 0x1F, 1..=23) && !is_length_prefixed(frame)
+}
+
+/// Rewrites the Annex B frame as an MP4 sample, every NAL unit gets its 4 bytes size instead of
+/// the start code, ISO/IEC 14496-15 section 5.3.2. SPS and PPS are not kept, they are in
+/// `avcC`. Frames that are length-prefixed already are copied as they are.
+pub fn to_avc_sample(frame: &[u8]) -> Vec<u8> {
+    if !is_annex_b(frame) {
+        return frame.to_vec();
+    }
+    let mut sample = Vec::with_capacity(frame.len());
+    for nal in nal_units(frame) {
+        if matches!(nal_unit_type(nal), NalUnitType::Sps | NalUnitType::Pps) {
+            continue;
+        }
+        sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        sample.extend_from_slice(nal);
+    }
+    sample
+}
+
+/// Reads Exp-Golomb coded fields, ITU-T H.264 section 9.1
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some(value)
+    }
+
+    fn read_flag(&mut self) -> Option<bool> {
+        Some(self.read_bit()? == 1)
+    }
+
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    fn read_se(&mut self) -> Option<i32> {
+        let k = self.read_ue()? as i64;
+        let value = if k % 2 == 0 { -(k / 2) } else { (k + 1) / 2 };
+        Some(value as i32)
+    }
+}
+
+/// Fields of the sequence parameter set, ITU-T H.264 section 7.3.2.1.1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_set_flags: u8,
+    pub level_idc: u8,
+    pub width: u16,
+    pub height: u16,
+    /// Sample aspect ratio as `(horizontal, vertical)`, 1:1 when VUI doesn't say otherwise
+    pub sample_aspect_ratio: (u16, u16),
+    /// Fields needed to read the slice headers
+    #[serde(skip)]
+    pub slice_header: SliceHeaderFields,
+    /// Profile, tier and level of HEVC SPS, `profile_idc` and `level_idc` are the general ones
+    #[serde(skip)]
+    pub hevc_profile: Option<HevcProfile>,
+}
+
+/// General `profile_tier_level()` fields of HEVC SPS, ITU-T H.265 section 7.3.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HevcProfile {
+    pub profile_space: u8,
+    pub tier_flag: bool,
+    pub profile_compatibility_flags: u32,
+    /// `general_progressive_source_flag` up to `general_inbld_flag`, 48 bits
+    pub constraint_indicator_flags: [u8; 6],
+}
+
+/// Fields of the SPS the slice header syntax depends on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SliceHeaderFields {
+    pub separate_colour_plane: bool,
+    pub log2_max_frame_num: u32,
+    pub frame_mbs_only: bool,
+    pub pic_order_cnt_type: u32,
+    /// Only used with `pic_order_cnt_type` 0
+    pub log2_max_pic_order_cnt_lsb: u32,
+}
+
+impl Sps {
+    /// `CODECS` attribute value of HLS playlists, RFC 6381 section 3.3, `hvc1` ones are
+    /// ISO/IEC 14496-15 Annex E.3
+    pub fn codecs(&self) -> String {
+        let Some(hevc) = &self.hevc_profile else {
+            return format!(
+                "avc1.{:02X}{:02X}{:02X}",
+                self.profile_idc, self.constraint_set_flags, self.level_idc
+            );
+        };
+        let profile_space = ["", "A", "B", "C"][hevc.profile_space as usize & 3];
+        let tier = if hevc.tier_flag { 'H' } else { 'L' };
+        let mut codecs = format!(
+            "hvc1.{profile_space}{}.{:X}.{tier}{}",
+            self.profile_idc,
+            // In reverse bit order
+            hevc.profile_compatibility_flags.reverse_bits(),
+            self.level_idc
+        );
+        // Trailing bytes of zeros are left out
+        let constraints = hevc
+            .constraint_indicator_flags
+            .iter()
+            .rposition(|b| *b != 0)
+            .map_or(0, |pos| pos + 1);
+        for b in &hevc.constraint_indicator_flags[..constraints] {
+            codecs += format!(".{b:X}").as_str();
+        }
+        codecs
+    }
+
+    /// Parses SPS NAL unit, `nal` starts with the NAL header byte
+    pub fn parse(nal: &[u8]) -> Option<Sps> {
+        if nal_unit_type(nal) != NalUnitType::Sps {
+            return None;
+        }
+        let rbsp = to_rbsp(&nal[1..]);
+        let mut r = BitReader::new(&rbsp);
+
+        let profile_idc = r.read_bits(8)? as u8;
+        let constraint_set_flags = r.read_bits(8)? as u8;
+        let level_idc = r.read_bits(8)? as u8;
+        let _seq_parameter_set_id = r.read_ue()?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = r.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.read_flag()?;
+            }
+            let _bit_depth_luma_minus8 = r.read_ue()?;
+            let _bit_depth_chroma_minus8 = r.read_ue()?;
+            let _qpprime_y_zero_transform_bypass = r.read_flag()?;
+            if r.read_flag()? {
+                let scaling_lists = if chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..scaling_lists {
+                    if r.read_flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let log2_max_frame_num = r.read_ue()? + 4;
+        let pic_order_cnt_type = r.read_ue()?;
+        let mut log2_max_pic_order_cnt_lsb = 0;
+        match pic_order_cnt_type {
+            0 => {
+                log2_max_pic_order_cnt_lsb = r.read_ue()? + 4;
+            }
+            1 => {
+                let _delta_pic_order_always_zero = r.read_flag()?;
+                let _offset_for_non_ref_pic = r.read_se()?;
+                let _offset_for_top_to_bottom_field = r.read_se()?;
+                for _ in 0..r.read_ue()? {
+                    let _offset_for_ref_frame = r.read_se()?;
+                }
+            }
+            _ => {}
+        }
+        let _max_num_ref_frames = r.read_ue()?;
+        let _gaps_in_frame_num_value_allowed = r.read_flag()?;
+        let pic_width_in_mbs_minus1 = r.read_ue()?;
+        let pic_height_in_map_units_minus1 = r.read_ue()?;
+        let frame_mbs_only = r.read_flag()?;
+        if !frame_mbs_only {
+            let _mb_adaptive_frame_field = r.read_flag()?;
+        }
+        let _direct_8x8_inference = r.read_flag()?;
+        let (crop_left, crop_right, crop_top, crop_bottom) = if r.read_flag()? {
+            (r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?)
+        } else {
+            (0, 0, 0, 0)
+        };
+
+        // Table 6-1, cropping is in chroma sample units
+        let chroma_array_type = if separate_colour_plane {
+            0
+        } else {
+            chroma_format_idc
+        };
+        let frame_height_factor = 2 - frame_mbs_only as u32;
+        let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+            0 => (1, frame_height_factor),
+            1 => (2, 2 * frame_height_factor),
+            2 => (2, frame_height_factor),
+            _ => (1, frame_height_factor),
+        };
+
+        let mut sample_aspect_ratio = (1, 1);
+        let vui_parameters_present = r.read_flag()?;
+        if vui_parameters_present && r.read_flag()? {
+            sample_aspect_ratio = match r.read_bits(8)? {
+                // Extended_SAR
+                255 => (r.read_bits(16)? as u16, r.read_bits(16)? as u16),
+                aspect_ratio_idc => sample_aspect_ratio_from_idc(aspect_ratio_idc),
+            };
+        }
+
+        let width = (pic_width_in_mbs_minus1 + 1) * 16;
+        let height = frame_height_factor * (pic_height_in_map_units_minus1 + 1) * 16;
+        let width = width.checked_sub(crop_unit_x * (crop_left + crop_right))?;
+        let height = height.checked_sub(crop_unit_y * (crop_top + crop_bottom))?;
+
+        Some(Sps {
+            profile_idc,
+            constraint_set_flags,
+            level_idc,
+            width: u16::try_from(width).ok()?,
+            height: u16::try_from(height).ok()?,
+            sample_aspect_ratio,
+            slice_header: SliceHeaderFields {
+                separate_colour_plane,
+                log2_max_frame_num,
+                frame_mbs_only,
+                pic_order_cnt_type,
+                log2_max_pic_order_cnt_lsb,
+            },
+            hevc_profile: None,
+        })
+    }
+
+    /// Parses HEVC SPS NAL unit, ITU-T H.265 section 7.3.2.2, `nal` starts with the 2 bytes of
+    /// NAL header. VUI is not read, the sample aspect ratio is 1:1 and the slice header fields
+    /// are left unset.
+    pub fn parse_hevc(nal: &[u8]) -> Option<Sps> {
+        if VideoCodec::Hevc.nal_unit_type(nal) != NalUnitType::Sps {
+            return None;
+        }
+        let rbsp = to_rbsp(nal.get(2..)?);
+        let mut r = BitReader::new(&rbsp);
+
+        let _sps_video_parameter_set_id = r.read_bits(4)?;
+        let max_sub_layers_minus1 = r.read_bits(3)?;
+        let _temporal_id_nesting = r.read_flag()?;
+
+        let profile_space = r.read_bits(2)? as u8;
+        let tier_flag = r.read_flag()?;
+        let profile_idc = r.read_bits(5)? as u8;
+        let profile_compatibility_flags = r.read_bits(32)?;
+        let mut constraint_indicator_flags = [0; 6];
+        for b in constraint_indicator_flags.iter_mut() {
+            *b = r.read_bits(8)? as u8;
+        }
+        let level_idc = r.read_bits(8)? as u8;
+        let mut sub_layers = Vec::new();
+        for _ in 0..max_sub_layers_minus1 {
+            // sub_layer_profile_present_flag and sub_layer_level_present_flag
+            sub_layers.push((r.read_flag()?, r.read_flag()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            for _ in max_sub_layers_minus1..8 {
+                let _reserved_zero_2bits = r.read_bits(2)?;
+            }
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present {
+                // Profile space up to the constraint flags, like the general ones above
+                for _ in 0..11 {
+                    r.read_bits(8)?;
+                }
+            }
+            if level_present {
+                let _sub_layer_level_idc = r.read_bits(8)?;
+            }
+        }
+
+        let _sps_seq_parameter_set_id = r.read_ue()?;
+        let chroma_format_idc = r.read_ue()?;
+        let separate_colour_plane = chroma_format_idc == 3 && r.read_flag()?;
+        let width = r.read_ue()?;
+        let height = r.read_ue()?;
+        let (crop_left, crop_right, crop_top, crop_bottom) = if r.read_flag()? {
+            (r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?)
+        } else {
+            (0, 0, 0, 0)
+        };
+        // Table 6-1, the conformance window is in chroma sample units
+        let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+            _ if separate_colour_plane => (1, 1),
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let width = width.checked_sub(crop_unit_x * (crop_left + crop_right))?;
+        let height = height.checked_sub(crop_unit_y * (crop_top + crop_bottom))?;
+
+        Some(Sps {
+            profile_idc,
+            constraint_set_flags: 0,
+            level_idc,
+            width: u16::try_from(width).ok()?,
+            height: u16::try_from(height).ok()?,
+            sample_aspect_ratio: (1, 1),
+            slice_header: SliceHeaderFields::default(),
+            hevc_profile: Some(HevcProfile {
+                profile_space,
+                tier_flag,
+                profile_compatibility_flags,
+                constraint_indicator_flags,
+            }),
+        })
+    }
+
+    /// Parses SPS NAL unit of the codec
+    pub fn parse_for(codec: VideoCodec, nal: &[u8]) -> Option<Sps> {
+        match codec {
+            VideoCodec::H264 => Sps::parse(nal),
+            VideoCodec::Hevc => Sps::parse_hevc(nal),
+        }
+    }
+}
+
+/// `pic_order_cnt_lsb` of the first slice of the frame, ITU-T H.264 section 7.3.3. `None` when
+/// the frame has no slice or the SPS has another `pic_order_cnt_type`.
+pub fn pic_order_cnt_lsb(frame: &[u8], fields: &SliceHeaderFields) -> Option<u32> {
+    if fields.pic_order_cnt_type != 0 {
+        return None;
+    }
+    let nal = nal_units(frame).into_iter().find(|nal| {
+        matches!(
+            nal_unit_type(nal),
+            NalUnitType::NonIdrSlice | NalUnitType::IdrSlice
+        )
+    })?;
+    let rbsp = to_rbsp(&nal[1..]);
+    let mut r = BitReader::new(&rbsp);
+    let _first_mb_in_slice = r.read_ue()?;
+    let _slice_type = r.read_ue()?;
+    let _pic_parameter_set_id = r.read_ue()?;
+    if fields.separate_colour_plane {
+        let _colour_plane_id = r.read_bits(2)?;
+    }
+    let _frame_num = r.read_bits(fields.log2_max_frame_num)?;
+    if !fields.frame_mbs_only && r.read_flag()? {
+        let _bottom_field = r.read_flag()?;
+    }
+    if nal_unit_type(nal) == NalUnitType::IdrSlice {
+        let _idr_pic_id = r.read_ue()?;
+    }
+    r.read_bits(fields.log2_max_pic_order_cnt_lsb)
+}
+
+/// The DPB holds at most 16 frames, Annex A.3.1, so a frame can't be reordered any further
+const MAX_REORDERED_FRAMES: i64 = 16;
+
+/// Frame periods from the decoding of every frame to its presentation, for frames given in
+/// decode order as whether they are IDR frames and their `pic_order_cnt_lsb`. The presentation
+/// order starts over at every IDR frame. All the frames are delayed by the largest reordering, so
+/// none is presented before it is decoded. Frames without a POC, or with POCs that reorder them
+/// further than a decoder can, are presented in decode order.
+pub fn composition_offsets(
+    frames: &[(bool, Option<u32>)],
+    log2_max_pic_order_cnt_lsb: u32,
+) -> Vec<u64> {
+    let max_lsb = 1i64 << log2_max_pic_order_cnt_lsb;
+    // Presentation position minus decode position of every frame
+    let mut shifts = vec![0i64; frames.len()];
+    let mut start = 0;
+    while start < frames.len() {
+        let end = (start + 1..frames.len())
+            .find(|&idx| frames[idx].0)
+            .unwrap_or(frames.len());
+        let run = &frames[start..end];
+        if run.iter().all(|(_, lsb)| lsb.is_some()) {
+            // PicOrderCntMsb follows the wrapping of the LSBs, section 8.2.1.1
+            let mut pocs = Vec::with_capacity(run.len());
+            let (mut prev_msb, mut prev_lsb) = (0i64, 0i64);
+            for (_, lsb) in run {
+                let lsb = lsb.unwrap_or(0) as i64;
+                let msb = if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+                    prev_msb + max_lsb
+                } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+                    prev_msb - max_lsb
+                } else {
+                    prev_msb
+                };
+                pocs.push(msb + lsb);
+                (prev_msb, prev_lsb) = (msb, lsb);
+            }
+            let mut presentation_order: Vec<usize> = (0..run.len()).collect();
+            presentation_order.sort_by_key(|&idx| pocs[idx]);
+            let run_shifts: Vec<(usize, i64)> = presentation_order
+                .into_iter()
+                .enumerate()
+                .map(|(position, idx)| (idx, position as i64 - idx as i64))
+                .collect();
+            if run_shifts
+                .iter()
+                .all(|(_, shift)| shift.abs() <= MAX_REORDERED_FRAMES)
+            {
+                for (idx, shift) in run_shifts {
+                    shifts[start + idx] = shift;
+                }
+            }
+        }
+        start = end;
+    }
+    let delay = shifts.iter().map(|shift| -shift).max().unwrap_or(0).max(0);
+    shifts.iter().map(|shift| (shift + delay) as u64).collect()
+}
+
+/// ITU-T H.264 Table E-1, unspecified and reserved values are treated as square pixels
+fn sample_aspect_ratio_from_idc(aspect_ratio_idc: u32) -> (u16, u16) {
+    match aspect_ratio_idc {
+        2 => (12, 11),
+        3 => (10, 11),
+        4 => (16, 11),
+        5 => (40, 33),
+        6 => (24, 11),
+        7 => (20, 11),
+        8 => (32, 11),
+        9 => (80, 33),
+        10 => (18, 11),
+        11 => (15, 11),
+        12 => (64, 33),
+        13 => (160, 99),
+        14 => (4, 3),
+        15 => (3, 2),
+        16 => (2, 1),
+        _ => (1, 1),
+    }
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Bits of an RBSP, most significant first
+    #[derive(Default)]
+    struct BitWriter {
+        bits: Vec<bool>,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, n: u32) {
+            for i in (0..n).rev() {
+                self.bits.push(value >> i & 1 == 1);
+            }
+        }
+
+        fn flag(&mut self, flag: bool) {
+            self.bits.push(flag);
+        }
+
+        fn ue(&mut self, value: u32) {
+            let len = 32 - (value + 1).leading_zeros();
+            self.bits(0, len - 1);
+            self.bits(value + 1, len);
+        }
+
+        /// Bytes of the bits with `rbsp_trailing_bits`
+        fn finish(mut self) -> Vec<u8> {
+            self.flag(true);
+            while !self.bits.len().is_multiple_of(8) {
+                self.flag(false);
+            }
+            self.bits
+                .chunks(8)
+                .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+                .collect()
+        }
+    }
+
+    /// Inserts the emulation prevention bytes `to_rbsp` removes
+    fn to_ebsp(rbsp: &[u8]) -> Vec<u8> {
+        let mut ebsp = Vec::with_capacity(rbsp.len());
+        let mut zeros = 0;
+        for &b in rbsp {
+            if zeros >= 2 && b <= 0x03 {
+                ebsp.push(0x03);
+                zeros = 0;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            ebsp.push(b);
+        }
+        ebsp
+    }
+
+    /// Baseline SPS NAL unit of 1280x720 with VUI of `aspect_ratio_idc`, `sar` is the
+    /// Extended_SAR of 255. The SAR starts on a byte boundary.
+    pub fn sps_nal(aspect_ratio_idc: u8, sar: (u16, u16)) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // profile_idc, constraint_set_flags and level_idc
+        w.bits(66, 8);
+        w.bits(0xC0, 8);
+        w.bits(31, 8);
+        // seq_parameter_set_id, log2_max_frame_num_minus4, pic_order_cnt_type and
+        // max_num_ref_frames
+        for value in [0, 0, 2, 3] {
+            w.ue(value);
+        }
+        // gaps_in_frame_num_value_allowed_flag
+        w.flag(false);
+        w.ue(1280 / 16 - 1);
+        w.ue(720 / 16 - 1);
+        // frame_mbs_only_flag, direct_8x8_inference_flag and frame_cropping_flag
+        w.flag(true);
+        w.flag(true);
+        w.flag(false);
+        // vui_parameters_present_flag and aspect_ratio_info_present_flag
+        w.flag(true);
+        w.flag(true);
+        w.bits(aspect_ratio_idc as u32, 8);
+        if aspect_ratio_idc == 255 {
+            w.bits(sar.0 as u32, 16);
+            w.bits(sar.1 as u32, 16);
+        }
+        [0x67].into_iter().chain(to_ebsp(&w.finish())).collect()
+    }
+
+    /// Main SPS NAL unit of 1280x720 with `pic_order_cnt_type` 0, `frame_num` and
+    /// `pic_order_cnt_lsb` are 4 bits
+    pub fn poc_sps_nal() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.bits(77, 8);
+        w.bits(0x40, 8);
+        w.bits(31, 8);
+        // seq_parameter_set_id, log2_max_frame_num_minus4, pic_order_cnt_type,
+        // log2_max_pic_order_cnt_lsb_minus4 and max_num_ref_frames
+        for value in [0, 0, 0, 0, 2] {
+            w.ue(value);
+        }
+        w.flag(false);
+        w.ue(1280 / 16 - 1);
+        w.ue(720 / 16 - 1);
+        // frame_mbs_only_flag, direct_8x8_inference_flag, frame_cropping_flag and
+        // vui_parameters_present_flag
+        for flag in [true, true, false, false] {
+            w.flag(flag);
+        }
+        [0x67].into_iter().chain(to_ebsp(&w.finish())).collect()
+    }
+
+    /// Slice NAL unit of the SPS of `poc_sps_nal` up to its `pic_order_cnt_lsb`
+    pub fn slice_nal(idr: bool, frame_num: u32, pic_order_cnt_lsb: u32) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // first_mb_in_slice, slice_type of I or P and pic_parameter_set_id
+        w.ue(0);
+        w.ue(if idr { 7 } else { 5 });
+        w.ue(0);
+        w.bits(frame_num, 4);
+        if idr {
+            w.ue(0);
+        }
+        w.bits(pic_order_cnt_lsb, 4);
+        let nal_header = if idr { 0x65 } else { 0x41 };
+        [nal_header]
+            .into_iter()
+            .chain(to_ebsp(&w.finish()))
+            .collect()
+    }
+
+    #[test]
+    fn pic_order_cnt_lsb_is_read_from_the_slice_header() {
+        let fields = Sps::parse(&poc_sps_nal()).unwrap().slice_header;
+        assert_eq!(fields.pic_order_cnt_type, 0);
+        assert_eq!(fields.log2_max_pic_order_cnt_lsb, 4);
+        let frame = |nals: &[&[u8]]| -> Vec<u8> {
+            nals.iter()
+                .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+                .collect()
+        };
+        let sps = poc_sps_nal();
+        let idr = slice_nal(true, 0, 0);
+        let p = slice_nal(false, 1, 6);
+        assert_eq!(pic_order_cnt_lsb(&frame(&[&sps, &idr]), &fields), Some(0));
+        assert_eq!(pic_order_cnt_lsb(&frame(&[&p]), &fields), Some(6));
+        assert_eq!(pic_order_cnt_lsb(&frame(&[&sps]), &fields), None);
+        let other_type = SliceHeaderFields {
+            pic_order_cnt_type: 2,
+            ..fields
+        };
+        assert_eq!(pic_order_cnt_lsb(&frame(&[&p]), &other_type), None);
+    }
+
+    #[test]
+    fn composition_offsets_present_no_frame_before_it_is_decoded() {
+        // I P B B P B B in decode order, presented as I B B P B B P
+        let frames = [
+            (true, Some(0)),
+            (false, Some(6)),
+            (false, Some(2)),
+            (false, Some(4)),
+            (false, Some(12)),
+            (false, Some(8)),
+            (false, Some(10)),
+        ];
+        let offsets = composition_offsets(&frames, 4);
+        assert_eq!(offsets, [1, 3, 0, 0, 3, 0, 0]);
+        let mut presentation: Vec<u64> = offsets
+            .iter()
+            .enumerate()
+            .map(|(idx, offset)| idx as u64 + offset)
+            .collect();
+        presentation.sort();
+        assert_eq!(presentation, (1..=7).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn composition_offsets_follow_the_wrapping_of_the_lsb() {
+        // 18 and 16 wrap to 2 and 0, the last two frames are swapped
+        let frames = [0, 4, 8, 12, 2, 0].map(|lsb| (false, Some(lsb)));
+        let frames = [&[(true, Some(0))], &frames[1..]].concat();
+        assert_eq!(composition_offsets(&frames, 4), [1, 1, 1, 1, 2, 0]);
+    }
+
+    #[test]
+    fn composition_offsets_are_zero_without_a_usable_order() {
+        // A frame without POC, a reordering further than the DPB, and a new IDR run
+        assert_eq!(
+            composition_offsets(&[(true, Some(0)), (false, None), (false, Some(2))], 4),
+            [0, 0, 0]
+        );
+        let mut far: Vec<(bool, Option<u32>)> = vec![(true, Some(0)), (false, Some(40))];
+        far.extend((1..20).map(|poc| (false, Some(poc * 2))));
+        assert!(composition_offsets(&far, 8)
+            .iter()
+            .all(|offset| *offset == 0));
+        // The order starts over at every IDR frame
+        assert_eq!(
+            composition_offsets(&[(true, Some(0)), (false, Some(2)), (true, Some(0))], 4),
+            [0, 0, 0]
+        );
+    }
+
+    /// SEI NAL unit with the ATSC cc_data of `cc_count` CEA-608 byte pairs of `cc`
+    pub fn caption_sei_nal(cc_count: u8, cc: u8) -> Vec<u8> {
+        let mut cc_data = ATSC_CC_DATA_HEADER.to_vec();
+        // process_cc_data_flag and cc_count, em_data
+        cc_data.extend_from_slice(&[0x40 | cc_count, 0xFF]);
+        for _ in 0..cc_count {
+            // cc_valid and cc_type 0, the field 1 of line 21
+            cc_data.extend_from_slice(&[0xFC, cc, cc]);
+        }
+        // marker_bits
+        cc_data.push(0xFF);
+        let mut rbsp = vec![
+            SEI_USER_DATA_REGISTERED_ITU_T_T35 as u8,
+            cc_data.len() as u8,
+        ];
+        rbsp.extend_from_slice(&cc_data);
+        rbsp.push(0x80);
+        [0x06].into_iter().chain(to_ebsp(&rbsp)).collect()
+    }
+
+    #[test]
+    fn caption_sei_is_told_apart_from_other_sei() {
+        assert!(is_caption_sei(&caption_sei_nal(2, 0x80)));
+        // Zero bytes in the captions are escaped
+        assert!(is_caption_sei(&caption_sei_nal(31, 0)));
+        // Unregistered user data, and a registered payload of another provider
+        assert!(!is_caption_sei(&[0x06, 5, 2, 0xAB, 0xCD, 0x80]));
+        assert!(!is_caption_sei(&[0x06, 4, 3, 0xB5, 0x00, 0x2F, 0x80]));
+        // Payload sizes past the end of the NAL unit
+        assert!(!is_caption_sei(&[0x06, 4, 200, 0xB5, 0x00, 0x31, 0x80]));
+        let mut sei = caption_sei_nal(2, 0x80);
+        sei[0] = 0x41;
+        assert!(!is_caption_sei(&sei));
+    }
+
+    #[test]
+    fn sample_aspect_ratio_is_read_from_the_vui() {
+        assert_eq!(
+            Sps::parse(&sps_nal(14, (0, 0)))
+                .unwrap()
+                .sample_aspect_ratio,
+            (4, 3)
+        );
+        assert_eq!(
+            Sps::parse(&sps_nal(1, (0, 0))).unwrap().sample_aspect_ratio,
+            (1, 1)
+        );
+        // Reserved
+        assert_eq!(
+            Sps::parse(&sps_nal(17, (0, 0)))
+                .unwrap()
+                .sample_aspect_ratio,
+            (1, 1)
+        );
+        assert_eq!(
+            Sps::parse(&sps_nal(255, (8, 9)))
+                .unwrap()
+                .sample_aspect_ratio,
+            (8, 9)
+        );
+    }
+
+    #[test]
+    fn emulation_prevention_bytes_are_removed_before_parsing_the_sps() {
+        // 01 00 00 01 of 256:1 is escaped to 01 00 00 03 01, sar_height would be 3 with the 03
+        let nal = sps_nal(255, (256, 1));
+        assert!(nal.windows(5).any(|w| w == [0x01, 0, 0, 0x03, 0x01]));
+        let sps = Sps::parse(&nal).unwrap();
+        assert_eq!((sps.width, sps.height), (1280, 720));
+        assert_eq!(sps.sample_aspect_ratio, (256, 1));
+        assert_eq!(sps.codecs(), "avc1.42C01F");
+    }
+
+    /// Annex B of the length-prefixed NAL units of an MP4 sample
+    fn to_annex_b(sample: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        let mut rest = sample;
+        while let Some((size, tail)) = rest.split_first_chunk::<4>() {
+            let (nal, tail) = tail.split_at(u32::from_be_bytes(*size) as usize);
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend_from_slice(nal);
+            rest = tail;
+        }
+        frame
+    }
+
+    #[test]
+    fn annex_b_frames_round_trip_through_avc_samples() {
+        let sps = [0x67, 0x64, 0x00, 0x1F, 0xAC];
+        let pps = [0x68, 0xEE, 0x3C, 0x80];
+        let idr: Vec<u8> = [0x65].into_iter().chain(vec![0xAB; 300]).collect();
+        let mut frame = Vec::new();
+        for nal in [&sps[..], &pps[..], &idr[..]] {
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend_from_slice(nal);
+        }
+
+        let sample = to_avc_sample(&frame);
+        // Only the IDR slice is left, SPS and PPS are in avcC
+        assert_eq!(sample[..5], [0, 0, 1, 45, 0x65]);
+        assert_eq!(sample.len(), 4 + idr.len());
+        assert_eq!(to_annex_b(&sample), [&[0, 0, 0, 1][..], &idr].concat());
+        // The sample starts like a 3 bytes start code, it is still left as it is
+        assert_eq!(to_avc_sample(&sample), sample);
+    }
+
+    #[test]
+    fn length_prefixed_frames_are_copied() {
+        for len in [1, 255, 256, 300, 511, 70_000] {
+            let mut sample = (len as u32).to_be_bytes().to_vec();
+            sample.push(0x41);
+            sample.resize(4 + len, 0xAB);
+            assert!(!is_annex_b(&sample), "NAL unit of {len} bytes");
+            assert_eq!(to_avc_sample(&sample), sample);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// Counts the requests being served, a request lasts until its response body is sent
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+
+static IN_FLIGHT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn in_flight_requests() -> usize {
+    IN_FLIGHT_REQUESTS.load(Ordering::Relaxed)
+}
+
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Self {
+        IN_FLIGHT_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT_REQUESTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the request counted while the body is streamed, keeping the size hint of the body
+struct InFlightBody {
+    inner: Body,
+    _guard: InFlightGuard,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware counting the request in `in_flight_requests` until its response is sent or the
+/// connection is dropped
+pub async fn track_in_flight(request: Request, next: Next) -> Response {
+    let guard = InFlightGuard::new();
+    let response = next.run(request).await;
+    response.map(|inner| {
+        Body::new(InFlightBody {
+            inner,
+            _guard: guard,
+        })
+    })
+}
@@ -0,0 +1,184 @@
+// Post-processing of ISO BMFF (MP4) boxes that the mp4 crate can't write itself, and the boxes
+// of fragmented MP4 it doesn't write right
+
+/// Path from the top level to the AVC sample entry, with the size of each box's own fields
+/// (header included) that precede its children
+const AVC1_PATH: [(&[u8; 4], usize); 7] = [
+    (b"moov", 8),
+    (b"trak", 8),
+    (b"mdia", 8),
+    (b"minf", 8),
+    (b"stbl", 8),
+    // version, flags and entry_count
+    (b"stsd", 16),
+    // VisualSampleEntry fields
+    (b"avc1", 86),
+];
+
+/// Finds the first box of `box_type` in `data[start..end]`, returns its offset and size
+fn find_box(data: &[u8], start: usize, end: usize, box_type: &[u8; 4]) -> Option<(usize, usize)> {
+    let mut pos = start;
+    while pos + 8 <= end {
+        let size = match u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) {
+            // largesize follows the box type
+            1 => usize::try_from(u64::from_be_bytes(
+                data.get(pos + 8..pos + 16)?.try_into().ok()?,
+            ))
+            .ok()?,
+            size => size as usize,
+        };
+        if size < 8 || pos + size > end {
+            return None;
+        }
+        if &data[pos + 4..pos + 8] == box_type {
+            return Some((pos, size));
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Appends a `pasp` box to the `avc1` sample entry so players render non-square pixels right.
+/// `moov` must come after `mdat`, which is how `mp4::Mp4Writer` lays it out, otherwise chunk
+/// offsets would need to be shifted too. Without samples there are no chunk offsets.
+pub fn add_pasp_box(mp4: &mut Vec<u8>, h_spacing: u32, v_spacing: u32) -> Option<()> {
+    let mut ancestors = Vec::with_capacity(AVC1_PATH.len());
+    let (mut start, mut end) = (0, mp4.len());
+    for (box_type, fields_len) in AVC1_PATH {
+        let (pos, size) = find_box(mp4, start, end, box_type)?;
+        // Sizes get patched in place, so boxes with largesize aren't supported
+        if u32::from_be_bytes(mp4[pos..pos + 4].try_into().ok()?) == 1 {
+            return None;
+        }
+        ancestors.push(pos);
+        start = pos + fields_len;
+        end = pos + size;
+    }
+
+    let pasp = [
+        16u32.to_be_bytes(),
+        *b"pasp",
+        h_spacing.to_be_bytes(),
+        v_spacing.to_be_bytes(),
+    ]
+    .concat();
+    let pasp_len = pasp.len() as u32;
+    mp4.splice(end..end, pasp);
+
+    for pos in ancestors {
+        let size = u32::from_be_bytes(mp4[pos..pos + 4].try_into().ok()?) + pasp_len;
+        mp4[pos..pos + 4].copy_from_slice(&size.to_be_bytes());
+    }
+    Some(())
+}
+
+/// `sample_flags` of a sync sample: `sample_depends_on` 2, ISO/IEC 14496-12 section 8.8.3.1
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// `sample_flags` of other samples: `sample_depends_on` 1 and `sample_is_non_sync_sample`
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+/// `default-base-is-moof`, the data offset of `trun` is relative to the `moof`
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+/// `data-offset`, `sample-duration`, `sample-size` and `sample-flags` present
+const TRUN_FLAGS: u32 = 0x0701;
+
+fn mp4_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let size = (8 + content.len()) as u32;
+    [size.to_be_bytes().as_slice(), box_type, content].concat()
+}
+
+fn full_box_header(version: u8, flags: u32) -> [u8; 4] {
+    (((version as u32) << 24) | flags).to_be_bytes()
+}
+
+/// Turns a `mp4::Mp4Writer` output without samples into the init segment of fragmented MP4:
+/// `ftyp` and `moov` with `mvex`, which says the samples are in the fragments
+pub fn init_segment(mp4: &[u8], track_id: u32) -> Option<Vec<u8>> {
+    let (ftyp_pos, ftyp_size) = find_box(mp4, 0, mp4.len(), b"ftyp")?;
+    let (moov_pos, moov_size) = find_box(mp4, 0, mp4.len(), b"moov")?;
+
+    // default_sample_description_index 1, no other defaults, `trun` has them all
+    let trex = [
+        full_box_header(0, 0),
+        track_id.to_be_bytes(),
+        1u32.to_be_bytes(),
+        0u32.to_be_bytes(),
+        0u32.to_be_bytes(),
+        0u32.to_be_bytes(),
+    ]
+    .concat();
+    let mvex = mp4_box(b"mvex", &mp4_box(b"trex", &trex));
+    let moov = [&mp4[moov_pos + 8..moov_pos + moov_size], mvex.as_slice()].concat();
+
+    let mut init = mp4[ftyp_pos..ftyp_pos + ftyp_size].to_vec();
+    init.extend_from_slice(&mp4_box(b"moov", &moov));
+    Some(init)
+}
+
+/// Sample of a media fragment, `duration` is in the track timescale
+pub struct FragmentSample {
+    pub duration: u32,
+    pub is_sync: bool,
+    pub bytes: Vec<u8>,
+}
+
+/// Media segment of fragmented MP4, a `moof` with a single track fragment and the `mdat`.
+/// `base_media_decode_time` is the decode time of the first sample in the track timescale.
+pub fn media_segment(
+    sequence_number: u32,
+    track_id: u32,
+    base_media_decode_time: u64,
+    samples: &[FragmentSample],
+) -> Vec<u8> {
+    let mfhd = mp4_box(
+        b"mfhd",
+        &[full_box_header(0, 0), sequence_number.to_be_bytes()].concat(),
+    );
+    let tfhd = mp4_box(
+        b"tfhd",
+        &[
+            full_box_header(0, TFHD_DEFAULT_BASE_IS_MOOF),
+            track_id.to_be_bytes(),
+        ]
+        .concat(),
+    );
+    let tfdt = mp4_box(
+        b"tfdt",
+        &[
+            full_box_header(1, 0).as_slice(),
+            &base_media_decode_time.to_be_bytes(),
+        ]
+        .concat(),
+    );
+    let moof = |data_offset: u32| {
+        let mut trun = [
+            full_box_header(0, TRUN_FLAGS),
+            (samples.len() as u32).to_be_bytes(),
+            data_offset.to_be_bytes(),
+        ]
+        .concat();
+        for sample in samples {
+            let flags = match sample.is_sync {
+                true => SYNC_SAMPLE_FLAGS,
+                false => NON_SYNC_SAMPLE_FLAGS,
+            };
+            trun.extend_from_slice(&sample.duration.to_be_bytes());
+            trun.extend_from_slice(&(sample.bytes.len() as u32).to_be_bytes());
+            trun.extend_from_slice(&flags.to_be_bytes());
+        }
+        let traf = [tfhd.as_slice(), &tfdt, &mp4_box(b"trun", &trun)].concat();
+        mp4_box(
+            b"moof",
+            &[mfhd.as_slice(), &mp4_box(b"traf", &traf)].concat(),
+        )
+    };
+    // The samples start right after the `mdat` header, the size of `moof` doesn't depend on it
+    let data_offset = moof(0).len() + 8;
+    let mut segment = moof(data_offset as u32);
+
+    let mdat: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| sample.bytes.iter().copied())
+        .collect();
+    segment.extend_from_slice(&mp4_box(b"mdat", &mdat));
+    segment
+}
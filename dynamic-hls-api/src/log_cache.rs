@@ -0,0 +1,132 @@
+// LRU cache of the lookups that read a whole log, e.g. its keyframes. A lookup is of the frames
+// the log had, a log still being recorded is looked up again once it has more.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::errors;
+
+struct Entry<V> {
+    /// Frames of the log the value was looked up with
+    frames: usize,
+    value: V,
+    /// Value of `Entries::clock` when the value was used last
+    last_used: u64,
+}
+
+struct Entries<V> {
+    entries: HashMap<String, Entry<V>>,
+    clock: u64,
+}
+
+pub struct LogCache<V> {
+    capacity: usize,
+    entries: Mutex<Entries<V>>,
+}
+
+impl<V: Clone> LogCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(Entries {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// The value of the log at `path` with `frames` frames, looked up with `lookup` unless it is
+    /// cached. It replaces the value of another number of frames, failures are not cached. The
+    /// lookup runs without the lock, requests racing for a log may look it up twice.
+    pub fn get_or_lookup(
+        &self,
+        path: &str,
+        frames: usize,
+        lookup: impl FnOnce() -> errors::Result<V>,
+    ) -> errors::Result<V> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some(entry) = entries
+                .entries
+                .get_mut(path)
+                .filter(|entry| entry.frames == frames)
+            {
+                entry.last_used = clock;
+                return Ok(entry.value.clone());
+            }
+        }
+        let value = lookup()?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        // The capacity is small, a scan is cheaper than keeping the entries ordered
+        while !entries.entries.contains_key(path) && entries.entries.len() >= self.capacity {
+            let least_recently_used = entries
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(k) = least_recently_used {
+                entries.entries.remove(&k);
+            }
+        }
+        entries.entries.insert(
+            path.to_string(),
+            Entry {
+                frames,
+                value: value.clone(),
+                last_used: clock,
+            },
+        );
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_again_when_the_frames_change() {
+        let cache = LogCache::new(4);
+        let mut lookups = 0;
+        let mut get = |frames| {
+            cache
+                .get_or_lookup("log", frames, || {
+                    lookups += 1;
+                    Ok(frames * 10)
+                })
+                .unwrap()
+        };
+        assert_eq!(get(1), 10);
+        assert_eq!(get(1), 10);
+        assert_eq!(get(2), 20);
+        assert_eq!(get(2), 20);
+        assert_eq!(lookups, 2);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_log() {
+        let cache = LogCache::new(2);
+        cache.get_or_lookup("a", 1, || Ok("a")).unwrap();
+        cache.get_or_lookup("b", 1, || Ok("b")).unwrap();
+        cache.get_or_lookup("a", 1, || Ok("a again")).unwrap();
+        cache.get_or_lookup("c", 1, || Ok("c")).unwrap();
+
+        let a = cache.get_or_lookup("a", 1, || Ok("a looked up")).unwrap();
+        let b = cache.get_or_lookup("b", 1, || Ok("b looked up")).unwrap();
+        assert_eq!((a, b), ("a", "b looked up"));
+    }
+
+    #[test]
+    fn doesnt_cache_failures() {
+        let cache = LogCache::new(2);
+        let failed = cache.get_or_lookup("a", 1, || {
+            Err(errors::ErrorKind::NotFoundError("no SPS".to_string()))?
+        });
+        assert!(failed.is_err());
+        assert_eq!(cache.get_or_lookup("a", 1, || Ok(1)).unwrap(), 1);
+    }
+}
@@ -1,15 +1,62 @@
 use std::env;
-use tracing_subscriber::fmt::format::{DefaultFields, Format};
-use tracing_subscriber::fmt::SubscriberBuilder;
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::Record;
+use tracing::{info, warn, Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::{DefaultFields, Format, Writer};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{
+    FmtContext, FormatEvent, FormatFields, FormattedFields, SubscriberBuilder,
+};
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::EnvFilter;
 
+/// Span fields copied to the top of the JSON log lines, the lines of a request are found by them
+const CORRELATION_FIELDS: [&str; 2] = ["request_id", "trace_id"];
+
+/// Format of the log lines, set by the `LOG_FORMAT` env variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    /// A JSON object per line for the log ingestion
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {s}, expected pretty or json")),
+        }
+    }
+}
+
 pub fn setup(log_level: &str) {
     if env::var_os("RUST_LOG").is_none() {
         let env = format!("dynamic_hls_api={log_level},tower_http=WARN,hyper=WARN");
         env::set_var("RUST_LOG", env);
     }
-    let subscriber = get_subscriber();
-    subscriber.init();
+    let log_format = env::var("LOG_FORMAT").map(|v| v.parse::<LogFormat>());
+    match log_format {
+        Ok(Ok(LogFormat::Json)) => get_subscriber()
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .init(),
+        _ => get_subscriber().init(),
+    }
+    // Logged once the subscriber is there
+    match log_format {
+        Ok(Ok(log_format)) => info!("`LOG_FORMAT` env variable is set to {:?}", log_format),
+        Ok(Err(err)) => warn!("`LOG_FORMAT` env variable is ignored: {}", err),
+        Err(_) => {}
+    }
 }
 
 pub fn get_subscriber() -> SubscriberBuilder<DefaultFields, Format, EnvFilter> {
@@ -20,3 +67,122 @@ pub fn get_subscriber() -> SubscriberBuilder<DefaultFields, Format, EnvFilter> {
         .with_thread_ids(true)
         .with_thread_names(true)
 }
+
+/// Collects the fields as JSON values, numbers and booleans keep their type
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), Value::from(format!("{value:?}")));
+    }
+}
+
+/// Keeps the fields of the spans as a JSON object, `JsonFormat` reads them back
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        // The fields recorded later are merged into the object, not appended to it
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+/// A JSON object per event with the same details as the pretty format and the spans it is in
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let thread = std::thread::current();
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), Value::from(timestamp));
+        line.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        line.insert("target".to_string(), Value::from(metadata.target()));
+        line.insert("filename".to_string(), Value::from(metadata.file()));
+        line.insert("line_number".to_string(), Value::from(metadata.line()));
+        line.insert(
+            "thread_id".to_string(),
+            Value::from(format!("{:?}", thread.id())),
+        );
+        line.insert("thread_name".to_string(), Value::from(thread.name()));
+
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+        line.insert("fields".to_string(), Value::Object(fields.0));
+
+        let mut spans = Vec::new();
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            let extensions = span.extensions();
+            let mut span_fields: Map<String, Value> = extensions
+                .get::<FormattedFields<JsonFields>>()
+                .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                .unwrap_or_default();
+            // The innermost span wins
+            for name in CORRELATION_FIELDS {
+                if let Some(value) = span_fields.get(name) {
+                    line.insert(name.to_string(), value.clone());
+                }
+            }
+            span_fields.insert("name".to_string(), Value::from(span.name()));
+            spans.push(Value::Object(span_fields));
+        }
+        if !spans.is_empty() {
+            line.insert("spans".to_string(), Value::Array(spans));
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
@@ -0,0 +1,108 @@
+// Keyframes, captions and parameter sets of the logs, looked up once per log and number of
+// frames
+use crate::errors;
+use crate::h264::{self, NalUnitType, VideoCodec};
+use crate::log_cache::LogCache;
+use crate::mux::VIDEO_CODEC;
+use crate::retry;
+use crate::routes::get_frames;
+use lazy_static::lazy_static;
+use std::fs;
+
+/// Logs the keyframes, parameter sets and captions are kept of
+const LOG_CACHE_CAPACITY: usize = 256;
+
+lazy_static! {
+    static ref PARAMETER_SETS: LogCache<ParameterSets> = LogCache::new(LOG_CACHE_CAPACITY);
+    static ref KEYFRAMES: LogCache<Vec<usize>> = LogCache::new(LOG_CACHE_CAPACITY);
+    static ref HAS_CAPTIONS: LogCache<bool> = LogCache::new(LOG_CACHE_CAPACITY);
+}
+
+/// Encoders send captions with every frame, so the first segment is enough to find them
+const CAPTION_PROBE_FRAMES: usize = 100;
+
+/// Positions of the IDR frames in the frame list, looked up again when the log has more frames
+pub fn get_keyframes(path_to_h264_frames: &str) -> errors::Result<Vec<usize>> {
+    let files = get_frames(path_to_h264_frames)?;
+    KEYFRAMES.get_or_lookup(path_to_h264_frames, files.len(), || {
+        let mut keyframes = Vec::new();
+        for (idx, f) in files.iter().enumerate() {
+            let path = format!("{}/{}", path_to_h264_frames, f);
+            let bytes = retry::read_with_retry(&path, || fs::read(&path))?;
+            if VIDEO_CODEC.is_keyframe(&bytes) {
+                keyframes.push(idx);
+            }
+        }
+        Ok(keyframes)
+    })
+}
+
+/// Whether the first frames of the log carry caption SEI, looked up again until the log has
+/// `CAPTION_PROBE_FRAMES`. Only H264 SEI are read.
+pub fn has_captions(path_to_h264_frames: &str, files: &[String]) -> errors::Result<bool> {
+    if *VIDEO_CODEC != VideoCodec::H264 {
+        return Ok(false);
+    }
+    let probe_frames = files.len().min(CAPTION_PROBE_FRAMES);
+    HAS_CAPTIONS.get_or_lookup(path_to_h264_frames, probe_frames, || {
+        for f in &files[..probe_frames] {
+            let path = format!("{}/{}", path_to_h264_frames, f);
+            let bytes = retry::read_with_retry(&path, || fs::read(&path))?;
+            if h264::nal_units(&bytes)
+                .into_iter()
+                .any(h264::is_caption_sei)
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ParameterSets {
+    pub sps: Vec<u8>,
+    pub pps: Vec<u8>,
+}
+
+/// Scans the frames in order until both SPS and PPS are found
+pub fn find_parameter_sets(
+    path_to_h264_frames: &str,
+    files: &[impl AsRef<str>],
+) -> errors::Result<Option<ParameterSets>> {
+    let mut sps: Option<Vec<u8>> = None;
+    let mut pps: Option<Vec<u8>> = None;
+    for f in files {
+        let path = format!("{}/{}", path_to_h264_frames, f.as_ref());
+        let bytes = retry::read_with_retry(&path, || fs::read(&path))?;
+        for nal in h264::nal_units(&bytes) {
+            match VIDEO_CODEC.nal_unit_type(nal) {
+                NalUnitType::Sps if sps.is_none() => sps = Some(nal.to_vec()),
+                NalUnitType::Pps if pps.is_none() => pps = Some(nal.to_vec()),
+                _ => {}
+            }
+        }
+        if let (Some(sps), Some(pps)) = (&sps, &pps) {
+            return Ok(Some(ParameterSets {
+                sps: sps.clone(),
+                pps: pps.clone(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Parameter sets rarely change within a recording, so they are looked up once per log and
+/// number of frames
+pub fn get_parameter_sets(
+    log_name: &str,
+    path_to_h264_frames: &str,
+) -> errors::Result<ParameterSets> {
+    let files = get_frames(path_to_h264_frames)?;
+    PARAMETER_SETS.get_or_lookup(path_to_h264_frames, files.len(), || {
+        let params = find_parameter_sets(path_to_h264_frames, &files)?.ok_or_else(|| {
+            errors::ErrorKind::NotFoundError(format!("No SPS/PPS found in {log_name}"))
+        })?;
+        Ok(params)
+    })
+}
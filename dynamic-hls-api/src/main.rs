@@ -1,25 +1,54 @@
+mod admin;
+mod auth;
+mod dash;
 mod errors;
+mod export;
+mod ffmpeg;
+mod gif;
+mod h264;
+mod in_flight;
+mod isobmff;
+mod log_cache;
 mod logger;
+mod lookups;
+mod metrics;
 mod mpegts;
+mod mux;
+mod nals;
+mod playlist;
+mod probe;
+mod range;
+mod retry;
 mod routes;
+mod segment;
+mod segment_cache;
+mod thumbnail;
 
-use axum::http::header;
-use axum::middleware::map_response;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use axum::middleware::{from_fn, from_fn_with_state, map_response, Next};
 use axum::response::Response;
 use axum::routing::get;
-use axum::Router;
-use axum_prometheus::PrometheusMetricLayer;
+use axum_prometheus::PrometheusMetricLayerBuilder;
+use clap::{Parser, Subcommand};
 
+use std::env;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use shadow_rs::shadow;
 use tokio::signal;
-use tower_http::compression::CompressionLayer;
-use tower_http::cors::CorsLayer;
+use tokio::sync::Notify;
+use tower_http::compression::predicate::{NotForContentType, Predicate};
+use tower_http::compression::{CompressionLayer, DefaultPredicate};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::propagate_header::PropagateHeaderLayer;
+use tower_http::request_id::{MakeRequestUuid, SetRequestIdLayer};
 use tower_http::sensitive_headers::SetSensitiveHeadersLayer;
 use tower_http::trace;
-use tracing::info;
+use tracing::{info, warn};
 
 shadow!(build);
 
@@ -32,26 +61,348 @@ pub const APP_VERSION: &str = shadow_rs::formatcp!(
     build::RUST_CHANNEL,
     build::CARGO_VERSION
 );
+const VERSION_ID_HEADER: HeaderName = HeaderName::from_static("x-version-id");
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+const TRACE_ID_HEADER: HeaderName = HeaderName::from_static("x-datadog-trace-id");
+
 async fn set_version_header<B>(mut res: Response<B>) -> Response<B> {
     res.headers_mut()
-        .insert("x-version-id", APP_VERSION.parse().unwrap());
+        .insert(VERSION_ID_HEADER, APP_VERSION.parse().unwrap());
     res
 }
 
-#[tokio::main]
-async fn main() -> errors::Result<()> {
+/// Span of a request, the log lines of the request have its `request_id`, made up when the
+/// client sent none, and its `trace_id` when the client sent one
+fn make_request_span(request: &Request) -> tracing::Span {
+    let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = header(REQUEST_ID_HEADER),
+        trace_id = header(TRACE_ID_HEADER),
+    )
+}
+
+/// Only the origins in the comma separated `ALLOWED_ORIGINS` may request the API from a browser,
+/// any origin may when it is not set. An empty list allows no other origin.
+fn cors_layer() -> CorsLayer {
+    let allowed_origins = match env::var("ALLOWED_ORIGINS") {
+        Ok(v) => v,
+        Err(_) => {
+            warn!("`ALLOWED_ORIGINS` env variable is not set, any origin is allowed");
+            return CorsLayer::permissive();
+        }
+    };
+    let mut origins = Vec::new();
+    for origin in allowed_origins.split(',').map(str::trim) {
+        if origin.is_empty() {
+            continue;
+        }
+        match HeaderValue::from_str(origin) {
+            Ok(origin) => origins.push(origin),
+            Err(err) => warn!("`ALLOWED_ORIGINS` origin {origin:?} is ignored: {err}"),
+        }
+    }
+    info!("`ALLOWED_ORIGINS` env variable is set to {:?}", origins);
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers([header::RANGE, header::AUTHORIZATION, REQUEST_ID_HEADER])
+        // Players read the ranges and the sizes of the segments
+        .expose_headers([
+            header::ACCEPT_RANGES,
+            header::CONTENT_RANGE,
+            header::CONTENT_LENGTH,
+            VERSION_ID_HEADER,
+            REQUEST_ID_HEADER,
+            playlist::TOTAL_SEGMENTS_HEADER,
+            segment::PREROLL_FRAMES_HEADER,
+            segment::SEGMENT_SIZE_HEADER,
+            errors::ERROR_CODE_HEADER,
+        ])
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(author, about, long_version = APP_VERSION)]
+struct AppArgs {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Serve HLS playlists and segments over HTTP, the default
+    Serve {
+        /// Address to listen on, the `HOST` env variable or 127.0.0.1 when not given
+        #[clap(long)]
+        host: Option<String>,
+        /// Port to listen on, the `PORT` env variable or 18080 when not given
+        #[clap(long)]
+        port: Option<u16>,
+    },
+    /// Write the whole recording as numbered GOP aligned TS segments and a VOD playlist
+    Export {
+        /// Path to H264 frames
+        #[clap(long)]
+        path_to_h264_frames: String,
+        /// Directory for the segments and playlist.m3u8, created when missing
+        #[clap(long)]
+        output_dir: String,
+        /// Minimum duration of a segment, it ends at the next keyframe
+        #[clap(long, default_value_t = routes::DEFAULT_SEGMENT_LENGTH_MS)]
+        segment_duration_ms: usize,
+        /// Frame rate of the camera
+        #[clap(
+            long,
+            default_value_t = routes::DEFAULT_FPS,
+            value_parser = clap::value_parser!(u32).range(1..=routes::MAX_FPS as i64)
+        )]
+        fps: u32,
+    },
+}
+
+fn main() -> errors::Result<()> {
     logger::setup("INFO");
 
-    let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
-    let route = Router::new()
-        .merge(routes::create_route().await)
-        .route("/metrics", get(|| async move { metric_handle.render() }))
+    let args = AppArgs::parse();
+    let command = args.command.unwrap_or(Command::Serve {
+        host: None,
+        port: None,
+    });
+    match command {
+        Command::Serve { host, port } => {
+            runtime()?.block_on(serve(listen_host(host), listen_port(port)))
+        }
+        Command::Export {
+            path_to_h264_frames,
+            output_dir,
+            segment_duration_ms,
+            fps,
+        } => export::export(&path_to_h264_frames, &output_dir, segment_duration_ms, fps),
+    }
+}
+
+/// The default of tokio
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+/// Threads of the async executor serving the requests, one per core when it is not set. The
+/// muxing runs on the blocking threads, so fewer workers than cores leaves the cores to it.
+fn worker_threads() -> Option<usize> {
+    match env::var("WORKER_THREADS").map(|v| v.parse::<usize>()) {
+        Ok(Ok(threads)) if threads > 0 => {
+            info!("`WORKER_THREADS` env variable is set to {}", threads);
+            Some(threads)
+        }
+        Ok(_) => {
+            warn!("`WORKER_THREADS` env variable is ignored, expected a number > 0");
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Threads muxing the segments and reading the frame files at most. A request over it waits for
+/// a thread, so it bounds the CPU and the open files the muxing takes.
+fn max_blocking_threads() -> usize {
+    match env::var("MAX_BLOCKING_THREADS").map(|v| v.parse::<usize>()) {
+        Ok(Ok(threads)) if threads > 0 => {
+            info!("`MAX_BLOCKING_THREADS` env variable is set to {}", threads);
+            threads
+        }
+        Ok(_) => {
+            warn!(
+                "`MAX_BLOCKING_THREADS` env variable is ignored, use {}",
+                DEFAULT_MAX_BLOCKING_THREADS
+            );
+            DEFAULT_MAX_BLOCKING_THREADS
+        }
+        Err(_) => DEFAULT_MAX_BLOCKING_THREADS,
+    }
+}
+
+fn runtime() -> errors::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = worker_threads() {
+        builder.worker_threads(threads);
+    }
+    let runtime = builder
+        .max_blocking_threads(max_blocking_threads())
+        .enable_all()
+        .build()?;
+    Ok(runtime)
+}
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 18080;
+
+fn listen_host(host: Option<String>) -> String {
+    if let Some(host) = host {
+        return host;
+    }
+    match env::var("HOST") {
+        Ok(host) => {
+            info!("`HOST` env variable is set to {}", host);
+            host
+        }
+        Err(_) => DEFAULT_HOST.to_string(),
+    }
+}
+
+fn listen_port(port: Option<u16>) -> u16 {
+    if let Some(port) = port {
+        return port;
+    }
+    match env::var("PORT").map(|v| v.parse::<u16>()) {
+        Ok(Ok(port)) => {
+            info!("`PORT` env variable is set to {}", port);
+            port
+        }
+        Ok(Err(err)) => {
+            warn!(
+                "`PORT` env variable is ignored, use {}: {}",
+                DEFAULT_PORT, err
+            );
+            DEFAULT_PORT
+        }
+        Err(_) => DEFAULT_PORT,
+    }
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// How long the graceful shutdown waits for the requests in flight
+fn shutdown_timeout() -> Duration {
+    let secs = match env::var("SHUTDOWN_TIMEOUT_SECS").map(|v| v.parse::<u64>()) {
+        Ok(Ok(secs)) => {
+            info!("`SHUTDOWN_TIMEOUT_SECS` env variable is set to {}", secs);
+            secs
+        }
+        Ok(Err(err)) => {
+            warn!(
+                "`SHUTDOWN_TIMEOUT_SECS` env variable is ignored, use {}: {}",
+                DEFAULT_SHUTDOWN_TIMEOUT_SECS, err
+            );
+            DEFAULT_SHUTDOWN_TIMEOUT_SECS
+        }
+        Err(_) => DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+    };
+    Duration::from_secs(secs)
+}
+
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+
+/// How long a response may take to start, a streamed segment body is not cut by it
+fn request_timeout() -> Duration {
+    let secs = match env::var("REQUEST_TIMEOUT_SECS").map(|v| v.parse::<u64>()) {
+        Ok(Ok(secs)) if secs > 0 => {
+            info!("`REQUEST_TIMEOUT_SECS` env variable is set to {}", secs);
+            secs
+        }
+        Ok(_) => {
+            warn!(
+                "`REQUEST_TIMEOUT_SECS` env variable is ignored, use {}",
+                DEFAULT_REQUEST_TIMEOUT_SECS
+            );
+            DEFAULT_REQUEST_TIMEOUT_SECS
+        }
+        Err(_) => DEFAULT_REQUEST_TIMEOUT_SECS,
+    };
+    Duration::from_secs(secs)
+}
+
+/// The API only takes GET requests, the bodies are small SDP offers at most
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Largest request body in bytes, longer ones are `413 Payload Too Large`
+fn max_body_size() -> usize {
+    match env::var("MAX_BODY_SIZE").map(|v| v.parse::<usize>()) {
+        Ok(Ok(size)) => {
+            info!("`MAX_BODY_SIZE` env variable is set to {}", size);
+            size
+        }
+        Ok(Err(err)) => {
+            warn!(
+                "`MAX_BODY_SIZE` env variable is ignored, use {}: {}",
+                DEFAULT_MAX_BODY_SIZE, err
+            );
+            DEFAULT_MAX_BODY_SIZE
+        }
+        Err(_) => DEFAULT_MAX_BODY_SIZE,
+    }
+}
+
+/// Middleware answering `504 Gateway Timeout` when the handler takes longer than `timeout`.
+/// `tower_http::timeout::TimeoutLayer` answers `408 Request Timeout` without the error body.
+async fn timeout_request(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> errors::Result<Response> {
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => Ok(response),
+        Err(_) => Err(errors::ErrorKind::TimeoutError(format!(
+            "No response within {}s",
+            timeout.as_secs()
+        )))?,
+    }
+}
+
+/// Address of the listener for `/metrics` and the probes, so they aren't public. They are served
+/// with the video when it is not set.
+fn metrics_addr() -> Option<String> {
+    match env::var("METRICS_ADDR") {
+        Ok(addr) => {
+            info!("`METRICS_ADDR` env variable is set to {}", addr);
+            Some(addr)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Playlists refer to the segments relative to themselves or with the `Host` of the request, so
+/// they don't depend on the address the server listens on
+async fn serve(host: String, port: u16) -> errors::Result<()> {
+    let (prometheus_layer, metric_handle) = PrometheusMetricLayerBuilder::new()
+        .with_metrics_from_fn(metrics::install_recorder)
+        .build_pair();
+    let base_path = routes::BasePath::from_env();
+    ffmpeg::check_available().await;
+    let mut internal_route = routes::create_probe_route(base_path.clone())
+        .route("/metrics", get(|| async move { metric_handle.render() }));
+    if auth::admin_enabled() {
+        internal_route = internal_route.merge(routes::create_admin_route(base_path.clone()));
+    }
+    let mut route = routes::create_route(base_path).await;
+    match metrics_addr() {
+        Some(metrics_addr) => {
+            // Bound before the video, so a wrong address stops the server from starting
+            let metrics_listener = tokio::net::TcpListener::bind(metrics_addr.as_str()).await?;
+            info!(
+                "Server listening for metrics on {}",
+                metrics_listener.local_addr()?
+            );
+            tokio::spawn(async move {
+                axum::serve(metrics_listener, internal_route)
+                    .with_graceful_shutdown(shutdown_signal())
+                    .await
+                    .expect("Failed to start metrics server")
+            });
+        }
+        None => route = route.merge(internal_route),
+    }
+    let route = route
+        .layer(from_fn_with_state(request_timeout(), timeout_request))
+        .layer(from_fn(errors::negotiate_error_body))
+        .layer(RequestBodyLimitLayer::new(max_body_size()))
         .layer(prometheus_layer)
         .layer(map_response(set_version_header))
+        .layer(from_fn(in_flight::track_in_flight))
         // High level logging of requests and responses
         .layer(
             trace::TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().include_headers(true))
+                .make_span_with(make_request_span)
                 .on_request(trace::DefaultOnRequest::new().level(tracing::Level::DEBUG))
                 .on_response(trace::DefaultOnResponse::new().level(tracing::Level::DEBUG)),
         )
@@ -60,32 +411,65 @@ async fn main() -> errors::Result<()> {
         .layer(SetSensitiveHeadersLayer::new(std::iter::once(
             header::AUTHORIZATION,
         )))
-        // Compress responses
-        .layer(CompressionLayer::new())
+        // Compress the playlists, manifests and JSON. The video and the images are compressed
+        // already, and `Content-Range` and `Content-Length` of the segments are of their bytes.
+        // The parts of `multipart/byteranges` are ranges of the segments too.
+        .layer(
+            CompressionLayer::new().compress_when(
+                DefaultPredicate::new()
+                    .and(NotForContentType::const_new("video/"))
+                    .and(NotForContentType::const_new("multipart/byteranges")),
+            ),
+        )
         // Propagate `x-request-id`s from requests to responses
-        .layer(PropagateHeaderLayer::new(header::HeaderName::from_static(
-            "x-request-id",
-        )))
+        .layer(PropagateHeaderLayer::new(REQUEST_ID_HEADER))
         // Propagate `x-datadog-trace-id`s from requests to responses
-        .layer(PropagateHeaderLayer::new(header::HeaderName::from_static(
-            "x-datadog-trace-id",
-        )))
-        // CORS configuration. This should probably be more restrictive in
-        // production.
-        .layer(CorsLayer::permissive());
-
-    let http_addr: SocketAddr = format!("{}:{}", "127.0.0.1", "18080").parse().unwrap();
+        .layer(PropagateHeaderLayer::new(TRACE_ID_HEADER))
+        // Requests without `x-request-id` get a UUID, before the span and the propagation above
+        .layer(SetRequestIdLayer::new(REQUEST_ID_HEADER, MakeRequestUuid))
+        // CORS configuration, restricted to `ALLOWED_ORIGINS` in production
+        .layer(cors_layer());
 
-    info!("Server listening for HTTP on {}", &http_addr);
+    // Host names are resolved, IPv6 addresses don't need brackets
+    let http_listener = tokio::net::TcpListener::bind((host.as_str(), port)).await?;
+    info!(
+        "Server listening for HTTP on {}",
+        http_listener.local_addr()?
+    );
     let svc = route.into_make_service_with_connect_info::<SocketAddr>();
-    let http_listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
-    let f = tokio::spawn(async move {
+    let shutdown_timeout = shutdown_timeout();
+    let shutdown_started = Arc::new(Notify::new());
+    let server_shutdown_started = shutdown_started.clone();
+    let mut f = tokio::spawn(async move {
         axum::serve(http_listener, svc.clone())
-            .with_graceful_shutdown(shutdown_signal())
+            .with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                server_shutdown_started.notify_one();
+            })
             .await
             .expect("Failed to start server")
     });
-    f.await.expect("Failed to get the server running");
+    let drain_timeout = async {
+        shutdown_started.notified().await;
+        info!(
+            "Waiting up to {}s for {} requests in flight",
+            shutdown_timeout.as_secs(),
+            in_flight::in_flight_requests()
+        );
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::select! {
+        result = &mut f => result.expect("Failed to get the server running"),
+        _ = drain_timeout => {
+            // The connections go away with the runtime
+            warn!(
+                "{} requests still in flight after {}s, dropping their connections",
+                in_flight::in_flight_requests(),
+                shutdown_timeout.as_secs()
+            );
+            f.abort();
+        }
+    }
     info!("Server shutdown");
 
     Ok(())
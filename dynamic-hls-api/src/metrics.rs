@@ -0,0 +1,78 @@
+// Prometheus metrics of the muxing, rendered by `/metrics` along with the HTTP ones
+use std::time::Duration;
+
+use axum_prometheus::metrics::{describe_histogram, histogram, Unit};
+use axum_prometheus::metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use axum_prometheus::utils::SECONDS_DURATION_BUCKETS;
+
+const SEGMENT_MUX_DURATION_METRIC: &str = "segment_mux_duration_seconds";
+const SEGMENT_BYTES_METRIC: &str = "segment_bytes";
+const SEGMENT_FRAMES_METRIC: &str = "segment_frames";
+const PLAYLIST_DURATION_METRIC: &str = "playlist_generation_duration_seconds";
+
+/// From 16 KiB to 64 MiB
+const BYTES_BUCKETS: &[f64] = &[
+    16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+];
+/// From a frame to 5 minutes at 20 fps
+const FRAMES_BUCKETS: &[f64] = &[1.0, 10.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 6000.0];
+
+/// Installs the recorder with histogram buckets for the HTTP and the muxing metrics, without
+/// them the exporter renders the histograms as summaries
+pub fn install_recorder() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        // The HTTP request duration, the muxing and the playlist ones
+        .set_buckets_for_metric(
+            Matcher::Suffix("_duration_seconds".to_string()),
+            SECONDS_DURATION_BUCKETS,
+        )
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                Matcher::Full(SEGMENT_BYTES_METRIC.to_string()),
+                BYTES_BUCKETS,
+            )
+        })
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                Matcher::Full(SEGMENT_FRAMES_METRIC.to_string()),
+                FRAMES_BUCKETS,
+            )
+        })
+        .expect("Buckets are not empty")
+        .install_recorder()
+        .expect("Failed to install the Prometheus recorder");
+
+    describe_histogram!(
+        SEGMENT_MUX_DURATION_METRIC,
+        Unit::Seconds,
+        "Time to mux a segment"
+    );
+    describe_histogram!(
+        SEGMENT_BYTES_METRIC,
+        Unit::Bytes,
+        "Size of the muxed segments"
+    );
+    describe_histogram!(
+        SEGMENT_FRAMES_METRIC,
+        Unit::Count,
+        "Frames in the muxed segments"
+    );
+    describe_histogram!(
+        PLAYLIST_DURATION_METRIC,
+        Unit::Seconds,
+        "Time to generate a playlist"
+    );
+    handle
+}
+
+/// Records a muxed segment, `video_type` labels all of its metrics
+pub fn record_segment(video_type: &str, elapsed: Duration, bytes: usize, frames: usize) {
+    let labels = [("video_type", video_type.to_string())];
+    histogram!(SEGMENT_MUX_DURATION_METRIC, &labels).record(elapsed.as_secs_f64());
+    histogram!(SEGMENT_BYTES_METRIC, &labels).record(bytes as f64);
+    histogram!(SEGMENT_FRAMES_METRIC, &labels).record(frames as f64);
+}
+
+pub fn record_playlist(elapsed: Duration) {
+    histogram!(PLAYLIST_DURATION_METRIC).record(elapsed.as_secs_f64());
+}
@@ -1,24 +1,65 @@
 // Based on https://github.com/valeth/javelin/blob/master/javelin-codec/src/mpegts/transport_stream.rs with slight modification
 use std::io::Write;
+use std::str::FromStr;
 
 use mpeg2ts::ts::payload::Bytes;
+use serde::Deserialize;
 use thiserror::Error;
+use tracing::warn;
 
 use {
-    bytes::Buf,
+    crate::h264::{Sps, VideoCodec},
     mpeg2ts::{
         pes::PesHeader,
         time::{ClockReference, Timestamp},
-        ts::{self, ContinuityCounter, Pid, TsHeader, TsPacket, TsPayload},
+        ts::{self, ContinuityCounter, Descriptor, Pid, TsHeader, TsPacket, TsPayload},
     },
-    std::io::Cursor,
 };
 
-const PMT_PID: u16 = 256;
-const VIDEO_ES_PID: u16 = 257;
-const PES_VIDEO_STREAM_ID: u8 = 224;
+const SDT_PID: u16 = 0x0011;
+const DEFAULT_PMT_PID: u16 = 256;
+const DEFAULT_VIDEO_ES_PID: u16 = 257;
+const AUDIO_ES_PID: u16 = 258;
+const SCTE35_PID: u16 = 259;
+const METADATA_PID: u16 = 260;
+/// PIDs below are reserved for PAT, CAT and the DVB tables, ISO/IEC 13818-1 table 2-3
+const MIN_ES_PID: u16 = 0x0010;
+/// Null packets carry no data, demuxers drop them, ISO/IEC 13818-1 table 2-3
+const NULL_PID: u16 = 0x1FFF;
+/// ISO/IEC 13818-1 section 2.7.2 allows at most 100 ms between PCRs
+const PCR_INTERVAL_MS: u64 = 100;
+const DEFAULT_PES_VIDEO_STREAM_ID: u8 = 224;
+const PES_AUDIO_STREAM_ID: u8 = 192;
+/// private_stream_1, HLS timed metadata is carried in it
+const PES_METADATA_STREAM_ID: u8 = 0xBD;
+/// `splice_command_type` of `splice_insert`, SCTE 35 section 9.7.3
+const SPLICE_INSERT_COMMAND: u8 = 0x05;
+/// PES header with PTS and DTS: start code, stream id and packet length take 6 bytes, flags and
+/// header length 3 bytes, PTS and DTS 5 bytes each
+const PES_HEADER_SIZE: usize = 19;
+/// PES header with just the PTS
+const PES_AUDIO_HEADER_SIZE: usize = 14;
+/// Start code, stream id and the packet length itself, `PES_packet_length` counts the bytes after
+const PES_PACKET_LEN_PREFIX_SIZE: usize = 6;
+/// Adaptation field with just the PCR: length, flags and 6 bytes of PCR
+const PCR_ADAPTATION_FIELD_SIZE: usize = 8;
+/// Bytes of the frame that fit in the packet starting its PES, when it has no adaptation field
+const PES_FIRST_PAYLOAD_CAPACITY: usize = Bytes::MAX_SIZE - PES_HEADER_SIZE;
+pub const PACKET_SIZE: usize = TsPacket::SIZE;
+/// PAT and PMT written by `write_header`, the SDT follows them when there is one
+const HEADER_PACKETS_SIZE: usize = 2 * PACKET_SIZE;
+const DEFAULT_TRANSPORT_STREAM_ID: u16 = 1;
+const DEFAULT_PROGRAM_NUMBER: u16 = 1;
+/// `original_network_id` of the SDT, from the private use range like ffmpeg's
+const SDT_ORIGINAL_NETWORK_ID: u16 = 0xFF01;
+/// Bytes of the SDT section besides the names, from `table_id` to `CRC_32` with a single
+/// service and its service descriptor
+const SDT_SECTION_FIXED_SIZE: usize = 25;
+/// The SDT is a single packet, the names share what is left of it after `pointer_field`
+pub const MAX_SERVICE_NAMES_SIZE: usize = Bytes::MAX_SIZE - 1 - SDT_SECTION_FIXED_SIZE;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum TsError {
     #[error("Failed to create TS file")]
     FileCreationFailed(#[from] std::io::Error),
@@ -32,18 +73,215 @@ pub enum TsError {
     #[error("Invalid timestamp {0}")]
     InvalidTimestamp(u64),
 
+    #[error("Audio stream is not listed in the PMT")]
+    NoAudioStream,
+
+    #[error("SCTE-35 stream is not listed in the PMT")]
+    NoSpliceStream,
+
+    #[error("Metadata stream is not listed in the PMT")]
+    NoMetadataStream,
+
     #[error("Packet payload exceeded packet limit")]
     PayloadTooBig,
 
     #[error("Clock reference value of {0} exceeds maximum")]
     ClockValueOutOfRange(u64),
+
+    #[error("Service provider and name are {0} bytes, at most {MAX_SERVICE_NAMES_SIZE} fit")]
+    ServiceNamesTooLong(usize),
+
+    #[error("Invalid PIDs: {0}")]
+    InvalidPids(String),
     #[error("Mpeg2TsError: {0}")]
     Mpeg2TsError(#[from] mpeg2ts::Error),
 }
 
+/// Kind of damage done to the output by [`FaultInjection`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Skips a value of the continuity counter
+    ContinuityError,
+    /// Leaves the packet out of the output
+    DropPacket,
+}
+
+/// Deliberately corrupts every n-th media packet, it is only meant for testing how players
+/// handle broken streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultInjection {
+    pub kind: FaultKind,
+    pub every_nth: usize,
+}
+
+impl FromStr for FaultInjection {
+    type Err = String;
+
+    /// Parses `<continuity|drop>:<every_nth>`, e.g. `drop:100`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, every_nth) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected <continuity|drop>:<every_nth>, got {s}"))?;
+        let kind = match kind {
+            "continuity" => FaultKind::ContinuityError,
+            "drop" => FaultKind::DropPacket,
+            _ => return Err(format!("Unknown fault kind {kind}")),
+        };
+        let every_nth = every_nth
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("Invalid packet interval {every_nth}"))?;
+        Ok(Self { kind, every_nth })
+    }
+}
+
+/// Names of the program in the SDT, the service descriptor of ETSI EN 300 468 section 6.2.33
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServiceDescription {
+    service_provider: String,
+    service_name: String,
+}
+
+impl ServiceDescription {
+    pub fn new(service_provider: String, service_name: String) -> Result<Self, TsError> {
+        let size = service_provider.len() + service_name.len();
+        if size > MAX_SERVICE_NAMES_SIZE {
+            return Err(TsError::ServiceNamesTooLong(size));
+        }
+        Ok(Self {
+            service_provider,
+            service_name,
+        })
+    }
+}
+
+/// Where the program is found in the stream, for systems that expect it on specific PIDs. The
+/// video packets carry the PCR too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamIds {
+    pmt_pid: u16,
+    video_pid: u16,
+    /// `stream_id` of the video PES, one of the video stream ids 0xE0 to 0xEF
+    video_stream_id: u8,
+}
+
+impl StreamIds {
+    pub fn new(pmt_pid: u16, video_pid: u16, video_stream_id: u8) -> Result<Self, TsError> {
+        for (name, pid) in [("PMT", pmt_pid), ("video", video_pid)] {
+            if !(MIN_ES_PID..NULL_PID).contains(&pid) {
+                return Err(TsError::InvalidPids(format!(
+                    "{name} PID {pid:#06x} is not between {MIN_ES_PID:#06x} and {:#06x}",
+                    NULL_PID - 1
+                )));
+            }
+            if [SDT_PID, AUDIO_ES_PID, SCTE35_PID, METADATA_PID].contains(&pid) {
+                return Err(TsError::InvalidPids(format!(
+                    "{name} PID {pid:#06x} is taken by the SDT, the audio, the SCTE-35 cues or \
+                     the metadata"
+                )));
+            }
+        }
+        if pmt_pid == video_pid {
+            return Err(TsError::InvalidPids(format!(
+                "PMT and video share PID {pmt_pid:#06x}"
+            )));
+        }
+        if video_stream_id >> 4 != 0xE {
+            return Err(TsError::InvalidPids(format!(
+                "Video stream id {video_stream_id:#04x} is not between 0xe0 and 0xef"
+            )));
+        }
+        Ok(Self {
+            pmt_pid,
+            video_pid,
+            video_stream_id,
+        })
+    }
+
+    pub fn pmt_pid(&self) -> u16 {
+        self.pmt_pid
+    }
+
+    pub fn video_pid(&self) -> u16 {
+        self.video_pid
+    }
+
+    pub fn video_stream_id(&self) -> u8 {
+        self.video_stream_id
+    }
+}
+
+impl Default for StreamIds {
+    fn default() -> Self {
+        Self {
+            pmt_pid: DEFAULT_PMT_PID,
+            video_pid: DEFAULT_VIDEO_ES_PID,
+            video_stream_id: DEFAULT_PES_VIDEO_STREAM_ID,
+        }
+    }
+}
+
+/// `splice_insert` of SCTE 35 section 9.7.3 for the whole program, at a given time rather than
+/// immediately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct SpliceInsert {
+    pub splice_event_id: u32,
+    /// Leaving the network feed for an ad break, returning to it when unset
+    pub out_of_network: bool,
+    /// Length of the break, the splicer returns to the network feed after it by itself
+    pub break_duration_ms: Option<u64>,
+}
+
+/// Frames that carry a PCR, every keyframe since players start decoding at them, and at least a
+/// frame every `PCR_INTERVAL_MS`
+#[derive(Debug, Default, Clone)]
+pub struct PcrSchedule {
+    /// Decode time of the last frame with a PCR, in milliseconds
+    last_pcr_ms: Option<u64>,
+}
+
+impl PcrSchedule {
+    /// Whether the frame decoded at `timestamp` ms carries a PCR, frames have to be passed in
+    /// decode order
+    pub fn needs_pcr(&mut self, timestamp: u64, keyframe: bool) -> bool {
+        let needs_pcr = keyframe
+            || self
+                .last_pcr_ms
+                .is_none_or(|last| timestamp.saturating_sub(last) >= PCR_INTERVAL_MS);
+        if needs_pcr {
+            self.last_pcr_ms = Some(timestamp);
+        }
+        needs_pcr
+    }
+}
+
 pub struct TransportStream {
     video_continuity_counter: ContinuityCounter,
-    packets: Vec<TsPacket>,
+    audio_continuity_counter: ContinuityCounter,
+    splice_continuity_counter: ContinuityCounter,
+    metadata_continuity_counter: ContinuityCounter,
+    /// Pushed packets with the decode time of their PES in milliseconds
+    packets: Vec<(u64, TsPacket)>,
+    /// Media packets already written by `write_packets`
+    written_packets: usize,
+    fault_injection: Option<FaultInjection>,
+    pts_range: Option<(u64, u64)>,
+    pcr_schedule: PcrSchedule,
+    /// Sets `discontinuity_indicator` along with the next PCR
+    discontinuity: bool,
+    zero_base: bool,
+    /// Timestamp of the first push, in milliseconds, subtracted from all of them `with_zero_base`
+    timestamp_base: Option<u64>,
+    video_codec: VideoCodec,
+    video_descriptors: Vec<Descriptor>,
+    has_audio: bool,
+    has_splices: bool,
+    has_metadata: bool,
+    transport_stream_id: u16,
+    program_number: u16,
+    stream_ids: StreamIds,
+    service_description: Option<ServiceDescription>,
 }
 
 impl TransportStream {
@@ -51,20 +289,173 @@ impl TransportStream {
         Self::default()
     }
 
+    pub fn with_fault_injection(mut self, fault_injection: Option<FaultInjection>) -> Self {
+        self.fault_injection = fault_injection;
+        self
+    }
+
+    /// Sets `transport_stream_id` of the PAT, 1 by default
+    pub fn with_transport_stream_id(mut self, transport_stream_id: u16) -> Self {
+        self.transport_stream_id = transport_stream_id;
+        self
+    }
+
+    /// Sets the number of the program in both PAT and PMT, 1 by default. 0 is reserved for the
+    /// network PID.
+    pub fn with_program_number(mut self, program_number: u16) -> Self {
+        self.program_number = program_number;
+        self
+    }
+
+    /// Sets the PIDs of the PMT and the video and the stream id of the video, 256, 257 and 0xE0
+    /// by default
+    pub fn with_stream_ids(mut self, stream_ids: StreamIds) -> Self {
+        self.stream_ids = stream_ids;
+        self
+    }
+
+    /// Writes an SDT with the names of the program after PAT and PMT, there is none by default.
+    /// Some set-top boxes and broadcast validators want it.
+    pub fn with_service_description(
+        mut self,
+        service_description: Option<ServiceDescription>,
+    ) -> Self {
+        self.service_description = service_description;
+        self
+    }
+
+    /// Marks the time base of the stream as discontinuous with the previous segment, the first
+    /// PCR is not continued from it
+    pub fn with_discontinuity(mut self, discontinuity: bool) -> Self {
+        self.discontinuity = discontinuity;
+        self
+    }
+
+    /// Starts PTS, DTS and PCR at zero, the timestamp of the first push is subtracted from the
+    /// timestamps of all the pushes. PTS is relative to the segment then rather than to the log,
+    /// so it stays far from the 33-bit wrap however late in a long log the segment is. By
+    /// default the timestamps are taken as they are pushed, for the segments of a playlist whose
+    /// timestamps go on from segment to segment.
+    pub fn with_zero_base(mut self, zero_base: bool) -> Self {
+        self.zero_base = zero_base;
+        self
+    }
+
+    /// Sets the `stream_type` of the video in the PMT, H264 by default
+    pub fn with_video_codec(mut self, video_codec: VideoCodec) -> Self {
+        self.video_codec = video_codec;
+        self
+    }
+
+    /// Lists an ADTS AAC elementary stream in the PMT, `push_audio` needs it
+    pub fn with_audio(mut self) -> Self {
+        self.has_audio = true;
+        self
+    }
+
+    /// Lists the SCTE-35 cues in the PMT, `push_splice` needs it
+    pub fn with_splices(mut self) -> Self {
+        self.has_splices = true;
+        self
+    }
+
+    /// Lists an ID3 timed metadata stream in the PMT, `push_metadata` needs it
+    pub fn with_metadata(mut self) -> Self {
+        self.has_metadata = true;
+        self
+    }
+
+    /// Adds a descriptor to the video elementary stream entry of the PMT
+    pub fn add_video_descriptor(&mut self, descriptor: Descriptor) {
+        self.video_descriptors.push(descriptor);
+    }
+
+    /// Lowest and highest PTS of the pushed frames, in milliseconds like `push_video` takes them,
+    /// relative to the first push `with_zero_base`
+    pub fn timestamp_range(&self) -> Option<(u64, u64)> {
+        self.pts_range
+    }
+
+    /// Writes PAT, PMT and the pushed packets. Like every write of the stream it writes whole
+    /// packets only, a failed write is an error rather than a shorter stream.
     pub fn write_to<W: Write>(&mut self, wrt: W) -> Result<W, TsError> {
+        let wrt = self.write_header(wrt)?;
+        self.write_packets(wrt)
+    }
+
+    /// Size of what `write_header` writes
+    pub fn header_size(&self) -> usize {
+        match self.service_description {
+            Some(_) => HEADER_PACKETS_SIZE + PACKET_SIZE,
+            None => HEADER_PACKETS_SIZE,
+        }
+    }
+
+    /// Writes PAT, PMT and the SDT, so the video descriptors have to be added before
+    pub fn write_header<W: Write>(&self, wrt: W) -> Result<W, TsError> {
         use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
 
         let mut writer = TsPacketWriter::new(wrt);
         writer
-            .write_ts_packet(&default_pat_packet())
+            .write_ts_packet(&default_pat_packet(
+                self.transport_stream_id,
+                self.program_number,
+                self.stream_ids.pmt_pid,
+            ))
             .map_err(|_| TsError::WriteError)?;
 
         writer
-            .write_ts_packet(&default_pmt_packet())
+            .write_ts_packet(&default_pmt_packet(
+                self.program_number,
+                self.stream_ids,
+                self.video_codec,
+                &self.video_descriptors,
+                self.has_audio,
+                self.has_splices,
+                self.has_metadata,
+            ))
             .map_err(|_| TsError::WriteError)?;
 
-        for packet in &self.packets {
-            writer.write_ts_packet(packet)?;
+        if let Some(service_description) = &self.service_description {
+            let sdt = default_sdt_packet(
+                self.transport_stream_id,
+                self.program_number,
+                service_description,
+            )?;
+            writer
+                .write_ts_packet(&sdt)
+                .map_err(|_| TsError::WriteError)?;
+        }
+
+        Ok(writer.into_stream())
+    }
+
+    /// Writes the packets pushed since the last call and forgets them, so a stream can be
+    /// written while it is being muxed. Audio and video are interleaved by decode time, the
+    /// packets of a PES stay in order.
+    pub fn write_packets<W: Write>(&mut self, wrt: W) -> Result<W, TsError> {
+        use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
+
+        let mut writer = TsPacketWriter::new(wrt);
+        let first_idx = self.written_packets;
+        self.written_packets += self.packets.len();
+        // Stable, so it keeps the order of packets with the same timestamp
+        self.packets.sort_by_key(|(timestamp, _)| *timestamp);
+        let packets = self.packets.drain(..).map(|(_, packet)| packet);
+        for (idx, mut packet) in (first_idx..).zip(packets) {
+            match self.fault_injection {
+                Some(fault) if (idx + 1) % fault.every_nth == 0 => match fault.kind {
+                    FaultKind::ContinuityError => {
+                        warn!("Fault injection: skipping continuity counter of packet {idx}");
+                        packet.header.continuity_counter.increment();
+                        writer.write_ts_packet(&packet)?;
+                    }
+                    FaultKind::DropPacket => {
+                        warn!("Fault injection: dropping packet {idx}");
+                    }
+                },
+                _ => writer.write_ts_packet(&packet)?,
+            }
         }
 
         Ok(writer.into_stream())
@@ -75,40 +466,37 @@ impl TransportStream {
         timestamp: u64,
         composition_time: u64,
         keyframe: bool,
-        video: Vec<u8>,
+        video: &[u8],
     ) -> Result<(), TsError> {
         use mpeg2ts::{
             es::StreamId,
             ts::{payload, AdaptationField},
         };
 
-        let mut header = default_ts_header(VIDEO_ES_PID)?;
-        header.continuity_counter = self.video_continuity_counter;
+        let header = default_ts_header(self.stream_ids.video_pid)?;
 
-        let mut buf = Cursor::new(video.as_slice());
-        let packet = {
-            let data = {
-                let pos = buf.position() as usize;
-                let items = buf.remaining().min(153 - 1);
-                let pes_data = if buf.remaining() < 153 {
-                    &(buf.get_ref()[pos..pos + items])
-                } else {
-                    let pos = buf.position() as usize;
-                    let items = buf.remaining().min(153 - 1);
-                    &(buf.get_ref()[pos..pos + items])
-                };
-                make_raw_payload(pes_data)?
-            };
-            buf.advance(data.len());
+        let timestamp = self.stream_time(timestamp);
+        let pts_ms = timestamp + composition_time;
+        self.pts_range = match self.pts_range {
+            Some((min, max)) => Some((min.min(pts_ms), max.max(pts_ms))),
+            None => Some((pts_ms, pts_ms)),
+        };
 
-            let pcr = make_clock_reference(timestamp * 90)?;
+        let needs_pcr = self.pcr_schedule.needs_pcr(timestamp, keyframe);
+        let capacity = if needs_pcr {
+            PES_FIRST_PAYLOAD_CAPACITY - PCR_ADAPTATION_FIELD_SIZE
+        } else {
+            PES_FIRST_PAYLOAD_CAPACITY
+        };
+        let (first, remaining) = video.split_at(video.len().min(capacity));
 
-            let adaptation_field = if keyframe {
+        let packet = {
+            let adaptation_field = if needs_pcr {
                 Some(AdaptationField {
-                    discontinuity_indicator: false,
-                    random_access_indicator: true,
+                    discontinuity_indicator: std::mem::take(&mut self.discontinuity),
+                    random_access_indicator: keyframe,
                     es_priority_indicator: false,
-                    pcr: Some(pcr),
+                    pcr: Some(make_clock_reference(timestamp)?),
                     opcr: None,
                     splice_countdown: None,
                     transport_private_data: Vec::new(),
@@ -117,13 +505,14 @@ impl TransportStream {
             } else {
                 None
             };
+            let data = make_raw_payload(first)?;
 
-            let pts = make_timestamp((timestamp + composition_time) * 90)?;
-            let dts = make_timestamp(timestamp * 90)?;
+            let pts = make_timestamp(pts_ms)?;
+            let dts = make_timestamp(timestamp)?;
 
             let pes = payload::Pes {
                 header: PesHeader {
-                    stream_id: StreamId::new(PES_VIDEO_STREAM_ID),
+                    stream_id: StreamId::new(self.stream_ids.video_stream_id),
                     priority: false,
                     data_alignment_indicator: false,
                     copyright: false,
@@ -132,6 +521,7 @@ impl TransportStream {
                     dts: Some(dts),
                     escr: None,
                 },
+                // Unbounded, allowed for video only. Frames are often longer than it can say.
                 pes_packet_len: 0,
                 data,
             };
@@ -142,57 +532,220 @@ impl TransportStream {
             }
         };
 
-        self.packets.push(packet);
-        header.continuity_counter.increment();
+        self.push_packet(timestamp, packet);
+        self.push_remaining_payload(timestamp, &header, remaining)
+    }
 
-        while buf.has_remaining() {
-            let raw_payload = {
-                let pos = buf.position() as usize;
-                let items = buf.remaining().min(Bytes::MAX_SIZE - 1);
+    /// Pushes a PES of ADTS AAC frames, `timestamp` is the PTS in milliseconds. The stream has
+    /// to be created `with_audio`.
+    pub fn push_audio(&mut self, timestamp: u64, audio: Vec<u8>) -> Result<(), TsError> {
+        use mpeg2ts::{es::StreamId, ts::payload};
 
-                let pes_data = if buf.remaining() < payload::Bytes::MAX_SIZE {
-                    &(buf.get_ref()[pos..pos + items])
-                } else {
-                    &(buf.get_ref()[pos..pos + items])
-                };
-                make_raw_payload(pes_data)?
-            };
-            buf.advance(raw_payload.len());
+        if !self.has_audio {
+            return Err(TsError::NoAudioStream);
+        }
+        let header = default_ts_header(AUDIO_ES_PID)?;
+        let timestamp = self.stream_time(timestamp);
+
+        let capacity = Bytes::MAX_SIZE - PES_AUDIO_HEADER_SIZE;
+        let (first, remaining) = audio.split_at(audio.len().min(capacity));
+        let data = make_raw_payload(first)?;
+
+        // Unlike video, audio PES has to have its length
+        let pes_packet_len = pes_packet_len(PES_AUDIO_HEADER_SIZE, audio.len());
+        let pes = payload::Pes {
+            header: PesHeader {
+                stream_id: StreamId::new(PES_AUDIO_STREAM_ID),
+                priority: false,
+                data_alignment_indicator: true,
+                copyright: false,
+                original_or_copy: false,
+                pts: Some(make_timestamp(timestamp)?),
+                dts: None,
+                escr: None,
+            },
+            pes_packet_len,
+            data,
+        };
+        let packet = TsPacket {
+            header: header.clone(),
+            adaptation_field: None,
+            payload: Some(TsPayload::Pes(pes)),
+        };
+
+        self.push_packet(timestamp, packet);
+        self.push_remaining_payload(timestamp, &header, remaining)
+    }
+
+    /// Pushes a PES of ID3 tags, `timestamp` is the PTS in milliseconds of the frame they go
+    /// with. The stream has to be created `with_metadata`, Apple's Timed Metadata for HTTP Live
+    /// Streaming.
+    pub fn push_metadata(&mut self, timestamp: u64, id3: Vec<u8>) -> Result<(), TsError> {
+        use mpeg2ts::{es::StreamId, ts::payload};
+
+        if !self.has_metadata {
+            return Err(TsError::NoMetadataStream);
+        }
+        let header = default_ts_header(METADATA_PID)?;
+        let timestamp = self.stream_time(timestamp);
+
+        // With a PTS only, like the audio
+        let capacity = Bytes::MAX_SIZE - PES_AUDIO_HEADER_SIZE;
+        let (first, remaining) = id3.split_at(id3.len().min(capacity));
+        let data = make_raw_payload(first)?;
+
+        let pes = payload::Pes {
+            header: PesHeader {
+                stream_id: StreamId::new(PES_METADATA_STREAM_ID),
+                priority: false,
+                data_alignment_indicator: true,
+                copyright: false,
+                original_or_copy: false,
+                pts: Some(make_timestamp(timestamp)?),
+                dts: None,
+                escr: None,
+            },
+            pes_packet_len: pes_packet_len(PES_AUDIO_HEADER_SIZE, id3.len()),
+            data,
+        };
+        let packet = TsPacket {
+            header: header.clone(),
+            adaptation_field: None,
+            payload: Some(TsPayload::Pes(pes)),
+        };
+
+        self.push_packet(timestamp, packet);
+        self.push_remaining_payload(timestamp, &header, remaining)
+    }
+
+    /// Pushes an SCTE-35 `splice_info_section` with `cue`, `timestamp` is the PTS of the splice
+    /// point in milliseconds, on the time base of the video. It is written before the frame at
+    /// the splice point when pushed before it. The stream has to be created `with_splices`.
+    pub fn push_splice(&mut self, timestamp: u64, cue: SpliceInsert) -> Result<(), TsError> {
+        use mpeg2ts::ts::payload::Section;
 
+        if !self.has_splices {
+            return Err(TsError::NoSpliceStream);
+        }
+        let timestamp = self.stream_time(timestamp);
+        let section = splice_info_section(self.program_number, timestamp, cue)?;
+        let packet = TsPacket {
+            header: default_ts_header(SCTE35_PID)?,
+            adaptation_field: None,
+            payload: Some(TsPayload::Section(Section {
+                pointer_field: 0,
+                data: make_raw_payload(&section)?,
+            })),
+        };
+
+        self.push_packet(timestamp, packet);
+        Ok(())
+    }
+
+    /// Time of the stream of a pushed `timestamp`, both in milliseconds
+    fn stream_time(&mut self, timestamp: u64) -> u64 {
+        if !self.zero_base {
+            return timestamp;
+        }
+        let base = *self.timestamp_base.get_or_insert(timestamp);
+        // Pushes before the first one in time start at zero too
+        timestamp.saturating_sub(base)
+    }
+
+    /// Splits what is left of the PES after its first packet into packets without PES header,
+    /// every one full but the last
+    fn push_remaining_payload(
+        &mut self,
+        timestamp: u64,
+        header: &TsHeader,
+        remaining: &[u8],
+    ) -> Result<(), TsError> {
+        for chunk in remaining.chunks(Bytes::MAX_SIZE) {
+            let raw_payload = make_raw_payload(chunk)?;
             let packet = TsPacket {
                 header: header.clone(),
                 adaptation_field: None,
                 payload: Some(TsPayload::Raw(raw_payload)),
             };
 
-            self.packets.push(packet);
-            header.continuity_counter.increment();
+            self.push_packet(timestamp, packet);
         }
 
-        self.video_continuity_counter = header.continuity_counter;
-
         Ok(())
     }
+
+    /// Every packet of the elementary streams carries a payload, so each one advances the
+    /// continuity counter of its PID exactly once, it wraps after 15
+    fn push_packet(&mut self, timestamp: u64, mut packet: TsPacket) {
+        let continuity_counter = match packet.header.pid.as_u16() {
+            AUDIO_ES_PID => &mut self.audio_continuity_counter,
+            SCTE35_PID => &mut self.splice_continuity_counter,
+            METADATA_PID => &mut self.metadata_continuity_counter,
+            _ => &mut self.video_continuity_counter,
+        };
+        packet.header.continuity_counter = *continuity_counter;
+        continuity_counter.increment();
+        self.packets.push((timestamp, packet));
+    }
 }
 
 impl Default for TransportStream {
     fn default() -> Self {
         Self {
             video_continuity_counter: ContinuityCounter::new(),
+            audio_continuity_counter: ContinuityCounter::new(),
+            splice_continuity_counter: ContinuityCounter::new(),
+            metadata_continuity_counter: ContinuityCounter::new(),
             packets: Vec::new(),
+            written_packets: 0,
+            fault_injection: None,
+            pts_range: None,
+            pcr_schedule: PcrSchedule::default(),
+            discontinuity: false,
+            zero_base: false,
+            timestamp_base: None,
+            video_codec: VideoCodec::H264,
+            video_descriptors: Vec::new(),
+            has_audio: false,
+            has_splices: false,
+            has_metadata: false,
+            transport_stream_id: DEFAULT_TRANSPORT_STREAM_ID,
+            program_number: DEFAULT_PROGRAM_NUMBER,
+            stream_ids: StreamIds::default(),
+            service_description: None,
         }
     }
 }
 
+/// `PES_packet_length` of a PES with a header of `header_size` bytes and `payload_len` bytes of
+/// payload, 0 when it doesn't fit in 16 bits. ISO/IEC 13818-1 section 2.4.3.7 only allows 0 for
+/// video elementary streams.
+fn pes_packet_len(header_size: usize, payload_len: usize) -> u16 {
+    let len = header_size - PES_PACKET_LEN_PREFIX_SIZE + payload_len;
+    u16::try_from(len).unwrap_or_else(|_| {
+        warn!("PES of {payload_len} bytes is too long for PES_packet_length, it is left unbounded");
+        0
+    })
+}
+
 fn make_raw_payload(pes_data: &[u8]) -> Result<ts::payload::Bytes, TsError> {
     ts::payload::Bytes::new(pes_data).map_err(|_| TsError::PayloadTooBig)
 }
 
-fn make_timestamp(ts: u64) -> Result<Timestamp, TsError> {
+/// PTS, DTS and the PCR base count 90 kHz in 33 bits, ISO/IEC 13818-1 section 2.4.2.2. They
+/// wrap around to 0 after about 26.5 hours, players carry on across the wrap.
+const CLOCK_WRAP: u64 = 1 << 33;
+
+/// PTS or DTS of the time in milliseconds, wrapped around to 33 bits. Like the PCR it is on the
+/// time base of the log, not of the segment, unless the stream is `with_zero_base`.
+fn make_timestamp(ms: u64) -> Result<Timestamp, TsError> {
+    let ts = ms * 90 % CLOCK_WRAP;
     Timestamp::new(ts).map_err(|_| TsError::InvalidTimestamp(ts))
 }
 
-fn make_clock_reference(ts: u64) -> Result<ClockReference, TsError> {
+/// PCR of the time in milliseconds, the 27 MHz clock is 300 times the 90 kHz one of PTS
+fn make_clock_reference(ms: u64) -> Result<ClockReference, TsError> {
+    let ts = ms * 90 % CLOCK_WRAP * 300;
     ClockReference::new(ts).map_err(|_| TsError::ClockValueOutOfRange(ts))
 }
 
@@ -208,42 +761,758 @@ fn default_ts_header(pid: u16) -> Result<TsHeader, TsError> {
     })
 }
 
-fn default_pat_packet() -> TsPacket {
+fn default_pat_packet(transport_stream_id: u16, program_number: u16, pmt_pid: u16) -> TsPacket {
     use mpeg2ts::ts::{payload::Pat, ProgramAssociation, VersionNumber};
 
     TsPacket {
         header: default_ts_header(0).unwrap(),
         adaptation_field: None,
         payload: Some(TsPayload::Pat(Pat {
-            transport_stream_id: 1,
+            transport_stream_id,
             version_number: VersionNumber::default(),
             table: vec![ProgramAssociation {
-                program_num: 1,
-                program_map_pid: Pid::new(PMT_PID).unwrap(),
+                program_num: program_number,
+                program_map_pid: Pid::new(pmt_pid).unwrap(),
             }],
         })),
     }
 }
 
-fn default_pmt_packet() -> TsPacket {
+/// SDT of the actual transport stream with the one program, ETSI EN 300 468 section 5.2.3
+fn default_sdt_packet(
+    transport_stream_id: u16,
+    program_number: u16,
+    service_description: &ServiceDescription,
+) -> Result<TsPacket, TsError> {
+    use mpeg2ts::ts::payload::Section;
+
+    let provider = service_description.service_provider.as_bytes();
+    let name = service_description.service_name.as_bytes();
+    // service_type of a digital television service
+    let mut service_descriptor = vec![0x48, 0, 0x01, provider.len() as u8];
+    service_descriptor.extend_from_slice(provider);
+    service_descriptor.push(name.len() as u8);
+    service_descriptor.extend_from_slice(name);
+    service_descriptor[1] = (service_descriptor.len() - 2) as u8;
+
+    let mut section = vec![0x42, 0, 0];
+    section.extend_from_slice(&transport_stream_id.to_be_bytes());
+    // 2 reserved bits, version_number 0 and current_next_indicator set
+    section.push(0b1100_0001);
+    // section_number and last_section_number
+    section.extend_from_slice(&[0, 0]);
+    section.extend_from_slice(&SDT_ORIGINAL_NETWORK_ID.to_be_bytes());
+    section.push(0xFF);
+    section.extend_from_slice(&program_number.to_be_bytes());
+    // 6 reserved bits, no EIT
+    section.push(0b1111_1100);
+    // running_status running, free_CA_mode unset and descriptors_loop_length
+    let running = 4 << 13;
+    section.extend_from_slice(&(running | service_descriptor.len() as u16).to_be_bytes());
+    section.extend_from_slice(&service_descriptor);
+    // section_syntax_indicator, a reserved future use bit, 2 reserved bits and section_length,
+    // which counts the CRC too
+    let section_length = (section.len() - 3 + 4) as u16;
+    section[1..3].copy_from_slice(&(0b1111 << 12 | section_length).to_be_bytes());
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+
+    Ok(TsPacket {
+        header: default_ts_header(SDT_PID)?,
+        adaptation_field: None,
+        payload: Some(TsPayload::Section(Section {
+            pointer_field: 0,
+            data: make_raw_payload(&section)?,
+        })),
+    })
+}
+
+/// 33 bits of a time in 90 kHz after 7 bits of flags and reserved bits, `splice_time` and
+/// `break_duration` of SCTE 35 sections 10.3.1 and 10.3.2
+fn splice_time_bytes(flag: bool, ms: u64) -> Result<[u8; 5], TsError> {
+    let ticks = make_timestamp(ms)?.as_u64();
+    let [_, _, _, bit_32, rest @ ..] = ticks.to_be_bytes();
+    Ok([
+        (flag as u8) << 7 | 0b0111_1110 | bit_32,
+        rest[0],
+        rest[1],
+        rest[2],
+        rest[3],
+    ])
+}
+
+/// `splice_info_section` of SCTE 35 section 9.6 with a `splice_insert` of the program
+fn splice_info_section(
+    program_number: u16,
+    timestamp: u64,
+    cue: SpliceInsert,
+) -> Result<Vec<u8>, TsError> {
+    let mut command = cue.splice_event_id.to_be_bytes().to_vec();
+    // splice_event_cancel_indicator unset and 7 reserved bits
+    command.push(0b0111_1111);
+    // out_of_network_indicator, program_splice_flag set, duration_flag,
+    // splice_immediate_flag unset and 4 reserved bits
+    command.push(
+        (cue.out_of_network as u8) << 7
+            | 1 << 6
+            | (cue.break_duration_ms.is_some() as u8) << 5
+            | 0b0000_1111,
+    );
+    // time_specified_flag set
+    command.extend_from_slice(&splice_time_bytes(true, timestamp)?);
+    if let Some(duration_ms) = cue.break_duration_ms {
+        // auto_return set
+        command.extend_from_slice(&splice_time_bytes(true, duration_ms)?);
+    }
+    command.extend_from_slice(&program_number.to_be_bytes());
+    // avail_num and avails_expected
+    command.extend_from_slice(&[0, 0]);
+
+    let mut section = vec![0xFC, 0, 0];
+    // protocol_version, encrypted_packet unset, encryption_algorithm and pts_adjustment 0
+    section.extend_from_slice(&[0; 6]);
+    // cw_index
+    section.push(0);
+    // tier 0xFFF, meaning any, and splice_command_length
+    let tier = 0xFFF << 12;
+    section.extend_from_slice(&(tier | command.len() as u32).to_be_bytes()[1..]);
+    section.push(SPLICE_INSERT_COMMAND);
+    section.extend_from_slice(&command);
+    // descriptor_loop_length
+    section.extend_from_slice(&[0, 0]);
+    // section_syntax_indicator and private_indicator unset, sap_type 3, which is unspecified,
+    // and section_length, which counts the CRC too
+    let section_length = (section.len() - 3 + 4) as u16;
+    section[1..3].copy_from_slice(&(0b0011 << 12 | section_length).to_be_bytes());
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    Ok(section)
+}
+
+/// CRC of the PSI sections, ISO/IEC 13818-1 annex A
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Writes null packets until the `written` bytes of a stream are at least `target_size`, rounded
+/// up to whole packets. Nothing is written when the stream is as long already.
+pub fn write_null_padding<W: Write>(
+    wrt: W,
+    written: usize,
+    target_size: usize,
+) -> Result<W, TsError> {
+    use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
+
+    let null_packet = TsPacket {
+        header: default_ts_header(NULL_PID)?,
+        adaptation_field: None,
+        payload: Some(TsPayload::Raw(make_raw_payload(&[0xFF; Bytes::MAX_SIZE])?)),
+    };
+    let padding_packets = target_size.saturating_sub(written).div_ceil(TsPacket::SIZE);
+    let mut writer = TsPacketWriter::new(wrt);
+    for _ in 0..padding_packets {
+        writer.write_ts_packet(&null_packet)?;
+    }
+    Ok(writer.into_stream())
+}
+
+/// Size of a frame of `len` bytes muxed by `push_video`, the PCR makes room for the frame in the
+/// first packet smaller
+pub fn muxed_video_size(len: usize, with_pcr: bool) -> usize {
+    let first_payload_capacity = if with_pcr {
+        PES_FIRST_PAYLOAD_CAPACITY - PCR_ADAPTATION_FIELD_SIZE
+    } else {
+        PES_FIRST_PAYLOAD_CAPACITY
+    };
+    let packets = 1 + len
+        .saturating_sub(first_payload_capacity)
+        .div_ceil(Bytes::MAX_SIZE);
+    packets * TsPacket::SIZE
+}
+
+/// Size of a PES of `len` bytes muxed by `push_audio` or `push_metadata`, their header has a PTS
+/// only
+pub fn muxed_pes_size(len: usize) -> usize {
+    let packets = 1 + len
+        .saturating_sub(Bytes::MAX_SIZE - PES_AUDIO_HEADER_SIZE)
+        .div_ceil(Bytes::MAX_SIZE);
+    packets * TsPacket::SIZE
+}
+
+/// AVC video descriptor, ISO/IEC 13818-1 section 2.6.64
+pub fn avc_video_descriptor(sps: &Sps) -> Descriptor {
+    // AVC_still_present and AVC_24_hour_picture_flag unset,
+    // Frame_Packing_SEI_not_present_flag set, 5 reserved bits
+    let flags = 0b0011_1111;
+    Descriptor {
+        tag: 0x28,
+        data: vec![
+            sps.profile_idc,
+            sps.constraint_set_flags,
+            sps.level_idc,
+            flags,
+        ],
+    }
+}
+
+/// Caption service descriptor, ATSC A/65 section 6.9.2, with the CEA-608 captions of line 21
+/// field 1, CC1, that the SEI of the video carry
+pub fn caption_service_descriptor() -> Descriptor {
+    // 3 reserved bits and number_of_services
+    let services = 0b1110_0001;
+    // digital_cc unset, a reserved bit, 5 reserved bits and line21_field unset
+    let line21 = 0b0111_1110;
+    // easy_reader and wide_aspect_ratio unset, 14 reserved bits
+    let [flags_hi, flags_lo] = 0b0011_1111_1111_1111u16.to_be_bytes();
+    Descriptor {
+        tag: 0x86,
+        data: vec![services, b'e', b'n', b'g', line21, flags_hi, flags_lo],
+    }
+}
+
+fn default_pmt_packet(
+    program_number: u16,
+    stream_ids: StreamIds,
+    video_codec: VideoCodec,
+    video_descriptors: &[Descriptor],
+    has_audio: bool,
+    has_splices: bool,
+    has_metadata: bool,
+) -> TsPacket {
     use mpeg2ts::{
         es::StreamType,
         ts::{payload::Pmt, EsInfo, VersionNumber},
     };
 
+    let stream_type = match video_codec {
+        VideoCodec::H264 => StreamType::H264,
+        VideoCodec::Hevc => StreamType::H265,
+    };
+    let mut es_info = vec![EsInfo {
+        stream_type,
+        elementary_pid: Pid::new(stream_ids.video_pid).unwrap(),
+        descriptors: video_descriptors.to_vec(),
+    }];
+    if has_audio {
+        es_info.push(EsInfo {
+            stream_type: StreamType::AdtsAac,
+            elementary_pid: Pid::new(AUDIO_ES_PID).unwrap(),
+            descriptors: vec![],
+        });
+    }
+    let mut program_info = Vec::new();
+    if has_splices {
+        // Registration descriptor of SCTE 35 section 8.1
+        program_info.push(Descriptor {
+            tag: 0x05,
+            data: b"CUEI".to_vec(),
+        });
+        es_info.push(EsInfo {
+            // 0x86, SCTE-35 in the SCTE registered range, mpeg2ts names it after DTS
+            stream_type: StreamType::Dts8ChannelLosslessAudio,
+            elementary_pid: Pid::new(SCTE35_PID).unwrap(),
+            // cue_identifier_descriptor of SCTE 35 section 8.2, cue_stream_type 0 is
+            // splice_insert, splice_null and splice_schedule
+            descriptors: vec![Descriptor {
+                tag: 0x8A,
+                data: vec![0x00],
+            }],
+        });
+    }
+
+    if has_metadata {
+        // metadata_application_format and metadata_format of ID3, ISO/IEC 13818-1 sections
+        // 2.6.58 and 2.6.60, then metadata_service_id 0
+        let id3_format = [
+            0xFF, 0xFF, b'I', b'D', b'3', b' ', 0xFF, b'I', b'D', b'3', b' ', 0,
+        ];
+        // metadata_pointer_descriptor, metadata_locator_record_flag unset, MPEG_carriage_flags
+        // 0, that is in this transport stream, 5 reserved bits and the program number
+        let mut pointer = id3_format.to_vec();
+        pointer.push(0b0001_1111);
+        pointer.extend_from_slice(&program_number.to_be_bytes());
+        program_info.push(Descriptor {
+            tag: 0x25,
+            data: pointer,
+        });
+        // metadata_descriptor, decoder_config_flags and DSM-CC_flag unset, 4 reserved bits
+        let mut metadata = id3_format.to_vec();
+        metadata.push(0b0000_1111);
+        es_info.push(EsInfo {
+            stream_type: StreamType::PacketizedMetadata,
+            elementary_pid: Pid::new(METADATA_PID).unwrap(),
+            descriptors: vec![Descriptor {
+                tag: 0x26,
+                data: metadata,
+            }],
+        });
+    }
+
     TsPacket {
-        header: default_ts_header(PMT_PID).unwrap(),
+        header: default_ts_header(stream_ids.pmt_pid).unwrap(),
         adaptation_field: None,
         payload: Some(TsPayload::Pmt(Pmt {
-            program_num: 1,
-            pcr_pid: Some(Pid::new(VIDEO_ES_PID).unwrap()),
+            program_num: program_number,
+            pcr_pid: Some(Pid::new(stream_ids.video_pid).unwrap()),
             version_number: VersionNumber::default(),
-            program_info: vec![],
-            es_info: vec![EsInfo {
-                stream_type: StreamType::H264,
-                elementary_pid: Pid::new(VIDEO_ES_PID).unwrap(),
-                descriptors: vec![],
-            }],
+            program_info,
+            es_info,
         })),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mpeg2ts::es::StreamType;
+    use mpeg2ts::ts::{ReadTsPacket, TsPacketReader};
+
+    const KEYFRAME: [u8; 6] = [0, 0, 0, 1, 0x65, 0xAB];
+
+    fn read_packets(stream: &[u8]) -> Vec<TsPacket> {
+        let mut reader = TsPacketReader::new(stream);
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// PTS and DTS of the PES and the PCR of their packets, in 90 kHz
+    fn pes_timestamps(packets: &[TsPacket]) -> Vec<(u64, u64, Option<u64>)> {
+        packets
+            .iter()
+            .filter_map(|packet| match &packet.payload {
+                Some(TsPayload::Pes(pes)) => Some((
+                    pes.header.pts?.as_u64(),
+                    pes.header.dts?.as_u64(),
+                    packet
+                        .adaptation_field
+                        .as_ref()
+                        .and_then(|field| field.pcr)
+                        .map(|pcr| pcr.as_u64() / 300),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn timestamp_range_is_the_lowest_and_highest_pts() {
+        let mut ts = TransportStream::new();
+        assert_eq!(ts.timestamp_range(), None);
+        // I P B B in decode order, presented as I B B P
+        for (dts, composition) in [(1000, 50), (1050, 150), (1100, 0), (1150, 0)] {
+            ts.push_video(dts, composition, dts == 1000, &KEYFRAME)
+                .unwrap();
+        }
+        assert_eq!(ts.timestamp_range(), Some((1050, 1200)));
+        // Writing the packets doesn't forget it
+        ts.write_to(Vec::new()).unwrap();
+        assert_eq!(ts.timestamp_range(), Some((1050, 1200)));
+    }
+
+    #[test]
+    fn video_descriptors_are_written_to_the_pmt() {
+        let sps = Sps::parse(&crate::h264::tests::sps_nal(1, (0, 0))).unwrap();
+        let mut ts = TransportStream::new();
+        ts.add_video_descriptor(avc_video_descriptor(&sps));
+        ts.add_video_descriptor(caption_service_descriptor());
+        let header = ts.write_header(Vec::new()).unwrap();
+
+        let pmt = &header[PACKET_SIZE..];
+        let avc = [0x28, 4, 66, 0xC0, 31, 0b0011_1111];
+        let captions = [0x86, 7, 0b1110_0001, b'e', b'n', b'g', 0x7E, 0x3F, 0xFF];
+        let descriptors = [&avc[..], &captions[..]].concat();
+        assert!(pmt.windows(descriptors.len()).any(|w| w == descriptors));
+
+        let packets = read_packets(&header);
+        let Some(TsPayload::Pmt(pmt)) = &packets[1].payload else {
+            panic!("No PMT in {:?}", packets[1]);
+        };
+        let video = &pmt.es_info[0];
+        assert_eq!(video.descriptors.len(), 2);
+        assert_eq!(video.descriptors[0].data, avc[2..]);
+        assert_eq!(video.descriptors[1].data, captions[2..]);
+    }
+
+    #[test]
+    fn transport_stream_id_and_program_number_are_in_pat_and_pmt() {
+        let ts = TransportStream::new()
+            .with_transport_stream_id(0x1234)
+            .with_program_number(42);
+        let packets = read_packets(&ts.write_header(Vec::new()).unwrap());
+        let Some(TsPayload::Pat(pat)) = &packets[0].payload else {
+            panic!("No PAT in {:?}", packets[0]);
+        };
+        assert_eq!(pat.transport_stream_id, 0x1234);
+        assert_eq!(pat.table.len(), 1);
+        assert_eq!(pat.table[0].program_num, 42);
+        assert_eq!(pat.table[0].program_map_pid.as_u16(), DEFAULT_PMT_PID);
+        let Some(TsPayload::Pmt(pmt)) = &packets[1].payload else {
+            panic!("No PMT in {:?}", packets[1]);
+        };
+        assert_eq!(pmt.program_num, 42);
+    }
+
+    #[test]
+    fn continuity_counters_go_on_across_pushes_per_pid() {
+        let mut ts = TransportStream::new().with_audio();
+        // 3 packets of video and one of audio every frame
+        let frame = [&KEYFRAME[..], &[0xAB; 400]].concat();
+        for idx in 0..6 {
+            ts.push_video(idx * 50, 0, idx == 0, &frame).unwrap();
+            ts.push_audio(idx * 50, vec![0xAA; 50]).unwrap();
+        }
+        let packets = read_packets(&ts.write_to(Vec::new()).unwrap());
+        let video: Vec<u8> = (0..18).map(|cc| cc % 16).collect();
+        assert_eq!(continuity_counters(&packets, DEFAULT_VIDEO_ES_PID), video);
+        assert_eq!(
+            continuity_counters(&packets, AUDIO_ES_PID),
+            [0, 1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn nal_units_take_as_many_packets_as_their_payload_needs() {
+        let nal = [&KEYFRAME[..], &[0xAB; 4096 - KEYFRAME.len()]].concat();
+        let mut ts = TransportStream::new();
+        ts.push_video(0, 0, true, &nal).unwrap();
+        let packets = read_packets(&ts.write_to(Vec::new()).unwrap());
+        let video = &packets[2..];
+        // 157 bytes after the PES header and the PCR, then 184 bytes a packet
+        assert_eq!(video.len(), 1 + (4096 - 157usize).div_ceil(184));
+        assert_eq!(video.len() * PACKET_SIZE, muxed_video_size(nal.len(), true));
+        assert_eq!(es_payload(video, DEFAULT_VIDEO_ES_PID), nal);
+    }
+
+    #[test]
+    fn payloads_around_the_packet_capacity_are_split_without_losing_bytes() {
+        for len in [1, 183, 184, 185, 10_000] {
+            for keyframe in [true, false] {
+                let video: Vec<u8> = (0..len).map(|idx| idx as u8).collect();
+                let mut ts = TransportStream::new();
+                // The first frame gets a PCR anyway, the second only as a keyframe
+                ts.push_video(0, 0, true, &KEYFRAME).unwrap();
+                ts.push_video(50, 0, keyframe, &video).unwrap();
+                let packets = read_packets(&ts.write_to(Vec::new()).unwrap());
+                let frame = &packets[3..];
+                assert_eq!(
+                    frame.len() * PACKET_SIZE,
+                    muxed_video_size(len, keyframe),
+                    "{len} bytes"
+                );
+                assert_eq!(
+                    es_payload(frame, DEFAULT_VIDEO_ES_PID),
+                    video,
+                    "{len} bytes"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn custom_pids_and_stream_id_are_read_back() {
+        let stream_ids = StreamIds::new(0x1000, 0x100, 0xE1).unwrap();
+        let mut ts = TransportStream::new().with_stream_ids(stream_ids);
+        ts.push_video(0, 0, true, &KEYFRAME).unwrap();
+        let packets = read_packets(&ts.write_to(Vec::new()).unwrap());
+
+        let Some(TsPayload::Pat(pat)) = &packets[0].payload else {
+            panic!("No PAT in {:?}", packets[0]);
+        };
+        assert_eq!(pat.table[0].program_map_pid.as_u16(), 0x1000);
+        assert_eq!(packets[1].header.pid.as_u16(), 0x1000);
+        let Some(TsPayload::Pmt(pmt)) = &packets[1].payload else {
+            panic!("No PMT in {:?}", packets[1]);
+        };
+        assert_eq!(pmt.pcr_pid.map(|pid| pid.as_u16()), Some(0x100));
+        assert_eq!(pmt_streams(&packets), [(StreamType::H264, 0x100)]);
+        assert_eq!(packets[2].header.pid.as_u16(), 0x100);
+        let Some(TsPayload::Pes(pes)) = &packets[2].payload else {
+            panic!("No PES in {:?}", packets[2]);
+        };
+        assert_eq!(pes.header.stream_id.as_u8(), 0xE1);
+    }
+
+    #[test]
+    fn pids_of_other_tables_and_streams_are_rejected() {
+        for (pmt_pid, video_pid, stream_id) in [
+            (SDT_PID, 0x100, 0xE0),
+            (0x1000, AUDIO_ES_PID, 0xE0),
+            (0x1000, NULL_PID, 0xE0),
+            (0x100, 0x100, 0xE0),
+            (0x1000, 0x100, 0xC0),
+        ] {
+            assert!(
+                matches!(
+                    StreamIds::new(pmt_pid, video_pid, stream_id),
+                    Err(TsError::InvalidPids(_))
+                ),
+                "{pmt_pid:#x} {video_pid:#x} {stream_id:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_base_starts_the_timestamps_of_late_segments_at_zero() {
+        // 40 days into a log, far past the 33-bit wrap after 26.5 hours
+        let start_ms = 40 * 24 * 3_600_000;
+        let mut ts = TransportStream::new().with_zero_base(true);
+        for frame in 0..3 {
+            ts.push_video(start_ms + frame * 50, 100, frame == 0, &KEYFRAME)
+                .unwrap();
+        }
+        assert_eq!(ts.timestamp_range(), Some((100, 200)));
+        let stream = ts.write_to(Vec::new()).unwrap();
+        assert_eq!(
+            pes_timestamps(&read_packets(&stream)),
+            [
+                (9000, 0, Some(0)),
+                (13500, 4500, None),
+                (18000, 9000, Some(9000))
+            ]
+        );
+    }
+
+    /// Continuity counters of the packets on the PID
+    fn continuity_counters(packets: &[TsPacket], pid: u16) -> Vec<u8> {
+        packets
+            .iter()
+            .filter(|packet| packet.header.pid.as_u16() == pid)
+            .map(|packet| packet.header.continuity_counter.as_u8())
+            .collect()
+    }
+
+    /// Payload of the PES and the packets after them on the PID, put back together
+    fn es_payload(packets: &[TsPacket], pid: u16) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for packet in packets.iter().filter(|p| p.header.pid.as_u16() == pid) {
+            match &packet.payload {
+                Some(TsPayload::Pes(pes)) => payload.extend_from_slice(&pes.data),
+                Some(TsPayload::Raw(data)) => payload.extend_from_slice(data),
+                _ => {}
+            }
+        }
+        payload
+    }
+
+    /// Elementary streams of the PMT, with their PIDs
+    fn pmt_streams(packets: &[TsPacket]) -> Vec<(StreamType, u16)> {
+        packets
+            .iter()
+            .find_map(|packet| match &packet.payload {
+                Some(TsPayload::Pmt(pmt)) => Some(
+                    pmt.es_info
+                        .iter()
+                        .map(|es| (es.stream_type, es.elementary_pid.as_u16()))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn audio_is_listed_in_the_pmt_and_muxed_after_the_video_with_its_pts() {
+        let mut ts = TransportStream::new().with_audio();
+        ts.push_video(1000, 50, true, &KEYFRAME).unwrap();
+        // Two ADTS frames, longer than the first packet of the PES
+        let audio = vec![0xAA; 300];
+        ts.push_audio(1050, audio.clone()).unwrap();
+        let packets = read_packets(&ts.write_to(Vec::new()).unwrap());
+
+        assert_eq!(
+            pmt_streams(&packets),
+            [
+                (StreamType::H264, DEFAULT_VIDEO_ES_PID),
+                (StreamType::AdtsAac, AUDIO_ES_PID)
+            ]
+        );
+        let pids: Vec<u16> = packets.iter().map(|p| p.header.pid.as_u16()).collect();
+        assert_eq!(
+            pids[2..],
+            [DEFAULT_VIDEO_ES_PID, AUDIO_ES_PID, AUDIO_ES_PID]
+        );
+        assert_eq!(muxed_pes_size(audio.len()), 2 * PACKET_SIZE);
+
+        let Some(TsPayload::Pes(pes)) = &packets[3].payload else {
+            panic!("No audio PES in {:?}", packets[3]);
+        };
+        assert_eq!(pes.header.stream_id.as_u8(), PES_AUDIO_STREAM_ID);
+        assert_eq!(pes.header.pts.map(|pts| pts.as_u64()), Some(1050 * 90));
+        assert_eq!(pes.header.dts, None);
+        assert_eq!(pes.pes_packet_len as usize, 8 + audio.len());
+        let Some(TsPayload::Raw(rest)) = &packets[4].payload else {
+            panic!("No audio payload in {:?}", packets[4]);
+        };
+        let mut muxed = pes.data.to_vec();
+        muxed.extend_from_slice(rest);
+        assert_eq!(muxed, audio);
+    }
+
+    #[test]
+    fn audio_needs_the_stream_in_the_pmt() {
+        let mut ts = TransportStream::new();
+        assert!(matches!(
+            ts.push_audio(0, vec![0xAA; 10]),
+            Err(TsError::NoAudioStream)
+        ));
+    }
+
+    /// PIDs and payloads of the packets without parsing them, the reader takes the payload of
+    /// every PID in the PMT for PES
+    fn raw_packets(stream: &[u8]) -> Vec<(u16, &[u8])> {
+        stream
+            .chunks(PACKET_SIZE)
+            .map(|packet| {
+                let pid = u16::from_be_bytes([packet[1] & 0x1F, packet[2]]);
+                let payload_start = match packet[3] & 0x20 {
+                    0 => 4,
+                    _ => 5 + packet[4] as usize,
+                };
+                (pid, &packet[payload_start..])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn null_padding_rounds_the_stream_up_to_whole_packets() {
+        let mut ts = TransportStream::new();
+        ts.push_video(0, 0, true, &KEYFRAME).unwrap();
+        let segment = ts.write_to(Vec::new()).unwrap();
+        for target_size in [0, segment.len(), segment.len() + 1, 10_000] {
+            let padded = write_null_padding(segment.clone(), segment.len(), target_size).unwrap();
+            assert_eq!(padded.len() % PACKET_SIZE, 0, "{target_size}");
+            assert_eq!(padded[..segment.len()], segment, "{target_size}");
+            if target_size <= segment.len() {
+                assert_eq!(padded.len(), segment.len(), "{target_size}");
+            } else {
+                assert!(padded.len() >= target_size, "{target_size}");
+                assert!(padded.len() < target_size + PACKET_SIZE, "{target_size}");
+            }
+            for (pid, payload) in raw_packets(&padded[segment.len()..]) {
+                assert_eq!(pid, NULL_PID);
+                assert!(payload.iter().all(|b| *b == 0xFF));
+            }
+            // Demuxers skip the null packets
+            assert_eq!(pes_timestamps(&read_packets(&padded)).len(), 1);
+        }
+    }
+
+    #[test]
+    fn splice_section_is_on_the_scte35_pid_before_the_frame() {
+        let mut ts = TransportStream::new().with_splices().with_program_number(7);
+        let cue = SpliceInsert {
+            splice_event_id: 42,
+            out_of_network: true,
+            break_duration_ms: Some(30_000),
+        };
+        ts.push_splice(2000, cue).unwrap();
+        ts.push_video(2000, 0, true, &KEYFRAME).unwrap();
+        let stream = ts.write_to(Vec::new()).unwrap();
+
+        let packets = raw_packets(&stream);
+        let pids: Vec<u16> = packets.iter().map(|(pid, _)| *pid).collect();
+        assert_eq!(pids, [0, DEFAULT_PMT_PID, SCTE35_PID, DEFAULT_VIDEO_ES_PID]);
+        // pointer_field, then the section
+        let payload = packets[2].1;
+        assert_eq!(payload[0], 0);
+        let section_length = u16::from_be_bytes([payload[2] & 0x0F, payload[3]]) as usize;
+        let section = &payload[1..4 + section_length];
+        assert_eq!(
+            section,
+            splice_info_section(7, 2000, cue).unwrap().as_slice()
+        );
+        assert_eq!(section[0], 0xFC);
+        assert_eq!(section[13], SPLICE_INSERT_COMMAND);
+        assert_eq!(section[14..18], 42u32.to_be_bytes());
+        // The CRC of a section with its CRC is 0
+        assert_eq!(crc32_mpeg2(section), 0);
+        assert!(payload[4 + section_length..].iter().all(|&b| b == 0xFF));
+
+        let packets = read_packets(&stream[..2 * PACKET_SIZE]);
+        let Some(TsPayload::Pmt(pmt)) = &packets[1].payload else {
+            panic!("No PMT in {:?}", packets[1]);
+        };
+        assert_eq!(pmt.program_info[0].data, b"CUEI");
+        assert_eq!(pmt.es_info[1].elementary_pid.as_u16(), SCTE35_PID);
+    }
+
+    #[test]
+    fn splices_need_the_stream_in_the_pmt() {
+        let mut ts = TransportStream::new();
+        let cue = SpliceInsert {
+            splice_event_id: 1,
+            out_of_network: false,
+            break_duration_ms: None,
+        };
+        assert!(matches!(
+            ts.push_splice(0, cue),
+            Err(TsError::NoSpliceStream)
+        ));
+    }
+
+    /// ID3v2.4 tag with a `TXXX` frame of the text
+    fn id3_tag(text: &str) -> Vec<u8> {
+        let mut frame = b"TXXX".to_vec();
+        // Syncsafe size, no flags, UTF-8 and an empty description
+        let frame_len = 2 + text.len() as u8;
+        frame.extend_from_slice(&[0, 0, 0, frame_len, 0, 0, 3, 0]);
+        frame.extend_from_slice(text.as_bytes());
+        let mut tag = b"ID3".to_vec();
+        tag.extend_from_slice(&[4, 0, 0, 0, 0, 0, frame.len() as u8]);
+        tag.extend_from_slice(&frame);
+        tag
+    }
+
+    #[test]
+    fn id3_tags_round_trip_through_the_metadata_stream() {
+        let mut ts = TransportStream::new().with_metadata();
+        ts.push_video(500, 0, true, &KEYFRAME).unwrap();
+        let id3 = id3_tag("lat=52.52;lon=13.40;speed=12.5");
+        ts.push_metadata(500, id3.clone()).unwrap();
+        let packets = read_packets(&ts.write_to(Vec::new()).unwrap());
+
+        assert_eq!(
+            pmt_streams(&packets),
+            [
+                (StreamType::H264, DEFAULT_VIDEO_ES_PID),
+                (StreamType::PacketizedMetadata, METADATA_PID)
+            ]
+        );
+        let pes: Vec<_> = packets
+            .iter()
+            .filter(|packet| packet.header.pid.as_u16() == METADATA_PID)
+            .filter_map(|packet| match &packet.payload {
+                Some(TsPayload::Pes(pes)) => Some(pes),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pes.len(), 1);
+        assert_eq!(pes[0].header.stream_id.as_u8(), PES_METADATA_STREAM_ID);
+        assert_eq!(pes[0].header.pts.map(|pts| pts.as_u64()), Some(500 * 90));
+        assert_eq!(&pes[0].data[..], id3.as_slice());
+        assert_eq!(muxed_pes_size(id3.len()), PACKET_SIZE);
+    }
+
+    #[test]
+    fn metadata_needs_the_stream_in_the_pmt() {
+        let mut ts = TransportStream::new();
+        assert!(matches!(
+            ts.push_metadata(0, id3_tag("speed=0")),
+            Err(TsError::NoMetadataStream)
+        ));
+    }
+}
@@ -0,0 +1,1156 @@
+// Muxing the frame files of a log to MPEG-TS, MP4 and fragmented MP4 segments
+use crate::errors;
+use crate::h264::{self, NalUnitType, Sps, VideoCodec};
+use crate::isobmff::{self, FragmentSample};
+use crate::lookups::find_parameter_sets;
+use crate::mpegts::{
+    self, FaultInjection, PcrSchedule, ServiceDescription, SpliceInsert, StreamIds, TransportStream,
+};
+use crate::retry;
+use crate::routes::frame_positions;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use mp4::{AvcConfig, MediaConfig, Mp4Config, Mp4Sample, TrackConfig, TrackType};
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::{env, fs};
+use tracing::{debug, info, warn};
+
+/// The MP4 muxing writes `avc1` sample entries, there is no `hvc1` counterpart yet
+pub fn check_mp4_codec() -> errors::Result<()> {
+    if *VIDEO_CODEC != VideoCodec::H264 {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "MP4 of {:?} frames is not supported, use MPEG-TS",
+            *VIDEO_CODEC
+        )))?
+    }
+    Ok(())
+}
+
+/// Frame periods from the decoding of every frame to its presentation, from the POC of the
+/// slices. Reading them takes another pass over the frames, it is skipped when the SPS says
+/// the frames are presented in decode order. The POC of the `pic_order_cnt_type` 1 is not
+/// derived, such frames are presented in decode order too. The presentation order is only
+/// known within the frames of the segment, reordered frames that are cut apart by the segment
+/// boundary get the order wrong. HEVC slice headers are not read, the frames are presented in
+/// decode order.
+fn composition_offsets(
+    base_path: &str,
+    streams: &[impl AsRef<str>],
+    sps: Option<&Sps>,
+) -> errors::Result<Vec<u64>> {
+    let Some(fields) = sps
+        .filter(|sps| sps.hevc_profile.is_none())
+        .map(|sps| sps.slice_header)
+        .filter(|f| f.pic_order_cnt_type == 0)
+    else {
+        return Ok(vec![0; streams.len()]);
+    };
+    let mut frames = Vec::with_capacity(streams.len());
+    for_each_frame(base_path, streams, |bytes, keyframe| {
+        frames.push((keyframe, h264::pic_order_cnt_lsb(bytes, &fields)));
+        Ok(true)
+    })?;
+    Ok(h264::composition_offsets(
+        &frames,
+        fields.log2_max_pic_order_cnt_lsb,
+    ))
+}
+
+/// Reads the frame file into `buffer`, replacing what was in it, and tells whether it is a
+/// keyframe
+fn read_frame_into(path: &str, buffer: &mut Vec<u8>) -> errors::Result<bool> {
+    retry::read_with_retry(path, || {
+        let mut file = fs::File::open(path)?;
+        buffer.clear();
+        buffer.reserve(file.metadata()?.len() as usize);
+        file.read_to_end(buffer)
+    })?;
+    Ok(VIDEO_CODEC.is_keyframe(buffer))
+}
+
+/// Reads the frame files and looks for IDR slices in them, `on_frame` gets the frames with
+/// whether they are keyframes one by one in the order of `streams`. Stops when `on_frame` returns
+/// false and returns whether it got all the frames. The frames are read on the calling thread,
+/// which is a blocking one already, into the buffer of the frame before them. `on_frame` takes
+/// the buffer with `mem::take` to keep the frame.
+fn for_each_frame(
+    base_path: &str,
+    streams: &[impl AsRef<str>],
+    mut on_frame: impl FnMut(&mut Vec<u8>, bool) -> errors::Result<bool>,
+) -> errors::Result<bool> {
+    let mut buffer = Vec::new();
+    for p in streams {
+        let path = format!("{}/{}", base_path, p.as_ref());
+        let keyframe = read_frame_into(&path, &mut buffer)?;
+        if !on_frame(&mut buffer, keyframe)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The frames are read straight into the output, sized for them from the metadata
+pub fn h264streams_concat(base_path: &str, streams: &[&String]) -> errors::Result<Vec<u8>> {
+    let paths: Vec<String> = streams
+        .iter()
+        .map(|p| format!("{}/{}", base_path, p))
+        .collect();
+    let mut size = 0;
+    for path in &paths {
+        size += retry::read_with_retry(path, || fs::metadata(path))?.len() as usize;
+    }
+    let mut data = Vec::with_capacity(size);
+    for path in &paths {
+        // A failed read leaves a part of the file behind
+        let start = data.len();
+        retry::read_with_retry(path, || {
+            data.truncate(start);
+            fs::File::open(path)?.read_to_end(&mut data)
+        })?;
+    }
+    Ok(data)
+}
+
+/// Parameter sets of the 2816x1856 camera the service was first written for
+fn default_avc_config() -> AvcConfig {
+    AvcConfig {
+        width: 2816,
+        height: 1856,
+        seq_param_set: vec![
+            0x27, 0x64, 0x00, 0x32, 0xac, 0x1b, 0x1a, 0x80, 0x2c, 0x00, 0xe9, 0x30, 0x16, 0xc8,
+            0x00, 0x00, 0x1f, 0x40, 0x00, 0x04, 0xe2, 0x07, 0x43, 0x00, 0x01, 0x7d, 0x78, 0x00,
+            0x00, 0x5f, 0x5e, 0x15, 0xde, 0x5c, 0x68, 0x60, 0x00, 0x2f, 0xaf, 0x00, 0x00, 0x0b,
+            0xeb, 0xc2, 0xbb, 0xcb, 0x85, 0x00,
+        ],
+        pic_param_set: vec![0x28, 0xee, 0x38, 0x30],
+    }
+}
+
+/// Timescale of the MP4 video track, a multiple of the common frame rates
+pub const MP4_TIMESCALE: u32 = 1200000;
+const MP4_TRACK_ID: u32 = 1;
+
+/// Video track with the parameter sets of the frames, and the sample aspect ratio of the SPS
+fn video_track_config(
+    base_path: &str,
+    streams: &[impl AsRef<str>],
+) -> errors::Result<(TrackConfig, (u16, u16))> {
+    let avc_config = match find_parameter_sets(base_path, streams)?
+        .and_then(|params| Some((Sps::parse(&params.sps)?, params)))
+    {
+        Some((sps, params)) => AvcConfig {
+            width: sps.width,
+            height: sps.height,
+            seq_param_set: params.sps,
+            pic_param_set: params.pps,
+        },
+        None => {
+            warn!("No SPS/PPS found in the frames of {base_path}, using the defaults");
+            default_avc_config()
+        }
+    };
+    let sample_aspect_ratio =
+        Sps::parse(&avc_config.seq_param_set).map_or((1, 1), |sps| sps.sample_aspect_ratio);
+    let track_cfg = TrackConfig {
+        track_type: TrackType::Video,
+        timescale: MP4_TIMESCALE,
+        language: "und".to_string(),
+        media_conf: MediaConfig::AvcConfig(avc_config),
+    };
+    Ok((track_cfg, sample_aspect_ratio))
+}
+
+fn signal_sample_aspect_ratio(mp4: &mut Vec<u8>, (h_spacing, v_spacing): (u16, u16)) {
+    if h_spacing != v_spacing
+        && isobmff::add_pasp_box(mp4, h_spacing as u32, v_spacing as u32).is_none()
+    {
+        warn!("Failed to signal {h_spacing}:{v_spacing} sample aspect ratio in MP4");
+    }
+}
+
+pub fn h264streams_to_mp4(
+    base_path: &str,
+    streams: &[&String],
+    fps: u32,
+) -> errors::Result<Vec<u8>> {
+    let config = Mp4Config {
+        major_brand: str::parse("isom").unwrap(),
+        minor_version: 512,
+        compatible_brands: vec![
+            str::parse("isom").unwrap(),
+            str::parse("iso2").unwrap(),
+            str::parse("avc1").unwrap(),
+            str::parse("mp41").unwrap(),
+        ],
+        timescale: 1000,
+    };
+    let data: Cursor<Vec<u8>> = Cursor::new(Vec::<u8>::new());
+    let mut wrt = mp4::Mp4Writer::write_start(data, &config)?;
+    let (track_cfg, sample_aspect_ratio) = video_track_config(base_path, streams)?;
+    let timescale = track_cfg.timescale;
+    wrt.add_track(&track_cfg)?;
+
+    // A dropped frame makes the previous one last longer
+    let positions = frame_positions(streams);
+    // Sample boundaries are rounded from the exact frame times instead of rounding every
+    // duration, so the rounding errors don't accumulate over long clips
+    let frame_time = |position: usize| position as u64 * timescale as u64 / fps as u64;
+
+    let mut idx = 0;
+    for_each_frame(base_path, streams, |bytes, keyframe| {
+        let start_time = frame_time(positions[idx]);
+        let end_time = frame_time(
+            positions
+                .get(idx + 1)
+                .map_or(positions[idx] + 1, |next| *next),
+        );
+        idx += 1;
+
+        let sample = Mp4Sample {
+            start_time,
+            duration: (end_time - start_time) as u32,
+            // The frames carry no composition time, the cameras don't use B-frames
+            rendering_offset: 0,
+            // Written to stss, players seek to these samples
+            is_sync: keyframe,
+            bytes: Bytes::from(h264::to_avc_sample(bytes)),
+        };
+        wrt.write_sample(MP4_TRACK_ID, &sample)?;
+        Ok(true)
+    })?;
+    wrt.write_end()?;
+    let mut mp4 = wrt.into_writer().into_inner();
+    signal_sample_aspect_ratio(&mut mp4, sample_aspect_ratio);
+    Ok(mp4)
+}
+
+/// Init segment of the fragmented MP4 segments of a log, with the parameter sets of `files`
+pub fn fmp4_init_segment(base_path: &str, files: &[impl AsRef<str>]) -> errors::Result<Vec<u8>> {
+    let config = Mp4Config {
+        major_brand: str::parse("iso6").unwrap(),
+        minor_version: 0,
+        compatible_brands: vec![
+            str::parse("iso6").unwrap(),
+            str::parse("cmfc").unwrap(),
+            str::parse("avc1").unwrap(),
+            str::parse("mp41").unwrap(),
+        ],
+        timescale: 1000,
+    };
+    let data: Cursor<Vec<u8>> = Cursor::new(Vec::<u8>::new());
+    let mut wrt = mp4::Mp4Writer::write_start(data, &config)?;
+    let (track_cfg, sample_aspect_ratio) = video_track_config(base_path, files)?;
+    wrt.add_track(&track_cfg)?;
+    wrt.write_end()?;
+    let mut mp4 = wrt.into_writer().into_inner();
+    signal_sample_aspect_ratio(&mut mp4, sample_aspect_ratio);
+    let init = isobmff::init_segment(&mp4, MP4_TRACK_ID).ok_or(mp4::Error::InvalidData(
+        "no ftyp or moov for the init segment",
+    ))?;
+    Ok(init)
+}
+
+/// Media segment of fragmented MP4, it needs the init segment of `fmp4_init_segment`.
+/// `first_frame` is the position of the first of `streams` in the recording, like for MPEG-TS.
+/// A dropped frame makes the previous one last longer, like in MP4.
+pub fn h264streams_to_fmp4(
+    base_path: &str,
+    streams: &[&String],
+    fps: u32,
+    first_frame: u64,
+) -> errors::Result<Vec<u8>> {
+    let frame_time = |frame: u64| frame * MP4_TIMESCALE as u64 / fps as u64;
+    let positions = frame_positions(streams);
+    let mut samples = Vec::with_capacity(streams.len());
+    for_each_frame(base_path, streams, |bytes, keyframe| {
+        let idx = samples.len();
+        let frame = first_frame + positions[idx] as u64;
+        let next_frame = first_frame
+            + positions
+                .get(idx + 1)
+                .map_or(positions[idx] + 1, |next| *next) as u64;
+        samples.push(FragmentSample {
+            duration: (frame_time(next_frame) - frame_time(frame)) as u32,
+            is_sync: keyframe,
+            bytes: h264::to_avc_sample(bytes),
+        });
+        Ok(true)
+    })?;
+    // Only has to increase from fragment to fragment
+    let sequence_number = first_frame as u32 + 1;
+    Ok(isobmff::media_segment(
+        sequence_number,
+        MP4_TRACK_ID,
+        frame_time(first_frame),
+        &samples,
+    ))
+}
+
+/// Extension of the ADTS AAC audio of a frame in a file next to it, `42.aac` is the audio of the
+/// frame `42.ts`. The MPEG-TS segments have it after the frame with the presentation time of
+/// the frame, frames without it have no audio.
+const AUDIO_EXTENSION: &str = "aac";
+
+/// Extension of the SCTE-35 cue at a frame in a file next to it, `42.cue` is a JSON
+/// `SpliceInsert` at the presentation time of the frame `42.ts`. The MPEG-TS segments have it
+/// before the frame.
+const CUE_EXTENSION: &str = "cue";
+
+/// Extension of the ID3 tags of a frame in a file next to it, `42.id3` are the tags of the frame
+/// `42.ts`, like its GPS position. The MPEG-TS segments have them after the frame and its audio
+/// with the presentation time of the frame.
+const METADATA_EXTENSION: &str = "id3";
+
+/// Path of the file next to the frame file with another extension
+fn sidecar_path(base_path: &str, frame: &str, extension: &str) -> String {
+    let sidecar = Path::new(frame).with_extension(extension);
+    format!("{}/{}", base_path, sidecar.display())
+}
+
+/// The file next to the frame file with another extension, if there is one
+fn read_sidecar(base_path: &str, frame: &str, extension: &str) -> errors::Result<Option<Vec<u8>>> {
+    let path = sidecar_path(base_path, frame, extension);
+    match retry::read_with_retry(&path, || fs::read(&path)) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err)?,
+    }
+}
+
+/// Size of the file next to the frame file with another extension, if there is one
+fn sidecar_len(base_path: &str, frame: &str, extension: &str) -> errors::Result<Option<usize>> {
+    let path = sidecar_path(base_path, frame, extension);
+    match retry::read_with_retry(&path, || fs::metadata(&path)) {
+        Ok(metadata) => Ok(Some(metadata.len() as usize)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err)?,
+    }
+}
+
+/// Whether any of the frames has a file with the extension next to it, the PMT lists its
+/// stream then
+fn any_sidecar(
+    base_path: &str,
+    streams: &[impl AsRef<str>],
+    extension: &str,
+) -> errors::Result<bool> {
+    for f in streams {
+        if sidecar_len(base_path, f.as_ref(), extension)?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Muxes the frames one by one. `on_chunk` gets PAT and PMT first and then the packets of every
+/// frame, muxing stops when it returns false. Returns whether all the frames were muxed.
+/// `first_frame` is the position of the first of `streams` in the recording, timestamps are
+/// derived from the positions at `fps`. Dropped frames are skipped over by the timestamps, like
+/// in MP4. With `zero_base` they start at zero instead, see `TransportStream::with_zero_base`.
+pub fn h264streams_to_mpegts_chunks(
+    base_path: &str,
+    streams: &[impl AsRef<str>],
+    fps: u32,
+    first_frame: u64,
+    discontinuity: bool,
+    zero_base: bool,
+    mut on_chunk: impl FnMut(Vec<u8>) -> bool,
+) -> errors::Result<bool> {
+    let mut ts: TransportStream = new_transport_stream()
+        .with_fault_injection(*TS_FAULT_INJECTION)
+        .with_discontinuity(discontinuity)
+        .with_zero_base(zero_base);
+    if any_sidecar(base_path, streams, AUDIO_EXTENSION)? {
+        ts = ts.with_audio();
+    }
+    if any_sidecar(base_path, streams, CUE_EXTENSION)? {
+        ts = ts.with_splices();
+    }
+    if any_sidecar(base_path, streams, METADATA_EXTENSION)? {
+        ts = ts.with_metadata();
+    }
+
+    // Picky demuxers want profile and level in the PMT, which goes before the frames. It reads
+    // the frames up to the first SPS twice, that is at most a GOP. Encoders send captions with
+    // every frame, so these frames tell whether there are captions to signal too. Captions and
+    // the descriptor are of H264 only.
+    let codec = *VIDEO_CODEC;
+    let mut sps = None;
+    let mut has_captions = false;
+    for p in streams {
+        let path = format!("{}/{}", base_path, p.as_ref());
+        let bytes = retry::read_with_retry(&path, || fs::read(&path))?;
+        let nals = h264::nal_units(&bytes);
+        if codec == VideoCodec::H264 {
+            has_captions |= nals.iter().copied().any(h264::is_caption_sei);
+        }
+        if let Some(parsed) = nals.into_iter().find_map(|nal| Sps::parse_for(codec, nal)) {
+            if codec == VideoCodec::H264 {
+                ts.add_video_descriptor(mpegts::avc_video_descriptor(&parsed));
+            }
+            sps = Some(parsed);
+            break;
+        }
+    }
+    if has_captions {
+        ts.add_video_descriptor(mpegts::caption_service_descriptor());
+    }
+    let composition_offsets = composition_offsets(base_path, streams, sps.as_ref())?;
+    let positions = frame_positions(streams);
+    let header = ts.write_header(Vec::new())?;
+    let mut written = header.len();
+    if !on_chunk(header) {
+        return Ok(false);
+    }
+
+    let mut idx = 0;
+    let all_muxed = for_each_frame(base_path, streams, |bytes, keyframe| {
+        // Rounded from the exact frame times, so the rounding errors don't accumulate
+        let frame = first_frame + positions[idx] as u64;
+        let start_time = frame * 1000 / fps as u64;
+        let presentation_time = (frame + composition_offsets[idx]) * 1000 / fps as u64;
+        let frame_file = streams[idx].as_ref();
+        idx += 1;
+        if *TS_INSERT_AUD && !codec.starts_with_aud(bytes) {
+            let aud = codec.access_unit_delimiter();
+            bytes.splice(0..0, aud.iter().copied());
+        }
+        // Written on its own, so it goes before the frame whatever the composition time of it
+        if let Some(cue) = read_sidecar(base_path, frame_file, CUE_EXTENSION)? {
+            let cue: SpliceInsert = serde_json::from_slice(&cue)?;
+            ts.push_splice(presentation_time, cue)?;
+        }
+        let packets = ts.write_packets(Vec::new())?;
+        ts.push_video(start_time, presentation_time - start_time, keyframe, bytes)?;
+        // Their PTS is after the DTS of the video, so they are written after the frame
+        if let Some(audio) = read_sidecar(base_path, frame_file, AUDIO_EXTENSION)? {
+            ts.push_audio(presentation_time, audio)?;
+        }
+        if let Some(id3) = read_sidecar(base_path, frame_file, METADATA_EXTENSION)? {
+            ts.push_metadata(presentation_time, id3)?;
+        }
+        let packets = ts.write_packets(packets)?;
+        written += packets.len();
+        Ok(on_chunk(packets))
+    })?;
+    if !all_muxed {
+        return Ok(false);
+    }
+    if let Some(target_size) = *TS_SEGMENT_PAD_SIZE {
+        let padding = mpegts::write_null_padding(Vec::new(), written, target_size)?;
+        if !padding.is_empty() && !on_chunk(padding) {
+            return Ok(false);
+        }
+    }
+    if let Some((min_pts, max_pts)) = ts.timestamp_range() {
+        debug!(
+            "Muxed {} frames, PTS from {min_pts} to {max_pts} ms",
+            streams.len()
+        );
+    }
+    Ok(true)
+}
+
+pub fn h264streams_to_mpegts(
+    base_path: &str,
+    streams: &[impl AsRef<str>],
+    fps: u32,
+    first_frame: u64,
+    discontinuity: bool,
+    zero_base: bool,
+) -> errors::Result<Vec<u8>> {
+    let mut segment = Vec::new();
+    h264streams_to_mpegts_chunks(
+        base_path,
+        streams,
+        fps,
+        first_frame,
+        discontinuity,
+        zero_base,
+        |chunk| {
+            segment.extend_from_slice(&chunk);
+            true
+        },
+    )?;
+    Ok(segment)
+}
+
+/// Size of the MPEG-TS segment of the frames, from their sizes without muxing them. The PCR
+/// schedule and the padding of `h264streams_to_mpegts_chunks` are replayed, like `index_iframes`
+/// does. Only the start of the frames is read, up to their first slice for the keyframes.
+pub fn muxed_mpegts_size(
+    base_path: &str,
+    streams: &[&String],
+    fps: u32,
+    first_frame: u64,
+) -> errors::Result<usize> {
+    let mut pcr_schedule = PcrSchedule::default();
+    let mut size = new_transport_stream().header_size();
+    let positions = frame_positions(streams);
+    for (idx, f) in streams.iter().enumerate() {
+        let timestamp = (first_frame + positions[idx] as u64) * 1000 / fps as u64;
+        let keyframe = is_keyframe_head(&read_frame_head(&format!("{}/{}", base_path, f))?);
+        let with_pcr = pcr_schedule.needs_pcr(timestamp, keyframe);
+        size += muxed_frame_size(base_path, f, with_pcr)?.total();
+    }
+    if let Some(target_size) = *TS_SEGMENT_PAD_SIZE {
+        size = size.max(target_size.next_multiple_of(mpegts::PACKET_SIZE));
+    }
+    Ok(size)
+}
+
+pub fn new_transport_stream() -> TransportStream {
+    let mut ts = TransportStream::new().with_video_codec(*VIDEO_CODEC);
+    if let Some(transport_stream_id) = *TS_TRANSPORT_STREAM_ID {
+        ts = ts.with_transport_stream_id(transport_stream_id);
+    }
+    if let Some(program_number) = *TS_PROGRAM_NUMBER {
+        ts = ts.with_program_number(program_number);
+    }
+    ts.with_stream_ids(*TS_STREAM_IDS)
+        .with_service_description(TS_SERVICE_DESCRIPTION.clone())
+}
+
+/// A valid MPEG-TS segment without any media, just the tables and the padding
+pub fn empty_mpegts() -> errors::Result<Vec<u8>> {
+    let mut ts = new_transport_stream();
+    let header_size = ts.header_size();
+    let mut segment = ts.write_to(Cursor::new(Vec::<u8>::new()))?.into_inner();
+    if let Some(target_size) = *TS_SEGMENT_PAD_SIZE {
+        segment = mpegts::write_null_padding(segment, header_size, target_size)?;
+    }
+    Ok(segment)
+}
+
+lazy_static! {
+    /// PAT/PMT identifiers, so the segments can be multiplexed with other programs
+    pub static ref TS_TRANSPORT_STREAM_ID: Option<u16> = env_u16("TS_TRANSPORT_STREAM_ID", 0);
+    pub static ref TS_PROGRAM_NUMBER: Option<u16> = env_u16("TS_PROGRAM_NUMBER", 1);
+    /// `TS_PMT_PID`, `TS_VIDEO_PID` and `TS_VIDEO_STREAM_ID` for systems that expect the program
+    /// on specific PIDs, the ones not set keep their defaults
+    pub static ref TS_STREAM_IDS: StreamIds = {
+        let defaults = StreamIds::default();
+        let pmt_pid = env_u16("TS_PMT_PID", 0);
+        let video_pid = env_u16("TS_VIDEO_PID", 0);
+        let video_stream_id = env_u16("TS_VIDEO_STREAM_ID", 0);
+        if pmt_pid.is_none() && video_pid.is_none() && video_stream_id.is_none() {
+            return defaults;
+        }
+        let stream_ids = video_stream_id
+            .map_or(Ok(defaults.video_stream_id()), u8::try_from)
+            .map_err(|err| err.to_string())
+            .and_then(|video_stream_id| {
+                StreamIds::new(
+                    pmt_pid.unwrap_or(defaults.pmt_pid()),
+                    video_pid.unwrap_or(defaults.video_pid()),
+                    video_stream_id,
+                )
+                .map_err(|err| err.to_string())
+            });
+        match stream_ids {
+            Ok(stream_ids) => stream_ids,
+            Err(err) => {
+                warn!("`TS_PMT_PID`, `TS_VIDEO_PID` and `TS_VIDEO_STREAM_ID` env variables are ignored: {}", err);
+                defaults
+            }
+        }
+    };
+    /// Names of the program in an SDT, it is only written when `TS_SERVICE_NAME` is set.
+    /// `TS_SERVICE_PROVIDER` is empty by default.
+    pub static ref TS_SERVICE_DESCRIPTION: Option<ServiceDescription> = {
+        let Ok(service_name) = env::var("TS_SERVICE_NAME") else {
+            return None;
+        };
+        let service_provider = env::var("TS_SERVICE_PROVIDER").unwrap_or_default();
+        match ServiceDescription::new(service_provider, service_name) {
+            Ok(service_description) => {
+                info!("`TS_SERVICE_NAME` env variable is set, the SDT is {:?}", service_description);
+                Some(service_description)
+            }
+            Err(err) => {
+                warn!("`TS_SERVICE_NAME` env variable is ignored: {}", err);
+                None
+            }
+        }
+    };
+    /// Pads the end of the MPEG-TS segments shorter than this many bytes with null packets, for
+    /// equipment that wants segments of a fixed size. Longer segments are left as they are.
+    pub static ref TS_SEGMENT_PAD_SIZE: Option<usize> = {
+        match env::var("TS_SEGMENT_PAD_SIZE") {
+            Ok(v) => match v.parse::<usize>() {
+                Ok(size) => {
+                    info!("`TS_SEGMENT_PAD_SIZE` env variable is set to {}", size);
+                    Some(size)
+                }
+                Err(err) => {
+                    warn!("`TS_SEGMENT_PAD_SIZE` env variable is ignored: {}", err);
+                    None
+                }
+            },
+            Err(_) => None,
+        }
+    };
+    /// Starts the frames without an access unit delimiter in the MPEG-TS segments with one, strict
+    /// demuxers and TS analyzers want it first in every access unit
+    pub static ref TS_INSERT_AUD: bool = {
+        match env::var("TS_INSERT_AUD") {
+            Ok(v) => {
+                let enabled = v == "1" || v.eq_ignore_ascii_case("true");
+                info!("`TS_INSERT_AUD` env variable is set to {}", enabled);
+                enabled
+            }
+            Err(_) => false,
+        }
+    };
+    /// Codec of all the logs, the cameras of a deployment are of one kind. MP4 output is H264 only.
+    pub static ref VIDEO_CODEC: VideoCodec = {
+        match env::var("VIDEO_CODEC").map(|v| v.parse::<VideoCodec>()) {
+            Ok(Ok(codec)) => {
+                info!("`VIDEO_CODEC` env variable is set to {:?}", codec);
+                codec
+            }
+            Ok(Err(err)) => {
+                warn!("`VIDEO_CODEC` env variable is ignored: {}", err);
+                VideoCodec::H264
+            }
+            Err(_) => VideoCodec::H264,
+        }
+    };
+    /// Corrupts the MPEG-TS output to test players, see `FaultInjection`. Debug builds only.
+    pub static ref TS_FAULT_INJECTION: Option<FaultInjection> = {
+        match env::var("TS_FAULT_INJECTION") {
+            Ok(v) if cfg!(debug_assertions) => match v.parse::<FaultInjection>() {
+                Ok(fault_injection) => {
+                    warn!("`TS_FAULT_INJECTION` env variable is set to {:?}", fault_injection);
+                    Some(fault_injection)
+                }
+                Err(err) => {
+                    warn!("`TS_FAULT_INJECTION` env variable is ignored: {}", err);
+                    None
+                }
+            },
+            Ok(_) => {
+                warn!("`TS_FAULT_INJECTION` env variable is ignored in release builds");
+                None
+            }
+            Err(_) => None,
+        }
+    };
+}
+
+/// Reads an optional numeric env variable, values below `min` are ignored
+fn env_u16(name: &str, min: u16) -> Option<u16> {
+    match env::var(name).map(|v| v.parse::<u16>()) {
+        Ok(Ok(value)) if value >= min => {
+            info!("`{}` env variable is set to {}", name, value);
+            Some(value)
+        }
+        Ok(_) => {
+            warn!(
+                "`{}` env variable is ignored, expected a number >= {}",
+                name, min
+            );
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Leading bytes of a frame read to find its first NAL unit header
+const AUD_PROBE_SIZE: u64 = 64;
+
+/// Leading bytes of a frame read at first to find its first slice, twice as many are read until
+/// the parameter sets and SEI before it are read past
+const SLICE_PROBE_SIZE: u64 = 4096;
+
+fn is_slice(nal_unit_type: NalUnitType) -> bool {
+    matches!(
+        nal_unit_type,
+        NalUnitType::NonIdrSlice | NalUnitType::IdrSlice
+    )
+}
+
+/// Start of the frame file up to the header of its first slice, or the whole frame when it has
+/// no slice
+fn read_frame_head(path: &str) -> errors::Result<Vec<u8>> {
+    let mut probe_size = SLICE_PROBE_SIZE;
+    let mut head = Vec::new();
+    loop {
+        retry::read_with_retry(path, || {
+            head.clear();
+            fs::File::open(path)?
+                .take(probe_size)
+                .read_to_end(&mut head)
+        })?;
+        let has_slice = h264::nal_units(&head)
+            .into_iter()
+            .any(|nal| is_slice(VIDEO_CODEC.nal_unit_type(nal)));
+        if has_slice || (head.len() as u64) < probe_size {
+            return Ok(head);
+        }
+        probe_size *= 2;
+    }
+}
+
+/// Whether the frame of `read_frame_head` is a keyframe. The slices of a picture are either all
+/// IDR slices or none is, so the first one tells.
+fn is_keyframe_head(head: &[u8]) -> bool {
+    h264::nal_units(head)
+        .into_iter()
+        .map(|nal| VIDEO_CODEC.nal_unit_type(nal))
+        .find(|nal_unit_type| is_slice(*nal_unit_type))
+        == Some(NalUnitType::IdrSlice)
+}
+
+/// Size of the frame in the MPEG-TS segments, with the access unit delimiter of `TS_INSERT_AUD`.
+/// Just the start of the frame is read for it.
+fn muxed_frame_len(path: &str) -> errors::Result<usize> {
+    let len = retry::read_with_retry(path, || fs::metadata(path))?.len() as usize;
+    if !*TS_INSERT_AUD {
+        return Ok(len);
+    }
+    let mut head = Vec::new();
+    retry::read_with_retry(path, || {
+        head.clear();
+        fs::File::open(path)?
+            .take(AUD_PROBE_SIZE)
+            .read_to_end(&mut head)
+    })?;
+    let codec = *VIDEO_CODEC;
+    if codec.starts_with_aud(&head) {
+        Ok(len)
+    } else {
+        Ok(len + codec.access_unit_delimiter().len())
+    }
+}
+
+/// Bytes of a frame in the MPEG-TS segments, the packets of its video and of the files next to
+/// it that are muxed before and after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MuxedFrameSize {
+    pub before_video: usize,
+    pub video: usize,
+    pub after_video: usize,
+}
+
+impl MuxedFrameSize {
+    pub fn total(&self) -> usize {
+        self.before_video + self.video + self.after_video
+    }
+}
+
+/// Size of the frame `f` muxed by `h264streams_to_mpegts_chunks`, from the sizes of the files
+pub fn muxed_frame_size(
+    base_path: &str,
+    f: &str,
+    with_pcr: bool,
+) -> errors::Result<MuxedFrameSize> {
+    let len = muxed_frame_len(&format!("{}/{}", base_path, f))?;
+    let audio = sidecar_len(base_path, f, AUDIO_EXTENSION)?.map_or(0, mpegts::muxed_pes_size);
+    let metadata = sidecar_len(base_path, f, METADATA_EXTENSION)?.map_or(0, mpegts::muxed_pes_size);
+    // A `splice_insert` section fits in a packet
+    let cue = sidecar_len(base_path, f, CUE_EXTENSION)?.map_or(0, |_| mpegts::PACKET_SIZE);
+    Ok(MuxedFrameSize {
+        before_video: cue,
+        video: mpegts::muxed_video_size(len, with_pcr),
+        after_video: audio + metadata,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::get_frames;
+    use crate::routes::tests::write_log;
+    use mpeg2ts::ts::{ReadTsPacket, TsPacketReader, TsPayload};
+    use std::time::Duration;
+
+    /// Frame files of `len` bytes in a directory of their own, every fifth one a keyframe
+    fn write_frames(name: &str, lens: &[usize]) -> (String, Vec<String>) {
+        let dir = env::temp_dir().join(format!("dynamic-hls-api-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut files = Vec::new();
+        for (idx, &len) in lens.iter().enumerate() {
+            let nal_header = if idx % 5 == 0 { 0x65 } else { 0x41 };
+            let mut frame = vec![0, 0, 0, 1, nal_header];
+            frame.resize(len, 0xAB);
+            let file = format!("{idx}.h264");
+            fs::write(dir.join(&file), frame).unwrap();
+            files.push(file);
+        }
+        (dir.display().to_string(), files)
+    }
+
+    #[test]
+    fn muxed_mpegts_size_is_the_size_of_the_muxed_segment() {
+        // Around the payload of the first packet and across several packets
+        let lens: Vec<usize> = (0..40).map(|idx| 150 + idx * 97 % 700).collect();
+        let (dir, files) = write_frames("size", &lens);
+        let streams: Vec<&String> = files.iter().collect();
+        for (first_frame, fps) in [(0, 30), (7, 25), (1234, 15)] {
+            let segment =
+                h264streams_to_mpegts(&dir, &streams, fps, first_frame, false, false).unwrap();
+            let size = muxed_mpegts_size(&dir, &streams, fps, first_frame);
+            assert_eq!(size.unwrap(), segment.len());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keyframes_are_found_past_long_sei_without_reading_the_whole_frame() {
+        let dir = env::temp_dir().join(format!("dynamic-hls-api-{}-heads", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut files = Vec::new();
+        for idx in 0..20 {
+            // SEI longer than a few probes before the slice
+            let mut frame = vec![0, 0, 0, 1, 0x06];
+            frame.resize(5 + 3 * SLICE_PROBE_SIZE as usize, 0xAB);
+            let nal_header = if idx % 5 == 0 { 0x65 } else { 0x41 };
+            frame.extend_from_slice(&[0, 0, 0, 1, nal_header]);
+            frame.resize(frame.len() + 20_000, 0xCD);
+            let file = format!("{idx}.ts");
+            fs::write(dir.join(&file), &frame).unwrap();
+            let head = read_frame_head(&dir.join(&file).display().to_string()).unwrap();
+            assert!(head.len() < frame.len());
+            assert_eq!(is_keyframe_head(&head), VIDEO_CODEC.is_keyframe(&frame));
+            files.push(file);
+        }
+        let dir = dir.display().to_string();
+        let streams: Vec<&String> = files.iter().collect();
+        let segment = h264streams_to_mpegts(&dir, &streams, 30, 0, false, false).unwrap();
+        assert_eq!(
+            muxed_mpegts_size(&dir, &streams, 30, 0).unwrap(),
+            segment.len()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mpegts_and_mp4_timestamps_skip_the_same_dropped_frames() {
+        let base_path = write_log("positions", (0..10).chain(15..20), 300);
+        let dir = base_path.log_path("positions");
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+        let fps = 20;
+
+        let ts = h264streams_to_mpegts(&dir, &streams, fps, 0, false, false).unwrap();
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        let mut ts_times_ms = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pes(pes)) = packet.payload {
+                let dts = pes.header.dts.or(pes.header.pts).unwrap();
+                ts_times_ms.push(dts.as_u64() / 90);
+            }
+        }
+
+        let mp4 = h264streams_to_mp4(&dir, &streams, fps).unwrap();
+        let size = mp4.len() as u64;
+        let mut reader = mp4::Mp4Reader::read_header(Cursor::new(mp4), size).unwrap();
+        let mp4_times_ms: Vec<u64> = (1..=streams.len() as u32)
+            .map(|sample_id| {
+                let sample = reader
+                    .read_sample(MP4_TRACK_ID, sample_id)
+                    .unwrap()
+                    .unwrap();
+                sample.start_time * 1000 / MP4_TIMESCALE as u64
+            })
+            .collect();
+
+        assert_eq!(ts_times_ms, mp4_times_ms);
+        // The frame 15 follows the gap, 5 frame periods after the frame 9
+        assert_eq!(ts_times_ms[9..11], [450, 750]);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn reordered_frames_are_presented_after_they_are_decoded() {
+        // I P B B P B B in decode order, presented as I B B P B B P
+        let dir = env::temp_dir().join(format!("dynamic-hls-api-{}-poc", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let sps = h264::tests::poc_sps_nal();
+        let mut files = Vec::new();
+        for (idx, lsb) in [0, 6, 2, 4, 12, 8, 10].into_iter().enumerate() {
+            let mut frame = Vec::new();
+            if idx == 0 {
+                frame.extend_from_slice(&[0, 0, 0, 1]);
+                frame.extend_from_slice(&sps);
+            }
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend(h264::tests::slice_nal(idx == 0, idx as u32, lsb));
+            let file = format!("{idx}.ts");
+            fs::write(dir.join(&file), frame).unwrap();
+            files.push(file);
+        }
+        let dir = dir.display().to_string();
+
+        let ts = h264streams_to_mpegts(&dir, &files, 20, 0, false, false).unwrap();
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        let mut timestamps = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pes(pes)) = packet.payload {
+                let pts = pes.header.pts.unwrap().as_u64();
+                timestamps.push((pts, pes.header.dts.map_or(pts, |dts| dts.as_u64())));
+            }
+        }
+        // The stream is delayed by a frame, the B frames are presented right when decoded
+        let offsets: Vec<u64> = timestamps.iter().map(|(pts, dts)| pts - dts).collect();
+        assert_eq!(offsets, [1, 3, 0, 0, 3, 0, 0].map(|frames| frames * 4500));
+        let mut pts: Vec<u64> = timestamps.iter().map(|(pts, _)| *pts).collect();
+        pts.sort();
+        assert_eq!(pts, (1..=7).map(|frame| frame * 4500).collect::<Vec<u64>>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn captions_are_signaled_in_the_pmt_and_muxed_whole() {
+        let dir = env::temp_dir().join(format!("dynamic-hls-api-{}-captions", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let sps = h264::tests::sps_nal(1, (0, 0));
+        let mut frames = Vec::new();
+        for idx in 0..3u8 {
+            let sei = h264::tests::caption_sei_nal(31, idx);
+            let slice: Vec<u8> = [if idx == 0 { 0x65 } else { 0x41 }]
+                .into_iter()
+                .chain(vec![0xAB; 500])
+                .collect();
+            let nals = if idx == 0 {
+                vec![sps.clone(), sei, slice]
+            } else {
+                vec![sei, slice]
+            };
+            let frame: Vec<u8> = nals
+                .iter()
+                .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+                .collect();
+            fs::write(dir.join(format!("{idx}.ts")), &frame).unwrap();
+            frames.push(frame);
+        }
+        let dir = dir.display().to_string();
+        let files = ["0.ts", "1.ts", "2.ts"];
+
+        let ts = h264streams_to_mpegts(&dir, &files, 20, 0, false, false).unwrap();
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        let mut descriptor_tags = Vec::new();
+        let mut video = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            match packet.payload {
+                Some(TsPayload::Pmt(pmt)) => {
+                    descriptor_tags = pmt.es_info[0].descriptors.iter().map(|d| d.tag).collect()
+                }
+                Some(TsPayload::Pes(pes)) => video.push(pes.data.to_vec()),
+                Some(TsPayload::Raw(data)) => video.last_mut().unwrap().extend_from_slice(&data),
+                _ => {}
+            }
+        }
+        // The AVC video descriptor and the caption service descriptor
+        assert_eq!(descriptor_tags, [0x28, 0x86]);
+        assert_eq!(video, frames);
+
+        // Without captions there is nothing to signal
+        fs::write(format!("{dir}/0.ts"), [&[0, 0, 0, 1][..], &sps].concat()).unwrap();
+        let ts = h264streams_to_mpegts(&dir, &files[..1], 20, 0, false, false).unwrap();
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pmt(pmt)) = packet.payload {
+                assert_eq!(pmt.es_info[0].descriptors.len(), 1);
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mp4_duration_is_the_frame_count_at_fps() {
+        let base_path = write_log("mp4-duration", 0..97, 200);
+        let dir = base_path.log_path("mp4-duration");
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+        // Frame rates that don't divide the timescale too
+        for fps in [7, 20, 24, 25, 30, 60] {
+            let mp4 = h264streams_to_mp4(&dir, &streams, fps).unwrap();
+            let size = mp4.len() as u64;
+            let reader = mp4::Mp4Reader::read_header(Cursor::new(mp4), size).unwrap();
+            let track = &reader.tracks()[&MP4_TRACK_ID];
+            assert_eq!(track.sample_count(), 97);
+            let expected = Duration::from_secs_f64(97.0 / fps as f64);
+            let duration = track.duration();
+            assert!(
+                duration.abs_diff(expected) < Duration::from_millis(1),
+                "{duration:?} of {fps} fps, expected {expected:?}"
+            );
+        }
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn audio_next_to_the_frames_is_muxed_with_them() {
+        let base_path = write_log("audio", 0..10, 300);
+        let dir = base_path.log_path("audio");
+        // Frames 3 and 4 have audio, one of them longer than a packet
+        fs::write(sidecar_path(&dir, "3.ts", AUDIO_EXTENSION), [0xAA; 100]).unwrap();
+        fs::write(sidecar_path(&dir, "4.ts", AUDIO_EXTENSION), [0xAA; 400]).unwrap();
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+        let fps = 20;
+
+        let ts = h264streams_to_mpegts(&dir, &streams, fps, 0, false, false).unwrap();
+        let size = muxed_mpegts_size(&dir, &streams, fps, 0);
+        assert_eq!(size.unwrap(), ts.len());
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        let mut audio_pts_ms = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pes(pes)) = packet.payload {
+                if packet.header.pid.as_u16() == 258 {
+                    audio_pts_ms.push(pes.header.pts.unwrap().as_u64() / 90);
+                }
+            }
+        }
+        assert_eq!(audio_pts_ms, [150, 200]);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn cues_next_to_the_frames_are_muxed_before_them() {
+        let base_path = write_log("cues", 0..10, 300);
+        let dir = base_path.log_path("cues");
+        let cue = r#"{"splice_event_id": 7, "out_of_network": true, "break_duration_ms": 30000}"#;
+        fs::write(sidecar_path(&dir, "5.ts", CUE_EXTENSION), cue).unwrap();
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+        let fps = 20;
+
+        let ts = h264streams_to_mpegts(&dir, &streams, fps, 0, false, false).unwrap();
+        let size = muxed_mpegts_size(&dir, &streams, fps, 0);
+        assert_eq!(size.unwrap(), ts.len());
+        // The reader takes the cues for PES, so the packets are split by hand
+        let pids: Vec<u16> = ts
+            .chunks(mpegts::PACKET_SIZE)
+            .map(|packet| u16::from_be_bytes([packet[1] & 0x1F, packet[2]]))
+            .collect();
+        let cue_packet = pids.iter().position(|&pid| pid == 259).unwrap();
+        // PAT, PMT and 5 frames of 2 packets
+        assert_eq!(cue_packet, 12);
+        assert_eq!(pids.iter().filter(|&&pid| pid == 259).count(), 1);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn metadata_next_to_the_frames_is_muxed_after_them() {
+        let base_path = write_log("metadata", 0..10, 300);
+        let dir = base_path.log_path("metadata");
+        let id3 = b"ID3\x04\x00\x00\x00\x00\x00\x00";
+        for frame in ["2.ts", "8.ts"] {
+            fs::write(sidecar_path(&dir, frame, METADATA_EXTENSION), id3).unwrap();
+        }
+        fs::write(sidecar_path(&dir, "8.ts", AUDIO_EXTENSION), [0xAA; 100]).unwrap();
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+        let fps = 20;
+
+        let ts = h264streams_to_mpegts(&dir, &streams, fps, 0, false, false).unwrap();
+        let size = muxed_mpegts_size(&dir, &streams, fps, 0);
+        assert_eq!(size.unwrap(), ts.len());
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        let mut pes = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pes(payload)) = packet.payload {
+                let pts_ms = payload.header.pts.unwrap().as_u64() / 90;
+                pes.push((packet.header.pid.as_u16(), pts_ms));
+            }
+        }
+        let [.., frame_8, audio, metadata, frame_9] = pes[..] else {
+            panic!("Not enough PES in {pes:?}");
+        };
+        assert_eq!(
+            [frame_8, audio, metadata],
+            [(257, 400), (258, 400), (260, 400)]
+        );
+        assert_eq!(frame_9, (257, 450));
+        assert!(pes.contains(&(260, 100)));
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn mp4_signals_the_sample_aspect_ratio_of_the_sps() {
+        let base_path = write_log("pasp", 0..10, 300);
+        let dir = base_path.log_path("pasp");
+        let mut frame = Vec::new();
+        for nal in [
+            h264::tests::sps_nal(14, (0, 0)),
+            vec![0x68, 0xCE, 0x38, 0x80],
+        ] {
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend_from_slice(&nal);
+        }
+        frame.extend_from_slice(&[0, 0, 0, 1, 0x65, 0xAB]);
+        fs::write(format!("{dir}/0.ts"), frame).unwrap();
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+
+        let mp4 = h264streams_to_mp4(&dir, &streams, 20).unwrap();
+        let pasp = mp4.windows(4).position(|w| w == b"pasp").unwrap();
+        assert_eq!(mp4[pasp - 4..pasp], 16u32.to_be_bytes());
+        assert_eq!(mp4[pasp + 4..pasp + 8], 4u32.to_be_bytes());
+        assert_eq!(mp4[pasp + 8..pasp + 12], 3u32.to_be_bytes());
+        // The sizes of the boxes around it still add up
+        let size = mp4.len() as u64;
+        let reader = mp4::Mp4Reader::read_header(Cursor::new(mp4), size).unwrap();
+        let track = &reader.tracks()[&MP4_TRACK_ID];
+        assert_eq!((track.width(), track.height()), (1280, 720));
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn mp4_sample_durations_dont_drift_over_long_clips() {
+        let frames = 10_000;
+        let base_path = write_log("drift", 0..frames, 16);
+        let dir = base_path.log_path("drift");
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+        // Frame periods that aren't whole ticks of the timescale
+        for fps in [7, 29] {
+            let mp4 = h264streams_to_mp4(&dir, &streams, fps).unwrap();
+            let size = mp4.len() as u64;
+            let reader = mp4::Mp4Reader::read_header(Cursor::new(mp4), size).unwrap();
+            let track = &reader.tracks()[&MP4_TRACK_ID];
+            let expected = frames as u64 * MP4_TIMESCALE as u64 / fps as u64;
+            assert_eq!(track.trak.mdia.mdhd.duration, expected, "{fps} fps");
+        }
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[test]
+    fn keyframes_are_sync_samples_and_random_access_points() {
+        let base_path = write_log("sync", 0..12, 300);
+        let dir = base_path.log_path("sync");
+        let files = get_frames(&dir).unwrap();
+        let streams: Vec<&String> = files.iter().collect();
+
+        let mp4 = h264streams_to_mp4(&dir, &streams, 20).unwrap();
+        let size = mp4.len() as u64;
+        let mut reader = mp4::Mp4Reader::read_header(Cursor::new(mp4), size).unwrap();
+        let sync: Vec<bool> = (1..=streams.len() as u32)
+            .map(|id| {
+                reader
+                    .read_sample(MP4_TRACK_ID, id)
+                    .unwrap()
+                    .unwrap()
+                    .is_sync
+            })
+            .collect();
+        let keyframes: Vec<bool> = (0..12).map(|idx| idx % 5 == 0).collect();
+        assert_eq!(sync, keyframes);
+
+        let ts = h264streams_to_mpegts(&dir, &streams, 20, 0, false, false).unwrap();
+        let mut reader = TsPacketReader::new(Cursor::new(ts));
+        let mut random_access = Vec::new();
+        while let Some(packet) = reader.read_ts_packet().unwrap() {
+            if let Some(TsPayload::Pes(_)) = packet.payload {
+                let field = packet.adaptation_field;
+                random_access.push(field.is_some_and(|f| f.random_access_indicator));
+            }
+        }
+        assert_eq!(random_access, keyframes);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+// `/v1/nals`, the NAL units of a part of a log
+use crate::errors;
+use crate::h264::{self, NalUnitType, VideoCodec};
+use crate::mux::VIDEO_CODEC;
+use crate::retry;
+use crate::routes::{check_fps, default_fps, get_frames, ms_to_frames, BasePath, LogName};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::{debug_handler, extract::Query, Json};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// Longest part of a log `/v1/nals` lists, its frames are read whole
+const MAX_NALS_LENGTH_MS: usize = 60_000;
+
+#[derive(Debug, Deserialize)]
+pub struct NalsQuery {
+    #[serde(rename = "offset")]
+    offset_ms: usize,
+    #[serde(rename = "length")]
+    length_ms: usize,
+    #[serde(default = "default_fps")]
+    fps: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct NalsResponse {
+    log_name: String,
+    frames: Vec<FrameNals>,
+}
+
+#[derive(Debug, Serialize)]
+struct FrameNals {
+    /// Index of the frame in the frame list of the log, which `offset` counts in too. The
+    /// dropped frames are not counted, unlike in the timestamps of the segments.
+    frame: usize,
+    file: String,
+    /// Size of the frame file in bytes, start codes included
+    size: usize,
+    keyframe: bool,
+    nals: Vec<NalInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct NalInfo {
+    /// `nal_unit_type` of the codec, ITU-T H.264 or H.265 Table 7-1
+    nal_unit_type: u8,
+    /// Size without the start code, the NAL header included
+    size: usize,
+    keyframe: bool,
+    sps: bool,
+    pps: bool,
+    sei: bool,
+}
+
+fn nal_info(nal: &[u8]) -> NalInfo {
+    let nal_unit_type = match *VIDEO_CODEC {
+        VideoCodec::H264 => nal[0] & 0x1F,
+        VideoCodec::Hevc => (nal[0] >> 1) & 0x3F,
+    };
+    let kind = VIDEO_CODEC.nal_unit_type(nal);
+    NalInfo {
+        nal_unit_type,
+        size: nal.len(),
+        keyframe: kind == NalUnitType::IdrSlice,
+        sps: kind == NalUnitType::Sps,
+        pps: kind == NalUnitType::Pps,
+        sei: kind == NalUnitType::Sei,
+    }
+}
+
+/// NAL units of `length` ms of the log from `offset`, to see how a log is encoded without
+/// downloading the video
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_nals(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<NalsQuery>,
+) -> errors::Result<impl IntoResponse> {
+    check_fps(query.fps)?;
+    if query.length_ms > MAX_NALS_LENGTH_MS {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "`length` must be at most {} ms, got {}",
+            MAX_NALS_LENGTH_MS, query.length_ms
+        )))?
+    }
+    let offset_frames = ms_to_frames(query.offset_ms, query.fps)?;
+    let frames = ms_to_frames(query.length_ms, query.fps)?;
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    let path = path_to_h264_frames.clone();
+    let files = tokio::task::spawn_blocking(move || get_frames(&path)).await??;
+    if offset_frames >= files.len() {
+        Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+            "NAL units start at frame {offset_frames}, {log_name} has {} frames",
+            files.len()
+        )))?
+    }
+    let frame_files: Vec<(usize, String)> = files
+        .into_iter()
+        .enumerate()
+        .skip(offset_frames)
+        .take(frames)
+        .collect();
+    let frames = tokio::task::spawn_blocking(move || {
+        frame_files
+            .into_iter()
+            .map(|(frame, file)| {
+                let path = format!("{}/{}", path_to_h264_frames, file);
+                let bytes = retry::read_with_retry(&path, || fs::read(&path))?;
+                let nals: Vec<NalInfo> =
+                    h264::nal_units(&bytes).into_iter().map(nal_info).collect();
+                Ok(FrameNals {
+                    frame,
+                    file,
+                    size: bytes.len(),
+                    keyframe: nals.iter().any(|nal| nal.keyframe),
+                    nals,
+                })
+            })
+            .collect::<errors::Result<Vec<_>>>()
+    })
+    .await??;
+    Ok(Json(NalsResponse { log_name, frames }))
+}
@@ -0,0 +1,944 @@
+// HLS media, concat, master and I-frame playlists of the logs
+use crate::errors;
+use crate::h264::Sps;
+use crate::lookups::{get_keyframes, get_parameter_sets, has_captions};
+use crate::metrics;
+use crate::mpegts::{self, PcrSchedule};
+use crate::mux::{
+    check_mp4_codec, muxed_frame_size, new_transport_stream, TS_SEGMENT_PAD_SIZE, VIDEO_CODEC,
+};
+use crate::routes::{
+    check_fps, check_segment_length, default_fps, default_segment_length_ms, elapsed_frames,
+    encode_log_name, frame_positions, frames_to_ms, get_frames, ms_to_frames, validate_log_name,
+    BasePath, LogName,
+};
+use crate::segment::{cached_segment, SegmentKey, VideoType};
+use crate::segment_cache::CacheStatus;
+use axum::extract::{Host, State};
+use axum::http::{header, HeaderMap, HeaderName};
+use axum::response::IntoResponse;
+use axum::{debug_handler, extract::Query};
+use h264_util::frames::frame_index;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::env;
+use std::time::Instant;
+use tracing::{debug, info, warn};
+
+const PLAYLIST_CONTENT_TYPE: [(HeaderName, &str); 1] =
+    [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+enum Segmentation {
+    /// Segments of `segment_length`, they start wherever the frames are
+    #[default]
+    Fixed,
+    /// Segments of at least `segment_length` that start at keyframes, decodable on their own
+    Keyframes,
+}
+
+/// Signals CEA-608 captions carried in the SEI of the video, players extract them from the TS
+const CLOSED_CAPTIONS_MEDIA: &str =
+    "#EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID=\"cc\",NAME=\"CC1\",INSTREAM-ID=\"CC1\"\n";
+
+pub const TOTAL_SEGMENTS_HEADER: HeaderName = HeaderName::from_static("x-total-segments");
+
+lazy_static! {
+    /// Number of segments muxed in the background when their playlist is requested, 0 disables it
+    static ref PLAYLIST_PREWARM_SEGMENTS: usize = {
+        match env::var("PLAYLIST_PREWARM_SEGMENTS") {
+            Ok(v) => match v.parse::<usize>() {
+                Ok(n) => {
+                    info!("`PLAYLIST_PREWARM_SEGMENTS` env variable is set to {}", n);
+                    n
+                }
+                Err(err) => {
+                    warn!("`PLAYLIST_PREWARM_SEGMENTS` env variable is ignored: {}", err);
+                    0
+                }
+            },
+            Err(_) => 0,
+        }
+    };
+}
+
+/// Segment of the media playlist, `offset_ms` and `length_ms` address the frames the same way
+/// `Pagination` does, `duration_ms` is the time its frames are played for
+#[derive(Debug, Clone)]
+pub struct PlaylistSegment {
+    pub offset_ms: usize,
+    pub length_ms: usize,
+    /// Position of the first frame in the frame list and the number of frames, what `offset_ms`
+    /// and `length_ms` map back to
+    pub offset_frames: usize,
+    pub frames: usize,
+    pub duration_ms: usize,
+    /// Frames were dropped right before the segment, it is preceded by `EXT-X-DISCONTINUITY`
+    pub discontinuity: bool,
+}
+
+/// A gap in the frame indices ends the segment early, so that the segment after it starts a
+/// discontinuity. The dropped frames are not played, the time of the gap is in neither segment
+/// and `#EXT-X-TARGETDURATION` doesn't grow with an outage of the camera. With `keyframes` a
+/// segment goes on past `segment_length_ms` up to the next keyframe, so every segment but the
+/// first one starts at a keyframe.
+pub fn split_into_segments(
+    files: &[String],
+    segment_length_ms: usize,
+    fps: u32,
+    keyframes: Option<&[usize]>,
+) -> Vec<PlaylistSegment> {
+    let mut segments = Vec::new();
+    // A length too long to count in frames is a single segment
+    let segment_frames = ms_to_frames(segment_length_ms, fps).map_or(usize::MAX, |f| f.max(1));
+
+    let mut offset_frames = 0;
+    let mut frames = 0;
+    let mut elapsed = 0;
+    let mut discontinuity = false;
+    let mut prev_index: Option<i64> = None;
+    let mut push_segment = |offset_frames: usize, frames: usize, elapsed: usize, discontinuity| {
+        segments.push(PlaylistSegment {
+            offset_ms: frames_to_ms(offset_frames, fps),
+            length_ms: frames_to_ms(frames, fps),
+            offset_frames,
+            frames,
+            duration_ms: frames_to_ms(elapsed, fps),
+            discontinuity,
+        });
+    };
+
+    for (position, f) in files.iter().enumerate() {
+        let index = frame_index(f);
+        let elapsed_since_prev = elapsed_frames(prev_index, index);
+        let gap = elapsed_since_prev > 1;
+        let full = match keyframes {
+            None => frames == segment_frames,
+            Some(keyframes) => {
+                frames >= segment_frames && keyframes.binary_search(&position).is_ok()
+            }
+        };
+        if full || (gap && frames != 0) {
+            push_segment(offset_frames, frames, elapsed, discontinuity);
+            offset_frames += frames;
+            frames = 0;
+            elapsed = 0;
+            discontinuity = gap;
+        }
+
+        frames += 1;
+        // The segment after a gap starts from its first frame
+        elapsed += if gap { 1 } else { elapsed_since_prev };
+        prev_index = index;
+    }
+
+    if frames != 0 {
+        push_segment(offset_frames, frames, elapsed, discontinuity);
+    }
+    segments
+}
+
+/// Muxes the first segments of the playlist in the background, players request them right after
+/// the playlist. It doesn't wait for them, the requests for the segments join the muxing instead.
+fn prewarm_segments(
+    log_name: &str,
+    path_to_h264_frames: &str,
+    segments: &[&PlaylistSegment],
+    video_type: VideoType,
+    fps: u32,
+) {
+    for segment in segments.iter().take(*PLAYLIST_PREWARM_SEGMENTS) {
+        let key = SegmentKey {
+            log_name: log_name.to_string(),
+            path_to_h264_frames: path_to_h264_frames.to_string(),
+            offset_frames: segment.offset_frames,
+            frames: segment.frames,
+            video_type,
+            rebase: false,
+            fps,
+            log_start_frame: 0,
+        };
+        tokio::spawn(async move {
+            match cached_segment(key.clone()).await {
+                Ok((_, CacheStatus::Hit)) => info!("Prewarm hit, already cached {key:?}"),
+                Ok((_, CacheStatus::Miss)) => info!("Prewarm miss, muxed {key:?}"),
+                Err(err) => warn!("Failed to prewarm {key:?}: {err}"),
+            }
+        });
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlaylistQuery {
+    /// Index of the first segment to list, it becomes the media sequence number
+    #[serde(default)]
+    from_index: usize,
+    /// Max number of segments to list, all the remaining ones when absent
+    count: Option<usize>,
+    /// Frame rate of the camera, see `Pagination::fps`
+    #[serde(default = "default_fps")]
+    fps: u32,
+    /// Length of the segments in ms, a gap of dropped frames ends a segment early
+    #[serde(rename = "segment_length", default = "default_segment_length_ms")]
+    segment_length_ms: usize,
+    /// Segment URLs are relative to the playlist by default. When set they are absolute, built
+    /// from the host and `X-Forwarded-Proto` of the request, for players that need them.
+    #[serde(default)]
+    absolute_urls: bool,
+    /// Container of the segments, MPEG-TS or fragmented MP4
+    #[serde(default)]
+    video_type: VideoType,
+    /// Where the segments are cut, every `segment_length` by default
+    #[serde(default)]
+    segmentation: Segmentation,
+    /// Tag the segments with `EXT-X-PROGRAM-DATE-TIME`, when their first frame is named after
+    /// its wall-clock time in milliseconds since the epoch
+    #[serde(default)]
+    program_date_time: bool,
+}
+
+/// Frame names from 2000 to 2100 are taken for milliseconds since the epoch, the frame indices
+/// of cameras counting from 0 are far below
+const EPOCH_MS_RANGE: std::ops::Range<i64> = 946_684_800_000..4_102_444_800_000;
+
+/// `date-time` of `EXT-X-PROGRAM-DATE-TIME` in UTC with milliseconds, RFC 3339
+fn program_date_time(epoch_ms: i64) -> String {
+    let (days, ms_of_day) = (
+        epoch_ms.div_euclid(86_400_000),
+        epoch_ms.rem_euclid(86_400_000),
+    );
+    // Civil date of the days since the epoch, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000
+    )
+}
+
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// Base of the segment and init segment URLs of the playlist, the `/v1` of the API
+fn playlist_url_base(
+    absolute_urls: bool,
+    host: Option<Host>,
+    headers: &HeaderMap,
+) -> errors::Result<String> {
+    if !absolute_urls {
+        // Relative to `/v1/playlist/{log_name}`, so it works behind proxies and on any port
+        return Ok("..".to_string());
+    }
+    let Some(Host(host)) = host else {
+        Err(errors::ErrorKind::BadRequestError(
+            "`absolute_urls` needs the host of the request".to_string(),
+        ))?
+    };
+    let scheme = headers
+        .get(X_FORWARDED_PROTO)
+        .and_then(|v| v.to_str().ok())
+        // A proxy chain may list several, the first one is what the client used
+        .and_then(|v| v.split(',').next())
+        .map(str::trim)
+        // Anything else isn't a scheme the segments are served with
+        .and_then(|v| {
+            ["http", "https"]
+                .into_iter()
+                .find(|s| v.eq_ignore_ascii_case(s))
+        })
+        .unwrap_or("http");
+    Ok(format!("{scheme}://{host}/v1"))
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path, headers))]
+pub async fn get_playlist(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<PlaylistQuery>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> errors::Result<impl IntoResponse> {
+    let started = Instant::now();
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    check_fps(query.fps)?;
+    check_segment_length(query.segment_length_ms)?;
+    let segment_params = match query.video_type {
+        VideoType::MpegTs => "",
+        VideoType::FragmentedMp4 => {
+            check_mp4_codec()?;
+            "&video_type=FragmentedMp4"
+        }
+        video_type => Err(errors::ErrorKind::BadRequestError(format!(
+            "HLS doesn't play {video_type:?} segments"
+        )))?,
+    };
+    let url_base = playlist_url_base(query.absolute_urls, host, &headers)?;
+    let log_path = encode_log_name(&log_name);
+    let (path, segmentation) = (path_to_h264_frames.clone(), query.segmentation);
+    let (files, keyframes, has_captions) = tokio::task::spawn_blocking(move || {
+        let files = get_frames(&path)?;
+        let keyframes = match segmentation {
+            Segmentation::Fixed => None,
+            Segmentation::Keyframes => Some(get_keyframes(&path)?),
+        };
+        let has_captions = has_captions(&path, &files)?;
+        Ok::<_, errors::AppError>((files, keyframes, has_captions))
+    })
+    .await??;
+    let segments = split_into_segments(
+        &files,
+        query.segment_length_ms,
+        query.fps,
+        keyframes.as_deref(),
+    );
+
+    // EXT-X-MAP in a playlist that isn't I-frames only needs version 6
+    let version = match query.video_type {
+        VideoType::FragmentedMp4 => 6,
+        _ => 3,
+    };
+    let mut playlist = format!("#EXTM3U\n#EXT-X-VERSION:{version}\n");
+    // Of all the segments rather than the page, so it doesn't change between the pages
+    let target_duration_secs = segments
+        .iter()
+        .map(|segment| segment.duration_ms.div_ceil(1000))
+        .max()
+        .unwrap_or(0);
+    playlist += format!("#EXT-X-TARGETDURATION:{target_duration_secs}\n").as_str();
+    if has_captions {
+        playlist += CLOSED_CAPTIONS_MEDIA;
+    }
+    playlist += format!("#EXT-X-MEDIA-SEQUENCE:{}\n", query.from_index).as_str();
+    if matches!(query.video_type, VideoType::FragmentedMp4) {
+        playlist += format!("#EXT-X-MAP:URI=\"{url_base}/init/{log_path}\"\n").as_str();
+    }
+
+    // Out of range pages are just empty playlists
+    let page: Vec<&PlaylistSegment> = segments
+        .iter()
+        .skip(query.from_index)
+        .take(query.count.unwrap_or(usize::MAX))
+        .collect();
+    // Players number the discontinuities, a page continues the numbering of the previous ones
+    let discontinuity_sequence = segments
+        .iter()
+        .take(query.from_index)
+        .filter(|segment| segment.discontinuity)
+        .count();
+    if discontinuity_sequence != 0 {
+        playlist += format!("#EXT-X-DISCONTINUITY-SEQUENCE:{discontinuity_sequence}\n").as_str();
+    }
+    prewarm_segments(
+        &log_name,
+        &path_to_h264_frames,
+        &page,
+        query.video_type,
+        query.fps,
+    );
+    for segment in page {
+        let PlaylistSegment {
+            offset_ms,
+            length_ms,
+            offset_frames,
+            duration_ms,
+            discontinuity,
+            ..
+        } = segment;
+        if *discontinuity {
+            playlist += "#EXT-X-DISCONTINUITY\n";
+        }
+        if query.program_date_time {
+            let first_frame = &files[*offset_frames];
+            match frame_index(first_frame).filter(|ms| EPOCH_MS_RANGE.contains(ms)) {
+                Some(epoch_ms) => {
+                    let date_time = program_date_time(epoch_ms);
+                    playlist += format!("#EXT-X-PROGRAM-DATE-TIME:{date_time}\n").as_str();
+                }
+                None => debug!("{first_frame} is not named after its time, no date-time tag"),
+            }
+        }
+        let duration_secs = *duration_ms as f64 / 1000.0;
+        playlist += format!("#EXTINF:{duration_secs:.3},\n").as_str();
+        playlist += format!(
+            "{url_base}/segment/{log_path}?offset={offset_ms}&length={length_ms}&fps={}{segment_params}\n",
+            query.fps
+        )
+        .as_str();
+    }
+    playlist += "#EXT-X-ENDLIST";
+    metrics::record_playlist(started.elapsed());
+
+    Ok((
+        PLAYLIST_CONTENT_TYPE,
+        [(TOTAL_SEGMENTS_HEADER, segments.len().to_string())],
+        playlist,
+    ))
+}
+
+/// Logs a concat playlist takes at most
+const MAX_CONCAT_LOGS: usize = 64;
+
+#[derive(Debug, Deserialize)]
+pub struct ConcatPlaylistQuery {
+    /// Comma separated names of the logs, they are played in this order
+    logs: String,
+    /// Frame rate of the cameras, see `Pagination::fps`
+    #[serde(default = "default_fps")]
+    fps: u32,
+    /// See `PlaylistQuery::segment_length_ms`
+    #[serde(rename = "segment_length", default = "default_segment_length_ms")]
+    segment_length_ms: usize,
+    /// See `PlaylistQuery::absolute_urls`
+    #[serde(default)]
+    absolute_urls: bool,
+}
+
+/// Names of the `logs` of a concat playlist
+fn concat_log_names(logs: &str) -> errors::Result<Vec<&str>> {
+    let log_names: Vec<&str> = logs.split(',').map(str::trim).collect();
+    if log_names.len() > MAX_CONCAT_LOGS {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "At most {MAX_CONCAT_LOGS} logs can be concatenated, got {}",
+            log_names.len()
+        )))?
+    }
+    for log_name in &log_names {
+        validate_log_name(log_name)?;
+    }
+    Ok(log_names)
+}
+
+/// MPEG-TS playlist of several logs one after the other, e.g. of a camera that went on in a new
+/// log. The segments are the ones of the logs, a log starts a discontinuity and its timestamps
+/// go on from the logs before it.
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path, headers))]
+pub async fn get_concat_playlist(
+    State(base_path): State<BasePath>,
+    Query(query): Query<ConcatPlaylistQuery>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> errors::Result<impl IntoResponse> {
+    let started = Instant::now();
+    check_fps(query.fps)?;
+    check_segment_length(query.segment_length_ms)?;
+    let log_names = concat_log_names(&query.logs)?;
+    let url_base = playlist_url_base(query.absolute_urls, host, &headers)?;
+
+    let paths: Vec<String> = log_names.iter().map(|l| base_path.log_path(l)).collect();
+    let logs = tokio::task::spawn_blocking(move || {
+        paths
+            .into_iter()
+            .map(|path| {
+                let files = get_frames(&path)?;
+                let has_captions = has_captions(&path, &files)?;
+                Ok((files, has_captions))
+            })
+            .collect::<errors::Result<Vec<_>>>()
+    })
+    .await??;
+
+    let mut has_any_captions = false;
+    // Segments of all the logs with the log and the frames of the logs before it
+    let mut segments: Vec<(&str, usize, PlaylistSegment)> = Vec::new();
+    let mut log_start_frame = 0;
+    for (log_name, (files, has_captions)) in log_names.into_iter().zip(logs) {
+        has_any_captions |= has_captions;
+        for mut segment in split_into_segments(&files, query.segment_length_ms, query.fps, None) {
+            segment.discontinuity |= log_start_frame > 0 && segment.offset_ms == 0;
+            segments.push((log_name, log_start_frame, segment));
+        }
+        log_start_frame += frame_positions(&files).last().map_or(0, |last| last + 1);
+    }
+
+    let mut playlist = "#EXTM3U\n#EXT-X-VERSION:3\n".to_string();
+    let target_duration_secs = segments
+        .iter()
+        .map(|(_, _, segment)| segment.duration_ms.div_ceil(1000))
+        .max()
+        .unwrap_or(0);
+    playlist += format!("#EXT-X-TARGETDURATION:{target_duration_secs}\n").as_str();
+    if has_any_captions {
+        playlist += CLOSED_CAPTIONS_MEDIA;
+    }
+    playlist += "#EXT-X-MEDIA-SEQUENCE:0\n";
+    for (log_name, log_start_frame, segment) in &segments {
+        if segment.discontinuity {
+            playlist += "#EXT-X-DISCONTINUITY\n";
+        }
+        let duration_secs = segment.duration_ms as f64 / 1000.0;
+        playlist += format!("#EXTINF:{duration_secs:.3},\n").as_str();
+        playlist += format!(
+            "{url_base}/segment/{}?offset={}&length={}&fps={}",
+            encode_log_name(log_name),
+            segment.offset_ms,
+            segment.length_ms,
+            query.fps
+        )
+        .as_str();
+        if *log_start_frame > 0 {
+            playlist += format!("&log_start_frame={log_start_frame}").as_str();
+        }
+        playlist += "\n";
+    }
+    playlist += "#EXT-X-ENDLIST";
+    metrics::record_playlist(started.elapsed());
+
+    Ok((
+        PLAYLIST_CONTENT_TYPE,
+        [(TOTAL_SEGMENTS_HEADER, segments.len().to_string())],
+        playlist,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MasterPlaylistQuery {
+    /// Frame rate of the camera, passed on to the media playlist
+    #[serde(default = "default_fps")]
+    fps: u32,
+    /// Passed on to the media playlist, see `PlaylistQuery::segment_length_ms`
+    #[serde(rename = "segment_length", default = "default_segment_length_ms")]
+    segment_length_ms: usize,
+}
+
+/// `EXT-X-STREAM-INF` entry of the master playlist
+#[derive(Debug)]
+struct Rendition {
+    /// Peak bitrate in bits/s
+    bandwidth: u64,
+    /// `RESOLUTION` and `CODECS` are left out without it
+    sps: Option<Sps>,
+    has_captions: bool,
+    uri: String,
+    /// `EXT-X-I-FRAME-STREAM-INF` of the rendition, absent without keyframes
+    iframes: Option<IFrameRendition>,
+}
+
+#[derive(Debug)]
+struct IFrameRendition {
+    /// Peak bitrate of the I-frames in bits/s
+    bandwidth: u64,
+    uri: String,
+}
+
+fn master_playlist(renditions: &[Rendition]) -> String {
+    let mut playlist = "#EXTM3U\n#EXT-X-VERSION:3\n".to_string();
+    if renditions.iter().any(|r| r.has_captions) {
+        playlist += CLOSED_CAPTIONS_MEDIA;
+    }
+    for rendition in renditions {
+        playlist += format!("#EXT-X-STREAM-INF:BANDWIDTH={}", rendition.bandwidth).as_str();
+        if let Some(sps) = &rendition.sps {
+            playlist += format!(
+                ",RESOLUTION={}x{},CODECS=\"{}\"",
+                sps.width,
+                sps.height,
+                sps.codecs()
+            )
+            .as_str();
+        }
+        if rendition.has_captions {
+            playlist += ",CLOSED-CAPTIONS=\"cc\"";
+        }
+        playlist += format!("\n{}\n", rendition.uri).as_str();
+    }
+    for rendition in renditions {
+        let Some(iframes) = &rendition.iframes else {
+            continue;
+        };
+        playlist += format!("#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH={}", iframes.bandwidth).as_str();
+        if let Some(sps) = &rendition.sps {
+            playlist += format!(
+                ",RESOLUTION={}x{},CODECS=\"{}\"",
+                sps.width,
+                sps.height,
+                sps.codecs()
+            )
+            .as_str();
+        }
+        playlist += format!(",URI=\"{}\"\n", iframes.uri).as_str();
+    }
+    playlist
+}
+
+/// Keyframe of the I-frame playlist, a byte range of the MPEG-TS segment it is in
+#[derive(Debug)]
+struct IFrame<'a> {
+    segment: &'a PlaylistSegment,
+    /// Position of the keyframe in the frame list
+    frame: usize,
+    /// Packets of the keyframe in the segment, from its PES header to its last byte
+    offset: usize,
+    length: usize,
+    /// Time until the next keyframe or the end of the recording, including the dropped frames
+    duration_ms: usize,
+    /// First keyframe after the discontinuity of its segment or of segments without keyframes
+    discontinuity: bool,
+}
+
+/// Byte ranges of the keyframes in the MPEG-TS segments, from the frame sizes without muxing the
+/// segments. The PCR schedule of the muxer is replayed, a PCR takes room in the first packet of
+/// a frame. Frames before the first keyframe are not in any range.
+fn index_iframes<'a>(
+    path_to_h264_frames: &str,
+    files: &[String],
+    segments: &'a [PlaylistSegment],
+    keyframes: &[usize],
+    fps: u32,
+) -> errors::Result<Vec<IFrame<'a>>> {
+    let mut iframes: Vec<IFrame> = Vec::with_capacity(keyframes.len());
+    let positions = frame_positions(files);
+    let header_size = new_transport_stream().header_size();
+    let mut discontinuity = false;
+    for segment in segments {
+        discontinuity |= segment.discontinuity;
+        // Like `h264streams_to_mpegts_chunks` muxes the segment
+        let (offset_frames, frames) = (segment.offset_frames, segment.frames);
+        let mut pcr_schedule = PcrSchedule::default();
+        let mut offset = header_size;
+        for (frame, f) in files.iter().enumerate().skip(offset_frames).take(frames) {
+            let keyframe = keyframes.binary_search(&frame).is_ok();
+            let timestamp = positions[frame] as u64 * 1000 / fps as u64;
+            let with_pcr = pcr_schedule.needs_pcr(timestamp, keyframe);
+            let size = muxed_frame_size(path_to_h264_frames, f, with_pcr)?;
+            if keyframe {
+                iframes.push(IFrame {
+                    segment,
+                    frame,
+                    offset: offset + size.before_video,
+                    length: size.video,
+                    duration_ms: 0,
+                    discontinuity: std::mem::take(&mut discontinuity),
+                });
+            }
+            offset += size.total();
+        }
+    }
+
+    let end = positions.last().map_or(0, |last| last + 1);
+    for idx in 0..iframes.len() {
+        let next = iframes
+            .get(idx + 1)
+            .map_or(end, |next| positions[next.frame]);
+        iframes[idx].duration_ms = frames_to_ms(next - positions[iframes[idx].frame], fps);
+    }
+    Ok(iframes)
+}
+
+/// Peak bitrate of the I-frame playlist
+fn iframes_bandwidth(iframes: &[IFrame]) -> u64 {
+    iframes
+        .iter()
+        .map(|iframe| iframe.length as u64 * 8 * 1000 / iframe.duration_ms.max(1) as u64)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Peak bitrate of the MPEG-TS segments, estimated from the frame sizes without muxing them
+pub fn estimate_bandwidth(
+    path_to_h264_frames: &str,
+    files: &[String],
+    segments: &[PlaylistSegment],
+) -> errors::Result<u64> {
+    let mut bandwidth = 0;
+    for segment in segments {
+        let mut size = new_transport_stream().header_size() as u64;
+        let (offset_frames, frames) = (segment.offset_frames, segment.frames);
+        for f in files.iter().skip(offset_frames).take(frames) {
+            size += muxed_frame_size(path_to_h264_frames, f, false)?.total() as u64;
+        }
+        if let Some(target_size) = *TS_SEGMENT_PAD_SIZE {
+            size = size.max(target_size.next_multiple_of(mpegts::PACKET_SIZE) as u64);
+        }
+        let duration_ms = segment.duration_ms.max(1) as u64;
+        bandwidth = bandwidth.max(size * 8 * 1000 / duration_ms);
+    }
+    Ok(bandwidth)
+}
+
+/// Rendition of the log in the master playlist, it reads the sizes of all the frames
+fn master_rendition(
+    log_name: &str,
+    path_to_h264_frames: &str,
+    query: &MasterPlaylistQuery,
+) -> errors::Result<Rendition> {
+    let files = get_frames(path_to_h264_frames)?;
+    let segments = split_into_segments(&files, query.segment_length_ms, query.fps, None);
+
+    let sps = match get_parameter_sets(log_name, path_to_h264_frames) {
+        Ok(params) => Sps::parse_for(*VIDEO_CODEC, &params.sps),
+        Err(err) => {
+            warn!("Master playlist of {log_name} is without RESOLUTION and CODECS: {err}");
+            None
+        }
+    };
+    let keyframes = get_keyframes(path_to_h264_frames)?;
+    let iframes = index_iframes(
+        path_to_h264_frames,
+        &files,
+        &segments,
+        &keyframes,
+        query.fps,
+    )?;
+    let params = format!(
+        "fps={}&segment_length={}",
+        query.fps, query.segment_length_ms
+    );
+    // Relative to `/v1/master/{log_name}`
+    let log_path = encode_log_name(log_name);
+    Ok(Rendition {
+        bandwidth: estimate_bandwidth(path_to_h264_frames, &files, &segments)?,
+        sps,
+        has_captions: has_captions(path_to_h264_frames, &files)?,
+        uri: format!("../playlist/{log_path}?{params}"),
+        iframes: (!iframes.is_empty()).then(|| IFrameRendition {
+            bandwidth: iframes_bandwidth(&iframes),
+            uri: format!("../iframes/{log_path}?{params}"),
+        }),
+    })
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_master_playlist(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<MasterPlaylistQuery>,
+) -> errors::Result<impl IntoResponse> {
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    check_fps(query.fps)?;
+    check_segment_length(query.segment_length_ms)?;
+    let rendition = tokio::task::spawn_blocking(move || {
+        master_rendition(&log_name, &path_to_h264_frames, &query)
+    })
+    .await??;
+    Ok((PLAYLIST_CONTENT_TYPE, master_playlist(&[rendition])))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IFramePlaylistQuery {
+    /// Frame rate of the camera, see `Pagination::fps`
+    #[serde(default = "default_fps")]
+    fps: u32,
+    /// Length of the segments holding the I-frames, the ones of the media playlist
+    #[serde(rename = "segment_length", default = "default_segment_length_ms")]
+    segment_length_ms: usize,
+    /// See `PlaylistQuery::absolute_urls`
+    #[serde(default)]
+    absolute_urls: bool,
+}
+
+/// I-frame playlist for trick play, RFC 8216 section 4.3.3.6. Every keyframe is the byte range
+/// of its packets in the MPEG-TS segment of the media playlist, so the players fetch just the
+/// keyframes out of the same cached segments.
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path, headers))]
+pub async fn get_iframe_playlist(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<IFramePlaylistQuery>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> errors::Result<impl IntoResponse> {
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    check_fps(query.fps)?;
+    check_segment_length(query.segment_length_ms)?;
+    let url_base = playlist_url_base(query.absolute_urls, host, &headers)?;
+    let playlist = tokio::task::spawn_blocking(move || {
+        iframe_playlist(&log_name, &path_to_h264_frames, &url_base, &query)
+    })
+    .await??;
+    Ok((PLAYLIST_CONTENT_TYPE, playlist))
+}
+
+/// It reads the sizes of all the frames of the log
+fn iframe_playlist(
+    log_name: &str,
+    path_to_h264_frames: &str,
+    url_base: &str,
+    query: &IFramePlaylistQuery,
+) -> errors::Result<String> {
+    let files = get_frames(path_to_h264_frames)?;
+    let segments = split_into_segments(&files, query.segment_length_ms, query.fps, None);
+    let keyframes = get_keyframes(path_to_h264_frames)?;
+    let iframes = index_iframes(
+        path_to_h264_frames,
+        &files,
+        &segments,
+        &keyframes,
+        query.fps,
+    )?;
+
+    // EXT-X-MAP in an I-frames only playlist needs version 5
+    let mut playlist = "#EXTM3U\n#EXT-X-VERSION:5\n".to_string();
+    let target_duration_secs = iframes
+        .iter()
+        .map(|iframe| iframe.duration_ms.div_ceil(1000))
+        .max()
+        .unwrap_or(0);
+    playlist += format!("#EXT-X-TARGETDURATION:{target_duration_secs}\n").as_str();
+    playlist += "#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-I-FRAMES-ONLY\n";
+    let log_path = encode_log_name(log_name);
+    let mut prev_segment: Option<&PlaylistSegment> = None;
+    for iframe in &iframes {
+        let segment = iframe.segment;
+        let uri = format!(
+            "{url_base}/segment/{log_path}?offset={}&length={}&fps={}",
+            segment.offset_ms, segment.length_ms, query.fps
+        );
+        if iframe.discontinuity {
+            playlist += "#EXT-X-DISCONTINUITY\n";
+        }
+        // The keyframes need PAT and PMT of their segment to be decoded
+        if !prev_segment.is_some_and(|prev| std::ptr::eq(prev, segment)) {
+            playlist += format!(
+                "#EXT-X-MAP:URI=\"{uri}\",BYTERANGE=\"{}@0\"\n",
+                new_transport_stream().header_size()
+            )
+            .as_str();
+            prev_segment = Some(segment);
+        }
+        let duration_secs = iframe.duration_ms as f64 / 1000.0;
+        playlist += format!("#EXTINF:{duration_secs:.3},\n").as_str();
+        playlist += format!("#EXT-X-BYTERANGE:{}@{}\n", iframe.length, iframe.offset).as_str();
+        playlist += format!("{uri}\n").as_str();
+    }
+    playlist += "#EXT-X-ENDLIST";
+    Ok(playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::tests::write_log;
+    use axum::body::to_bytes;
+    use axum::http::{StatusCode, Uri};
+    use std::fs;
+
+    async fn playlist(base_path: &BasePath, log_name: &str, query: &str) -> String {
+        playlist_of_host(base_path, log_name, query, None, HeaderMap::new()).await
+    }
+
+    async fn playlist_of_host(
+        base_path: &BasePath,
+        log_name: &str,
+        query: &str,
+        host: Option<Host>,
+        headers: HeaderMap,
+    ) -> String {
+        let uri: Uri = format!("/v1/playlist/log?{query}").parse().unwrap();
+        let response = get_playlist(
+            State(base_path.clone()),
+            LogName(log_name.to_string()),
+            Query::try_from_uri(&uri).unwrap(),
+            host,
+            headers,
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn logs_without_frames_have_no_playlist() {
+        let base_path = write_log("empty", [], 100);
+        let uri: Uri = "/v1/playlist/empty".parse().unwrap();
+        let result = get_playlist(
+            State(base_path.clone()),
+            LogName("empty".to_string()),
+            Query::try_from_uri(&uri).unwrap(),
+            None,
+            HeaderMap::new(),
+        )
+        .await;
+        let response = match result {
+            Ok(_) => panic!("Playlist of a log without frames"),
+            Err(err) => err.into_response(),
+        };
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[errors::ERROR_CODE_HEADER], "40402");
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gaps_are_not_in_the_segment_durations() {
+        // 5 s at 20 fps on both sides of a gap of 5 s
+        let base_path = write_log("gap", (0..100).chain(200..300), 64);
+        let playlist = playlist(&base_path, "gap", "fps=20&segment_length=5000").await;
+        let extinf: Vec<&str> = playlist
+            .lines()
+            .filter_map(|line| line.strip_prefix("#EXTINF:"))
+            .collect();
+        assert_eq!(extinf, ["5.000,", "5.000,"]);
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:5\n"));
+        assert!(playlist.contains("#EXT-X-DISCONTINUITY\n"));
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_last_segment_has_a_fractional_duration() {
+        let base_path = write_log("fractional", 0..105, 64);
+        let playlist = playlist(&base_path, "fractional", "fps=20&segment_length=5000").await;
+        let extinf: Vec<&str> = playlist
+            .lines()
+            .filter_map(|line| line.strip_prefix("#EXTINF:"))
+            .collect();
+        assert_eq!(extinf, ["5.000,", "0.250,"]);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    fn segment_urls(playlist: &str) -> Vec<&str> {
+        playlist
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn segment_urls_are_relative_by_default() {
+        let base_path = write_log("relative", 0..40, 64);
+        let playlist = playlist(&base_path, "relative", "fps=20&segment_length=1000").await;
+        assert_eq!(
+            segment_urls(&playlist),
+            [
+                "../segment/relative?offset=0&length=1000&fps=20",
+                "../segment/relative?offset=1000&length=1000&fps=20",
+            ]
+        );
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_names_are_percent_encoded_in_the_segment_urls() {
+        let log_name = "cam 1#2?3%";
+        let base_path = write_log(log_name, 0..20, 64);
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_PROTO, "javascript".parse().unwrap());
+        let playlist = playlist_of_host(
+            &base_path,
+            log_name,
+            "fps=20&absolute_urls=true",
+            Some(Host("example.com".to_string())),
+            headers,
+        )
+        .await;
+        assert_eq!(
+            segment_urls(&playlist),
+            ["http://example.com/v1/segment/cam%201%232%3F3%25?offset=0&length=1000&fps=20"]
+        );
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+}
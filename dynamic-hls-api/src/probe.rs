@@ -0,0 +1,175 @@
+// Listing and describing the logs: `/v1/logs`, `/v1/probe` and `/v1/params`
+use crate::errors;
+use crate::h264::Sps;
+use crate::lookups::{get_keyframes, get_parameter_sets};
+use crate::mux::VIDEO_CODEC;
+use crate::routes::{
+    check_fps, default_fps, frame_positions, frames_to_ms, get_frames, BasePath, LogName,
+};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::{debug_handler, extract::Query, Json};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use tracing::{debug, warn};
+
+#[derive(Debug, Serialize)]
+struct ParameterSetsResponse {
+    /// Base64 encoded SPS NAL unit, without start code
+    sps: String,
+    /// Base64 encoded PPS NAL unit, without start code
+    pps: String,
+    /// Parsed SPS fields, absent when the SPS could not be parsed
+    #[serde(flatten)]
+    parsed_sps: Option<Sps>,
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_params(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+) -> errors::Result<impl IntoResponse> {
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    let params =
+        tokio::task::spawn_blocking(move || get_parameter_sets(&log_name, &path_to_h264_frames))
+            .await??;
+    let b64 = base64::engine::general_purpose::STANDARD;
+    Ok(Json(ParameterSetsResponse {
+        sps: b64.encode(&params.sps),
+        pps: b64.encode(&params.pps),
+        parsed_sps: Sps::parse_for(*VIDEO_CODEC, &params.sps),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogsQuery {
+    /// Frame rate of the cameras, the durations are estimated with it
+    #[serde(default = "default_fps")]
+    fps: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct LogResponse {
+    log_name: String,
+    frames: usize,
+    /// Elapsed time of the recording including dropped frames, like the playlist
+    duration_ms: usize,
+}
+
+/// Subdirectories of `BASE_PATH` with frames in them, sorted by name. A missing `BASE_PATH` has no
+/// logs rather than being an error.
+fn list_logs(base_path: &BasePath, fps: u32) -> errors::Result<Vec<LogResponse>> {
+    let path = base_path.get();
+    let entries = match fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "`BASE_PATH` {} does not exist, there are no logs",
+                path.display()
+            );
+            return Ok(Vec::new());
+        }
+        Err(err) => Err(err)?,
+    };
+    let mut logs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(log_name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        // Directories without frames are not logs
+        let Ok(files) = get_frames(&format!("{}/{}", path.display(), log_name)) else {
+            debug!(
+                "Skipping {log_name} in {}, it has no frames",
+                path.display()
+            );
+            continue;
+        };
+        let elapsed = frame_positions(&files).last().map_or(0, |last| last + 1);
+        logs.push(LogResponse {
+            log_name,
+            frames: files.len(),
+            duration_ms: frames_to_ms(elapsed, fps),
+        });
+    }
+    logs.sort_by(|a, b| a.log_name.cmp(&b.log_name));
+    Ok(logs)
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_logs(
+    State(base_path): State<BasePath>,
+    query: Query<LogsQuery>,
+) -> errors::Result<impl IntoResponse> {
+    check_fps(query.fps)?;
+    let fps = query.fps;
+    let logs = tokio::task::spawn_blocking(move || list_logs(&base_path, fps)).await??;
+    Ok(Json(logs))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProbeQuery {
+    /// Frame rate of the camera, the durations are estimated with it
+    #[serde(default = "default_fps")]
+    fps: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct ProbeResponse {
+    log_name: String,
+    /// `CODECS` of the playlists, absent when the SPS could not be parsed
+    codecs: Option<String>,
+    /// Parsed fields of the first SPS, absent when it could not be parsed
+    #[serde(flatten)]
+    sps: Option<Sps>,
+    frames: usize,
+    /// Elapsed time of the recording including dropped frames, like the playlist
+    duration_ms: usize,
+    /// IDR frames, the number of GOPs `gop` of the segments selects from
+    keyframes: usize,
+    /// Mean time from an IDR frame to the next one, absent with less than two IDR frames
+    keyframe_interval_ms: Option<usize>,
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_probe(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<ProbeQuery>,
+) -> errors::Result<impl IntoResponse> {
+    check_fps(query.fps)?;
+    let (log, path) = (log_name.clone(), base_path.log_path(&log_name));
+    let (files, params, keyframes) = tokio::task::spawn_blocking(move || {
+        let files = get_frames(&path)?;
+        let params = get_parameter_sets(&log, &path)?;
+        Ok::<_, errors::AppError>((files, params, get_keyframes(&path)?))
+    })
+    .await??;
+    let sps = Sps::parse_for(*VIDEO_CODEC, &params.sps);
+
+    let positions = frame_positions(&files);
+    let elapsed = positions.last().map_or(0, |last| last + 1);
+    let keyframe_interval_ms = match (keyframes.first(), keyframes.last()) {
+        (Some(&first), Some(&last)) if keyframes.len() > 1 => {
+            let gops = keyframes.len() - 1;
+            Some(frames_to_ms(positions[last] - positions[first], query.fps) / gops)
+        }
+        _ => None,
+    };
+    Ok(Json(ProbeResponse {
+        log_name,
+        codecs: sps.as_ref().map(Sps::codecs),
+        sps,
+        frames: files.len(),
+        duration_ms: frames_to_ms(elapsed, query.fps),
+        keyframes: keyframes.len(),
+        keyframe_interval_ms,
+    }))
+}
@@ -0,0 +1,281 @@
+// HTTP Range requests, RFC 9110 section 14
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::header;
+
+/// Most ranges a request is answered with, a `Range` with more gets the whole body
+pub const MAX_RANGES: usize = 16;
+
+/// Range of the `bytes` unit as requested, before it is checked against the body length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRangeSpec {
+    /// `first-last` with both ends inclusive, `first-` to the end when `last` is absent
+    FromTo { first: u64, last: Option<u64> },
+    /// `-length`, the last `length` bytes
+    Suffix(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    Bytes(Vec<ByteRangeSpec>),
+    /// Any other range unit, e.g. `items=0-10`
+    UnsupportedUnit(String),
+}
+
+/// Parses the value of the `Range` header, `None` when it is malformed. Servers ignore
+/// malformed headers and answer with the whole body.
+pub fn parse_range(value: &str) -> Option<RangeRequest> {
+    let (unit, ranges) = value.trim().split_once('=')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return Some(RangeRequest::UnsupportedUnit(unit.to_string()));
+    }
+    let specs = ranges
+        .split(',')
+        .map(str::trim)
+        // The list may contain empty elements, RFC 9110 section 5.6.1
+        .filter(|spec| !spec.is_empty())
+        .map(parse_byte_range_spec)
+        .collect::<Option<Vec<_>>>()?;
+    if specs.is_empty() {
+        return None;
+    }
+    Some(RangeRequest::Bytes(specs))
+}
+
+fn parse_byte_range_spec(spec: &str) -> Option<ByteRangeSpec> {
+    let (first, last) = spec.split_once('-')?;
+    if first.is_empty() {
+        return Some(ByteRangeSpec::Suffix(parse_digits(last)?));
+    }
+    let first = parse_digits(first)?;
+    let last = match last {
+        "" => None,
+        last => Some(parse_digits(last)?),
+    };
+    if last.is_some_and(|last| last < first) {
+        return None;
+    }
+    Some(ByteRangeSpec::FromTo { first, last })
+}
+
+/// Unlike `str::parse`, doesn't accept a sign
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Byte ranges of a body of `len` bytes selected by `specs`, in the requested order.
+/// Unsatisfiable ranges are left out, none left means `416 Range Not Satisfiable`.
+pub fn satisfiable_ranges(specs: &[ByteRangeSpec], len: u64) -> Vec<Range<u64>> {
+    specs
+        .iter()
+        .filter_map(|spec| match *spec {
+            ByteRangeSpec::FromTo { first, .. } if first >= len => None,
+            ByteRangeSpec::FromTo { first, last } => {
+                let end = last.map_or(len, |last| last.saturating_add(1).min(len));
+                Some(first..end)
+            }
+            ByteRangeSpec::Suffix(length) if length == 0 || len == 0 => None,
+            ByteRangeSpec::Suffix(length) => Some(len.saturating_sub(length)..len),
+        })
+        .collect()
+}
+
+/// Ranges of a body of `len` bytes to answer `specs` with, overlapping and adjacent ones merged
+/// in the order of their start. `None` when the whole body is sent instead, for more than
+/// `MAX_RANGES` ranges or ranges that add up to more than the body, RFC 9110 section 14.2 lets
+/// servers ignore such a `Range`. No ranges left means `416 Range Not Satisfiable`.
+pub fn ranges_to_send(specs: &[ByteRangeSpec], len: u64) -> Option<Vec<Range<u64>>> {
+    if specs.len() > MAX_RANGES {
+        return None;
+    }
+    let mut ranges = satisfiable_ranges(specs, len);
+    let requested: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+    if requested > len {
+        return None;
+    }
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    Some(merged)
+}
+
+/// `Content-Range` value of a part of the body
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// `Content-Range` value of the `416` response
+pub fn unsatisfied_content_range(len: u64) -> String {
+    format!("bytes */{}", len)
+}
+
+/// Boundary of the `multipart/byteranges` parts, random enough not to occur in the body
+pub fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("byteranges_{:032x}", nanos)
+}
+
+/// Body of the `multipart/byteranges` response, RFC 9110 section 14.6. Each part has the
+/// `Content-Type` of the whole body and its own `Content-Range`.
+pub fn multipart_byteranges(
+    body: &[u8],
+    ranges: &[Range<u64>],
+    content_type: &str,
+    boundary: &str,
+) -> Vec<u8> {
+    let len = body.len() as u64;
+    let mut multipart = Vec::new();
+    for r in ranges {
+        let part_header = format!(
+            "--{boundary}\r\n{}: {content_type}\r\n{}: {}\r\n\r\n",
+            header::CONTENT_TYPE,
+            header::CONTENT_RANGE,
+            content_range(r, len)
+        );
+        multipart.extend_from_slice(part_header.as_bytes());
+        multipart.extend_from_slice(&body[r.start as usize..r.end as usize]);
+        multipart.extend_from_slice(b"\r\n");
+    }
+    multipart.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    multipart
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(value: &str) -> Vec<ByteRangeSpec> {
+        match parse_range(value) {
+            Some(RangeRequest::Bytes(specs)) => specs,
+            other => panic!("{value} is not a byte range: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(
+            bytes("bytes=0-10"),
+            [ByteRangeSpec::FromTo {
+                first: 0,
+                last: Some(10)
+            }]
+        );
+        assert_eq!(
+            bytes("bytes=0-10, 20-30,,40-"),
+            [
+                ByteRangeSpec::FromTo {
+                    first: 0,
+                    last: Some(10)
+                },
+                ByteRangeSpec::FromTo {
+                    first: 20,
+                    last: Some(30)
+                },
+                ByteRangeSpec::FromTo {
+                    first: 40,
+                    last: None
+                },
+            ]
+        );
+        assert_eq!(bytes("Bytes=-500"), [ByteRangeSpec::Suffix(500)]);
+    }
+
+    #[test]
+    fn tells_unsupported_units_apart() {
+        assert_eq!(
+            parse_range("items=0-10"),
+            Some(RangeRequest::UnsupportedUnit("items".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        for value in [
+            "bytes",
+            "bytes=",
+            "bytes=,",
+            "bytes=10-5",
+            "bytes=a-5",
+            "bytes=+1-5",
+            "bytes=-",
+            "bytes=0-10,x",
+        ] {
+            assert_eq!(parse_range(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn satisfies_ranges_within_the_body() {
+        let specs = bytes("bytes=0-9,95-200,-5,100-,-0");
+        assert_eq!(satisfiable_ranges(&specs, 100), [0..10, 95..100, 95..100]);
+        assert_eq!(
+            satisfiable_ranges(&bytes("bytes=-500"), 100),
+            [Range { start: 0, end: 100 }]
+        );
+        assert!(satisfiable_ranges(&bytes("bytes=100-"), 100).is_empty());
+        assert!(satisfiable_ranges(&bytes("bytes=-5"), 0).is_empty());
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let specs = bytes("bytes=50-59,0-9,10-19,15-29,70-");
+        assert_eq!(
+            ranges_to_send(&specs, 100),
+            Some(vec![0..30, 50..60, 70..100])
+        );
+        assert_eq!(ranges_to_send(&bytes("bytes=200-"), 100), Some(vec![]));
+    }
+
+    #[test]
+    fn sends_the_whole_body_for_too_many_ranges() {
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(ranges_to_send(&bytes(&format!("bytes={many}")), 100), None);
+        let repeated = ["0-"; 2].join(",");
+        assert_eq!(
+            ranges_to_send(&bytes(&format!("bytes={repeated}")), 100),
+            None
+        );
+        let whole = ranges_to_send(&bytes("bytes=0-"), 100).unwrap();
+        assert_eq!(whole, [Range { start: 0, end: 100 }]);
+    }
+
+    #[test]
+    fn multipart_parts_are_the_requested_bytes() {
+        let body: Vec<u8> = (0..=255).collect();
+        let ranges = [0..10, 100..150, 250..256];
+        let multipart = multipart_byteranges(&body, &ranges, "video/MP2T", "sep");
+        let multipart = String::from_utf8_lossy(&multipart).into_owned();
+        assert!(multipart.ends_with("--sep--\r\n"));
+
+        let parts: Vec<&str> = multipart
+            .strip_suffix("--sep--\r\n")
+            .unwrap()
+            .split("--sep\r\n")
+            .skip(1)
+            .collect();
+        assert_eq!(parts.len(), ranges.len());
+        for (part, r) in parts.iter().zip(&ranges) {
+            let (headers, bytes) = part.split_once("\r\n\r\n").unwrap();
+            assert_eq!(
+                headers,
+                format!(
+                    "content-type: video/MP2T\r\ncontent-range: {}",
+                    content_range(r, 256)
+                )
+            );
+            let expected = String::from_utf8_lossy(&body[r.start as usize..r.end as usize]);
+            assert_eq!(bytes.strip_suffix("\r\n").unwrap(), expected);
+        }
+    }
+}
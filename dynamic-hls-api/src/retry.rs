@@ -0,0 +1,75 @@
+// Retries of the frame file reads, `BASE_PATH` on NFS fails a read now and then
+use std::env;
+use std::io;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tracing::{info, warn};
+
+/// EIO, there is no `io::ErrorKind` of its own for it
+const EIO: i32 = 5;
+
+const DEFAULT_FILE_READ_ATTEMPTS: u32 = 3;
+const DEFAULT_FILE_READ_BACKOFF_MS: u64 = 50;
+
+lazy_static! {
+    /// Reads of a file before its error is returned, 1 turns the retries off
+    static ref FILE_READ_ATTEMPTS: u32 = {
+        match env::var("FILE_READ_ATTEMPTS").map(|v| v.parse::<u32>()) {
+            Ok(Ok(attempts)) if attempts >= 1 => {
+                info!("`FILE_READ_ATTEMPTS` env variable is set to {}", attempts);
+                attempts
+            }
+            Ok(_) => {
+                warn!("`FILE_READ_ATTEMPTS` env variable is ignored, expected a number >= 1");
+                DEFAULT_FILE_READ_ATTEMPTS
+            }
+            Err(_) => DEFAULT_FILE_READ_ATTEMPTS,
+        }
+    };
+    /// Wait before the first retry, it doubles with every retry after it
+    static ref FILE_READ_BACKOFF: Duration = {
+        match env::var("FILE_READ_BACKOFF_MS").map(|v| v.parse::<u64>()) {
+            Ok(Ok(ms)) => {
+                info!("`FILE_READ_BACKOFF_MS` env variable is set to {}", ms);
+                Duration::from_millis(ms)
+            }
+            Ok(Err(err)) => {
+                warn!("`FILE_READ_BACKOFF_MS` env variable is ignored: {}", err);
+                Duration::from_millis(DEFAULT_FILE_READ_BACKOFF_MS)
+            }
+            Err(_) => Duration::from_millis(DEFAULT_FILE_READ_BACKOFF_MS),
+        }
+    };
+}
+
+/// Errors the next read may not have, a missing file or no permission stays that way
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::StaleNetworkFileHandle
+    ) || err.raw_os_error() == Some(EIO)
+}
+
+/// Runs `read` of the file at `path` again after the transient errors, up to
+/// `FILE_READ_ATTEMPTS` times. It sleeps in between, so it is for the blocking threads only.
+pub fn read_with_retry<T>(path: &str, mut read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = *FILE_READ_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match read() {
+            Err(err) if attempt < *FILE_READ_ATTEMPTS && is_transient(&err) => {
+                warn!(
+                    "Reading {} failed, retrying in {:?} ({}/{}): {}",
+                    path, backoff, attempt, *FILE_READ_ATTEMPTS, err
+                );
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
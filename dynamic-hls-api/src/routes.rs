@@ -1,179 +1,131 @@
-use crate::errors;
-use crate::mpegts::TransportStream;
-use axum::extract::Path;
+// Routes of the API and what the handlers share: the logs under `BASE_PATH`, the frame
+// listing and the frame rate conversions
+use crate::{admin, auth, dash, errors, gif, nals, playlist, probe, segment, thumbnail};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path};
+use axum::http::request::Parts;
 use axum::http::{header, HeaderName};
-use axum::response::IntoResponse;
-use axum::{debug_handler, extract::Query, routing::get, Router};
-use bytes::Bytes;
-use lazy_static::lazy_static;
-use mp4::{AvcConfig, MediaConfig, Mp4Config, Mp4Sample, TrackConfig, TrackType};
-use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use axum::middleware::from_fn;
+use axum::{routing::get, Router};
+use h264_util::frames::{frame_index, list_frames};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 use std::{env, fs};
 use tracing::info;
 
+/// Frame files of the log ordered by their index, files that aren't named after a frame index
+/// are skipped. A log without frames is an error, so no playlist or manifest is without
+/// segments.
 pub fn get_frames(path_to_h264_frames: &str) -> Result<Vec<String>, errors::AppError> {
-    let paths = fs::read_dir(path_to_h264_frames)?;
-    let mut files: Vec<String> = paths
-        .map(|x| {
-            x.unwrap()
-                .path()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
-        })
-        .filter(|x| x.ends_with(".ts"))
+    let frames: Vec<String> = list_frames(path_to_h264_frames)?
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
         .collect();
-    files.sort_by(|a, b| {
-        let f0: i64 = a.replace(".ts", "").parse::<i64>().unwrap();
-        let f1: i64 = b.replace(".ts", "").parse::<i64>().unwrap();
-        f0.cmp(&f1)
-    });
-    Ok(files)
-}
-
-fn h264streams_concat(base_path: &str, streams: &[&String]) -> errors::Result<Vec<u8>> {
-    let mut data2 = Vec::<u8>::new();
-    for p in streams {
-        let path = format!("{}/{}", base_path, p);
-        let mut bytes = fs::read(path)?;
-        data2.append(&mut bytes);
+    if frames.is_empty() {
+        Err(errors::ErrorKind::NoMediaError(format!(
+            "No frames found in {path_to_h264_frames}"
+        )))?
     }
-    Ok(data2)
-}
-
-fn h264streams_to_mp4(base_path: &str, streams: &[&String]) -> errors::Result<Vec<u8>> {
-    let config = Mp4Config {
-        major_brand: str::parse("isom").unwrap(),
-        minor_version: 512,
-        compatible_brands: vec![
-            str::parse("isom").unwrap(),
-            str::parse("iso2").unwrap(),
-            str::parse("avc1").unwrap(),
-            str::parse("mp41").unwrap(),
-        ],
-        timescale: 1000,
-    };
-    let data: Cursor<Vec<u8>> = Cursor::new(Vec::<u8>::new());
-    let mut wrt = mp4::Mp4Writer::write_start(data, &config)?;
-    let avc_config = AvcConfig {
-        width: 2816,
-        height: 1856,
-        seq_param_set: vec![
-            0x27, 0x64, 0x00, 0x32, 0xac, 0x1b, 0x1a, 0x80, 0x2c, 0x00, 0xe9, 0x30, 0x16, 0xc8,
-            0x00, 0x00, 0x1f, 0x40, 0x00, 0x04, 0xe2, 0x07, 0x43, 0x00, 0x01, 0x7d, 0x78, 0x00,
-            0x00, 0x5f, 0x5e, 0x15, 0xde, 0x5c, 0x68, 0x60, 0x00, 0x2f, 0xaf, 0x00, 0x00, 0x0b,
-            0xeb, 0xc2, 0xbb, 0xcb, 0x85, 0x00,
-        ],
-        pic_param_set: vec![0x28, 0xee, 0x38, 0x30],
-    };
-    let track_cfg = TrackConfig {
-        track_type: TrackType::Video,
-        timescale: 1200000,
-        language: "und".to_string(),
-        media_conf: MediaConfig::AvcConfig(avc_config),
-    };
-    wrt.add_track(&track_cfg)?;
-
-    let mut start_time: u64 = 0;
-    let duration = 60000;
-    let track_id = 1;
-    for p in streams {
-        let path = format!("{}/{}", base_path, p);
-        let bytes = fs::read(path)?;
-        let sample = Mp4Sample {
-            start_time,
-            duration,
-            rendering_offset: 0,
-            is_sync: false,
-            bytes: Bytes::from(bytes),
-        };
-        wrt.write_sample(track_id, &sample)?;
-        start_time += duration as u64;
+    Ok(frames)
+}
+
+/// Frame rate of the camera sensors, it is a frame every 50 ms
+pub const DEFAULT_FPS: u32 = 20;
+
+pub fn default_fps() -> u32 {
+    DEFAULT_FPS
+}
+
+/// Length of the playlist segments, the last one is shorter
+pub const DEFAULT_SEGMENT_LENGTH_MS: usize = 5000;
+
+pub fn default_segment_length_ms() -> usize {
+    DEFAULT_SEGMENT_LENGTH_MS
+}
+
+pub fn check_segment_length(segment_length_ms: usize) -> errors::Result<()> {
+    if segment_length_ms == 0 {
+        Err(errors::ErrorKind::BadRequestError(
+            "`segment_length` must be positive".to_string(),
+        ))?
     }
-    wrt.write_end()?;
-    Ok(wrt.into_writer().into_inner())
-}
-
-fn h264streams_to_mpegts(
-    base_path: &str,
-    streams: &[&String],
-    duration: u32,
-) -> errors::Result<Vec<u8>> {
-    let mut ts: TransportStream = TransportStream::new();
-    let mut start_time: u64 = 0;
-    for p in streams {
-        let path = format!("{}/{}", base_path, p);
-        let bytes = std::fs::read(path)?;
-
-        ts.push_video(start_time, 0, false, bytes)?;
-        start_time += duration as u64;
+    Ok(())
+}
+
+/// Number of frames closest to `ms`, so that it inverts `frames_to_ms` up to `MAX_FPS`. The
+/// milliseconds of the queries have no upper bound, the ones too long to count in frames are out
+/// of range.
+pub fn ms_to_frames(ms: usize, fps: u32) -> errors::Result<usize> {
+    let frames = ms
+        .checked_mul(fps as usize)
+        .and_then(|n| n.checked_add(500))
+        .map(|n| n / 1000)
+        .ok_or_else(|| {
+            errors::ErrorKind::SegmentOutOfRangeError(format!(
+                "{ms} ms is out of range at {fps} fps"
+            ))
+        })?;
+    Ok(frames)
+}
+
+pub fn frames_to_ms(frames: usize, fps: u32) -> usize {
+    frames * 1000 / fps as usize
+}
+
+/// The playlists and manifests address the frames of the segments in milliseconds. Above it a
+/// frame lasts less than 2 ms, and the milliseconds of some frames round to the frame before.
+pub const MAX_FPS: u32 = 500;
+
+pub fn check_fps(fps: u32) -> errors::Result<()> {
+    if !(1..=MAX_FPS).contains(&fps) {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "`fps` must be between 1 and {MAX_FPS}, got {fps}"
+        )))?
     }
-    let wrt = ts.write_to(Cursor::new(Vec::<u8>::new()))?;
-    Ok(wrt.into_inner())
-}
-
-const MP2T_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "video/MP2T")];
-const MP4_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "video/mp4")];
-const PLAYLIST_CONTENT_TYPE: [(HeaderName, &str); 1] =
-    [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")];
-
-#[derive(Debug, Serialize, Deserialize, Default)]
-enum VideoType {
-    #[default]
-    MpegTs,
-    Mp4,
-    Raw,
-}
-
-#[derive(Debug, Deserialize)]
-struct Pagination {
-    #[serde(rename = "offset")]
-    offset_ms: usize,
-    #[serde(rename = "length")]
-    length_ms: usize,
-    #[serde(default)]
-    video_type: VideoType,
-}
-
-#[debug_handler]
-#[tracing::instrument(level = "INFO")]
-async fn get_segment(
-    Path(log_name): Path<String>,
-    pagination: Query<Pagination>,
-) -> errors::Result<impl IntoResponse> {
-    let path_to_h264_frames: String = get_h264_path(&log_name);
-    let files = get_frames(&path_to_h264_frames)?;
-
-    // Camera sensors have 20 FPS, so it is a frame every 50 ms
-    let offset_frames = pagination.offset_ms / 50;
-    let frames = pagination.length_ms / 50;
-
-    let frame_files: Vec<&String> = files.iter().skip(offset_frames).take(frames).collect();
-
-    let video_bytes = match pagination.video_type {
-        VideoType::MpegTs => {
-            h264streams_to_mpegts(&path_to_h264_frames, frame_files.as_slice(), 50)?
-        }
-        VideoType::Mp4 => h264streams_to_mp4(&path_to_h264_frames, frame_files.as_slice())?,
-        VideoType::Raw => h264streams_concat(&path_to_h264_frames, frame_files.as_slice())?,
+    Ok(())
+}
+
+/// Frame periods from the previous frame to this one, including the dropped frames
+pub fn elapsed_frames(prev_index: Option<i64>, index: Option<i64>) -> usize {
+    let dropped_frames = match (prev_index, index) {
+        (Some(prev), Some(cur)) if cur > prev + 1 => (cur - prev - 1) as usize,
+        _ => 0,
     };
-    let body = bytes::Bytes::from(video_bytes);
+    1 + dropped_frames
+}
 
-    match pagination.video_type {
-        VideoType::MpegTs => Ok((MP2T_CONTENT_TYPE, body)),
-        VideoType::Mp4 => Ok((MP4_CONTENT_TYPE, body)),
-        VideoType::Raw => Ok((MP2T_CONTENT_TYPE, body)),
+/// Frame periods from the first frame to every frame, including the dropped frames. The
+/// timestamps of all the containers are derived from them.
+pub fn frame_positions(files: &[impl AsRef<str>]) -> Vec<usize> {
+    let mut positions = Vec::with_capacity(files.len());
+    let mut prev: Option<(usize, Option<i64>)> = None;
+    for f in files {
+        let index = frame_index(f.as_ref());
+        let position = match prev {
+            None => 0,
+            Some((prev_position, prev_index)) => prev_position + elapsed_frames(prev_index, index),
+        };
+        positions.push(position);
+        prev = Some((position, index));
     }
+    positions
+}
+
+/// Set on the responses explicitly, `HEAD` answers have it without the body
+pub fn content_length(len: usize) -> [(HeaderName, String); 1] {
+    [(header::CONTENT_LENGTH, len.to_string())]
 }
 
 const DEFAULT_BASE_PATH: &str = "/data/testing/camera";
 
-lazy_static! {
-    static ref BASE_PATH: String = {
-        match env::var("BASE_PATH") {
+/// Directory of the logs shared by the routes. It starts as `BASE_PATH` and is replaced with
+/// `PUT /admin/base_path` when the data volume moves.
+#[derive(Debug, Clone)]
+pub struct BasePath(Arc<RwLock<PathBuf>>);
+
+impl BasePath {
+    pub fn from_env() -> BasePath {
+        let path = match env::var("BASE_PATH") {
             Ok(p) => {
                 info!("`BASE_PATH` env variable is set to {}", p);
                 p
@@ -185,58 +137,178 @@ lazy_static! {
                 );
                 DEFAULT_BASE_PATH.to_string()
             }
+        };
+        BasePath(Arc::new(RwLock::new(PathBuf::from(path))))
+    }
+
+    pub fn get(&self) -> PathBuf {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Directory of the frames of the log. A request resolves it once, so all of it reads the
+    /// same log when the path is replaced meanwhile.
+    pub fn log_path(&self, log_name: &str) -> String {
+        format!("{}/{}", self.get().display(), log_name)
+    }
+
+    /// Replaces the path with `path` if it is a directory
+    pub fn replace(&self, path: PathBuf) -> errors::Result<PathBuf> {
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => Err(errors::ErrorKind::BadRequestError(format!(
+                "{} is not a directory",
+                path.display()
+            )))?,
+            Err(err) => Err(errors::ErrorKind::BadRequestError(format!(
+                "{} can't be read: {err}",
+                path.display()
+            )))?,
+        }
+        Ok(std::mem::replace(&mut *self.0.write().unwrap(), path))
+    }
+}
+
+/// Logs are directories right under `BASE_PATH`, names that lead anywhere else are rejected
+pub fn validate_log_name(log_name: &str) -> errors::Result<()> {
+    if log_name.is_empty()
+        || log_name.contains(['/', '\\', '\0'])
+        || log_name == "."
+        || log_name == ".."
+    {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "Invalid log name `{log_name}`"
+        )))?
+    }
+    Ok(())
+}
+
+/// Log name as a path segment of the URLs in the playlists and manifests. All but the
+/// unreserved characters of RFC 3986 section 2.3 are percent-encoded, so a space, `#`, `?` or
+/// `%` in the name doesn't end or change the URL.
+pub fn encode_log_name(log_name: &str) -> String {
+    let mut encoded = String::with_capacity(log_name.len());
+    for byte in log_name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
         }
-    };
+    }
+    encoded
 }
 
-fn get_h264_path(log_name: &str) -> String {
-    format!("{}/{}", *BASE_PATH, log_name)
+/// `:log_name` of the path, checked with `validate_log_name`
+#[derive(Debug)]
+pub struct LogName(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LogName {
+    type Rejection = errors::AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> errors::Result<Self> {
+        let Path(log_name) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| errors::ErrorKind::BadRequestError(err.body_text()))?;
+        validate_log_name(&log_name)?;
+        Ok(LogName(log_name))
+    }
 }
 
-const PLAYLIST_HEADER: &str = r#"#EXTM3U
-#EXT-X-VERSION:3
-#EXT-X-TARGETDURATION:10
-#EXT-X-MEDIA-SEQUENCE:0"#;
+pub async fn create_route(base_path: BasePath) -> Router {
+    Router::new()
+        .route("/v1/segment/:log_name", get(segment::get_segment))
+        .route("/v1/playlist/:log_name", get(playlist::get_playlist))
+        .route("/v1/concat/playlist", get(playlist::get_concat_playlist))
+        .route("/v1/master/:log_name", get(playlist::get_master_playlist))
+        .route("/v1/iframes/:log_name", get(playlist::get_iframe_playlist))
+        .route("/v1/manifest.mpd/:log_name", get(dash::get_dash_manifest))
+        .route("/v1/init/:log_name", get(segment::get_init))
+        .route("/v1/probe/:log_name", get(probe::get_probe))
+        .route("/v1/params/:log_name", get(probe::get_params))
+        .route("/v1/gif/:log_name", get(gif::get_gif))
+        .route("/v1/thumbnail/:log_name", get(thumbnail::get_thumbnail))
+        .route("/v1/nals/:log_name", get(nals::get_nals))
+        .route("/v1/logs", get(probe::get_logs))
+        // Only the matched routes, unknown paths are still `404 Not Found`
+        .route_layer(from_fn(auth::require_token))
+        .with_state(base_path)
+}
 
-#[debug_handler]
-#[tracing::instrument(level = "INFO")]
-async fn get_playlist(Path(log_name): Path<String>) -> errors::Result<impl IntoResponse> {
-    let path_to_h264_frames: String = get_h264_path(&log_name);
-    let files = get_frames(&path_to_h264_frames)?;
+/// Probes of the orchestration, they don't have the token
+pub fn create_probe_route(base_path: BasePath) -> Router {
+    Router::new()
+        .route("/healthz", get(admin::get_healthz))
+        .route("/readyz", get(admin::get_readyz))
+        .with_state(base_path)
+}
 
-    let mut playlist = PLAYLIST_HEADER.to_string();
+/// Routes of the operators, only there with `ADMIN_TOKEN`
+pub fn create_admin_route(base_path: BasePath) -> Router {
+    Router::new()
+        .route(
+            "/admin/base_path",
+            get(admin::get_base_path).put(admin::put_base_path),
+        )
+        .route_layer(from_fn(auth::require_admin_token))
+        .with_state(base_path)
+}
 
-    let mut offset_ms = 0;
-    let mut length_ms = 0;
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
 
-    for _f in files {
-        if length_ms == 5000 {
-            let duration_secs = length_ms / 1000;
-            playlist += format!("#EXTINF:{duration_secs}.0,\n").as_str();
-            playlist += format!("http://127.0.0.1:18080/v1/segment/{log_name}?offset={offset_ms}&length={length_ms}\n").as_str();
-            offset_ms += length_ms;
-            length_ms = 0
+    /// Log named `name` with the frames `indices`, in a base path of its own. Every fifth frame
+    /// is a keyframe, the frames are `len` bytes of Annex B.
+    pub fn write_log(name: &str, indices: impl IntoIterator<Item = usize>, len: usize) -> BasePath {
+        let dir = env::temp_dir().join(format!("dynamic-hls-api-{}-{name}", std::process::id()));
+        fs::create_dir_all(dir.join(name)).unwrap();
+        for idx in indices {
+            let nal_header = if idx % 5 == 0 { 0x65 } else { 0x41 };
+            let mut frame = vec![0, 0, 0, 1, nal_header];
+            frame.resize(len, 0xAB);
+            fs::write(dir.join(name).join(format!("{idx}.ts")), frame).unwrap();
         }
-
-        length_ms += 50;
+        BasePath(Arc::new(RwLock::new(dir)))
     }
 
-    if length_ms != 0 {
-        let duration_secs = length_ms / 1000;
-        playlist += format!("#EXTINF:{duration_secs}.0,\n").as_str();
-        playlist += format!(
-            "http://127.0.0.1:18080/v1/segment/{log_name}?offset={offset_ms}&length={length_ms}\n"
-        )
-        .as_str();
+    #[test]
+    fn ms_to_frames_inverts_frames_to_ms_up_to_max_fps() {
+        for fps in 1..=MAX_FPS {
+            for frames in 0..1000 {
+                assert_eq!(
+                    ms_to_frames(frames_to_ms(frames, fps), fps).unwrap(),
+                    frames,
+                    "{frames} frames at {fps} fps"
+                );
+            }
+        }
+        assert!(ms_to_frames(usize::MAX / 20 + 1, 20).is_err());
+        assert!(check_fps(MAX_FPS).is_ok());
+        assert!(check_fps(MAX_FPS + 1).is_err());
     }
-    playlist += "#EXT-X-ENDLIST";
 
-    Ok((PLAYLIST_CONTENT_TYPE, playlist))
-}
+    #[test]
+    fn files_not_named_after_a_frame_index_are_not_frames() {
+        let base_path = write_log("junk", 0..3, 10);
+        let dir = base_path.log_path("junk");
+        for junk in ["init.ts", "1_backup.ts", "playlist.m3u8", "2.aac"] {
+            fs::write(format!("{dir}/{junk}"), []).unwrap();
+        }
+        assert_eq!(get_frames(&dir).unwrap(), ["0.ts", "1.ts", "2.ts"]);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
 
-pub async fn create_route() -> Router {
-    let get_layer_route = Router::new()
-        .route("/v1/segment/:log_name", get(get_segment))
-        .route("/v1/playlist/:log_name", get(get_playlist));
-    Router::new().merge(get_layer_route)
+    #[test]
+    fn logs_of_junk_files_only_have_no_media() {
+        let base_path = write_log("junk-only", [], 10);
+        let dir = base_path.log_path("junk-only");
+        fs::write(format!("{dir}/init.ts"), []).unwrap();
+        let response = get_frames(&dir).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[errors::ERROR_CODE_HEADER], "40402");
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
 }
@@ -0,0 +1,950 @@
+// `/v1/segment` and `/v1/init`, the muxed segments with their caching, validators and ranges
+use crate::errors;
+use crate::lookups::get_keyframes;
+use crate::metrics;
+use crate::mux::{
+    check_mp4_codec, empty_mpegts, fmp4_init_segment, h264streams_concat, h264streams_to_fmp4,
+    h264streams_to_mp4, h264streams_to_mpegts, h264streams_to_mpegts_chunks, muxed_mpegts_size,
+    TS_FAULT_INJECTION, TS_INSERT_AUD, TS_PROGRAM_NUMBER, TS_SEGMENT_PAD_SIZE,
+    TS_SERVICE_DESCRIPTION, TS_STREAM_IDS, TS_TRANSPORT_STREAM_ID, VIDEO_CODEC,
+};
+use crate::range::{self, RangeRequest};
+use crate::routes::{
+    check_fps, content_length, default_fps, elapsed_frames, frame_positions, get_frames,
+    ms_to_frames, BasePath, LogName,
+};
+use crate::segment_cache::{CacheStatus, SegmentCache};
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderName, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{debug_handler, extract::Query};
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use h264_util::frames::frame_index;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Whether frames were dropped right before the `idx`-th frame file, the playlist starts a
+/// discontinuity there
+fn follows_gap(files: &[String], idx: usize) -> bool {
+    idx > 0
+        && idx < files.len()
+        && elapsed_frames(frame_index(&files[idx - 1]), frame_index(&files[idx])) > 1
+}
+
+const MP2T_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "video/MP2T")];
+const MP4_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "video/mp4")];
+/// Annex B byte stream, RFC 6184 section 8.1
+const H264_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "video/H264")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+pub enum VideoType {
+    #[default]
+    MpegTs,
+    Mp4,
+    /// Media segment of fragmented MP4 (CMAF), played with the init segment of `/v1/init`
+    FragmentedMp4,
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+enum Seek {
+    /// The segment starts at the frame of `offset`, the frames before the next keyframe may not
+    /// decode
+    #[default]
+    Exact,
+    /// The segment starts at the keyframe before `offset` and still ends at `offset` + `length`,
+    /// `x-preroll-frames` tells how many frames it starts earlier
+    Keyframe,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pagination {
+    #[serde(rename = "offset")]
+    offset_ms: Option<usize>,
+    #[serde(rename = "length")]
+    length_ms: Option<usize>,
+    /// Selects the n-th GOP instead of `offset` and `length`. A GOP starts at an IDR frame and
+    /// ends before the next one, frames before the first IDR frame are not in any GOP.
+    gop: Option<usize>,
+    /// Where the frames of `offset` start, `gop` and `start_frame` are not moved. The playlist
+    /// segments are adjacent, so they are `Exact`.
+    #[serde(default)]
+    seek: Seek,
+    /// Selects the frames from `start_frame` up to `end_frame`, not included, by their position
+    /// in the recording. They take precedence over `offset`, `length` and `gop`, so there is no
+    /// rounding of milliseconds to frames.
+    start_frame: Option<usize>,
+    end_frame: Option<usize>,
+    #[serde(default)]
+    video_type: VideoType,
+    /// Answer with just the headers of the segment and `204 No Content`, the size is in
+    /// `x-segment-size`. It still muxes the segment to learn its size.
+    #[serde(default)]
+    probe: bool,
+    /// Start the timestamps at zero instead of at `offset`, for segments downloaded as
+    /// standalone clips. HLS needs the timestamps to continue across segments, so it is off by
+    /// default. MP4 segments always start at zero, fragmented MP4 ones follow this too.
+    #[serde(default)]
+    rebase: bool,
+    /// Frame rate of the camera, `offset`, `length` and the timestamps are converted to frames
+    /// with it. The playlist must be requested with the same `fps`, its segment URLs pass it on.
+    #[serde(default = "default_fps")]
+    fps: u32,
+    /// Frame periods of the logs before this one in a concat playlist, with their dropped frames.
+    /// The timestamps go on from them, and the first segment of the log starts a discontinuity.
+    #[serde(default)]
+    log_start_frame: usize,
+}
+
+impl Pagination {
+    /// Whether `segment_bounds` takes the frames from `offset` and `length`, the frames given
+    /// explicitly take precedence over them
+    fn by_time(&self) -> bool {
+        self.start_frame.is_none()
+            && self.end_frame.is_none()
+            && self.gop.is_none()
+            && self.offset_ms.is_some()
+    }
+}
+
+/// Frames of the requested segment as `(offset_frames, frames)`
+fn segment_bounds(
+    log_name: &str,
+    path_to_h264_frames: &str,
+    pagination: &Pagination,
+) -> errors::Result<(usize, usize)> {
+    match (pagination.start_frame, pagination.end_frame) {
+        (Some(start_frame), Some(end_frame)) if end_frame > start_frame => {
+            return Ok((start_frame, end_frame - start_frame))
+        }
+        (Some(start_frame), Some(end_frame)) => Err(errors::ErrorKind::BadRequestError(format!(
+            "`end_frame` {end_frame} must be after `start_frame` {start_frame}"
+        )))?,
+        (Some(_), None) | (None, Some(_)) => Err(errors::ErrorKind::BadRequestError(
+            "Expected both `start_frame` and `end_frame`".to_string(),
+        ))?,
+        (None, None) => {}
+    }
+    match (pagination.gop, pagination.offset_ms, pagination.length_ms) {
+        (None, Some(offset_ms), Some(length_ms)) => {
+            let frames = ms_to_frames(length_ms, pagination.fps)?;
+            if frames == 0 {
+                Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+                    "`length` {length_ms} ms is less than a frame at {} fps",
+                    pagination.fps
+                )))?
+            }
+            Ok((ms_to_frames(offset_ms, pagination.fps)?, frames))
+        }
+        (Some(gop), None, None) => gop_bounds(log_name, path_to_h264_frames, gop),
+        _ => Err(errors::ErrorKind::BadRequestError(
+            "Expected either `offset` and `length`, `gop` or `start_frame` and `end_frame`"
+                .to_string(),
+        ))?,
+    }
+}
+
+fn gop_bounds(
+    log_name: &str,
+    path_to_h264_frames: &str,
+    gop: usize,
+) -> errors::Result<(usize, usize)> {
+    let keyframes = get_keyframes(path_to_h264_frames)?;
+    let first_frame = *keyframes.get(gop).ok_or_else(|| {
+        errors::ErrorKind::NotFoundError(format!(
+            "{log_name} has {} GOPs, no GOP {gop}",
+            keyframes.len()
+        ))
+    })?;
+    let end_frame = match keyframes.get(gop + 1) {
+        Some(next_keyframe) => *next_keyframe,
+        // The last GOP lasts until the end of the recording
+        None => get_frames(path_to_h264_frames)?.len(),
+    };
+    Ok((first_frame, end_frame - first_frame))
+}
+
+/// Moves the start of the frames back to the keyframe before it, the frames up to the first
+/// keyframe are dropped when there is none. Returns the bounds and the frames moved back.
+fn snap_to_keyframe(
+    log_name: &str,
+    path_to_h264_frames: &str,
+    offset_frames: usize,
+    frames: usize,
+) -> errors::Result<(usize, usize, usize)> {
+    let keyframes = get_keyframes(path_to_h264_frames)?;
+    let end_frame = offset_frames + frames;
+    match keyframes.iter().rev().find(|k| **k <= offset_frames) {
+        Some(keyframe) => Ok((*keyframe, end_frame - keyframe, offset_frames - keyframe)),
+        None => match keyframes.first() {
+            Some(keyframe) if *keyframe < end_frame => Ok((*keyframe, end_frame - keyframe, 0)),
+            _ => Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+                "{log_name} has no keyframe before frame {end_frame}"
+            )))?,
+        },
+    }
+}
+
+/// Identifies a muxed segment in `SEGMENT_CACHE`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SegmentKey {
+    pub log_name: String,
+    /// The log in the `BASE_PATH` of the request, the segments of a replaced one are not served
+    pub path_to_h264_frames: String,
+    /// Position of the first frame in the recording
+    pub offset_frames: usize,
+    pub frames: usize,
+    pub video_type: VideoType,
+    pub rebase: bool,
+    pub fps: u32,
+    /// See `Pagination::log_start_frame`
+    pub log_start_frame: usize,
+}
+
+impl SegmentKey {
+    /// Timestamp of the first frame of the segment in frame periods, including the frames
+    /// dropped before it. `rebase` segments start at zero, MPEG-TS ones are muxed with a zero
+    /// base for that.
+    fn first_frame(&self, files: &[String]) -> u64 {
+        let position = frame_positions(files)
+            .get(self.offset_frames)
+            .copied()
+            .unwrap_or_default();
+        (self.log_start_frame + position) as u64
+    }
+
+    /// Whether the segment follows dropped frames, or starts a log that follows another one in
+    /// a concat playlist
+    fn discontinuity(&self, files: &[String]) -> bool {
+        follows_gap(files, self.offset_frames)
+            || (self.log_start_frame > 0 && self.offset_frames == 0)
+    }
+}
+
+/// Position of the first frame of the segment in the recording and the frame files of it
+fn select_frames<'a>(files: &'a [String], key: &SegmentKey) -> (usize, Vec<&'a String>) {
+    let frame_files = files
+        .iter()
+        .skip(key.offset_frames)
+        .take(key.frames)
+        .collect();
+    (key.offset_frames, frame_files)
+}
+
+/// Rejects segments that start after the last frame instead of muxing them empty. The end may go
+/// past the last frame, the segment is cut short then.
+fn check_segment_range(key: &SegmentKey) -> errors::Result<()> {
+    // A missing log is left to the muxing, it may fall back to an empty segment
+    let Ok(files) = get_frames(&key.path_to_h264_frames) else {
+        return Ok(());
+    };
+    if key.offset_frames >= files.len() {
+        Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+            "Segment starts at frame {}, {} has {} frames",
+            key.offset_frames,
+            key.log_name,
+            files.len()
+        )))?
+    }
+    Ok(())
+}
+
+/// Listing the frames is part of the muxing time, it grows with the log
+fn mux_segment(key: &SegmentKey) -> errors::Result<Vec<u8>> {
+    let started = Instant::now();
+    let path_to_h264_frames = key.path_to_h264_frames.clone();
+    let files = get_frames(&path_to_h264_frames)?;
+    let (_, frame_files) = select_frames(&files, key);
+
+    let segment = match key.video_type {
+        VideoType::MpegTs => h264streams_to_mpegts(
+            &path_to_h264_frames,
+            frame_files.as_slice(),
+            key.fps,
+            key.first_frame(&files),
+            key.discontinuity(&files),
+            key.rebase,
+        ),
+        VideoType::Mp4 => {
+            check_mp4_codec()?;
+            h264streams_to_mp4(&path_to_h264_frames, frame_files.as_slice(), key.fps)
+        }
+        VideoType::FragmentedMp4 => {
+            check_mp4_codec()?;
+            let first_frame = match key.rebase {
+                true => 0,
+                false => key.first_frame(&files),
+            };
+            h264streams_to_fmp4(&path_to_h264_frames, &frame_files, key.fps, first_frame)
+        }
+        VideoType::Raw => h264streams_concat(&path_to_h264_frames, frame_files.as_slice()),
+    }?;
+    let video_type = format!("{:?}", key.video_type);
+    metrics::record_segment(
+        &video_type,
+        started.elapsed(),
+        segment.len(),
+        frame_files.len(),
+    );
+    Ok(segment)
+}
+
+/// Size of the segment for `HEAD`, if it is known without muxing it. That is MPEG-TS segments
+/// without the fault injection, which drops packets. Just the frames of the segment are read, a
+/// log that is still recording isn't read whole on every request.
+fn segment_size(key: &SegmentKey) -> errors::Result<Option<usize>> {
+    if !matches!(key.video_type, VideoType::MpegTs) || TS_FAULT_INJECTION.is_some() {
+        return Ok(None);
+    }
+    let files = get_frames(&key.path_to_h264_frames)?;
+    let (_, frame_files) = select_frames(&files, key);
+    let size = muxed_mpegts_size(
+        &key.path_to_h264_frames,
+        &frame_files,
+        key.fps,
+        key.first_frame(&files),
+    )?;
+    Ok(Some(size))
+}
+
+/// Frames are sent as soon as they are muxed, a connection holds just a few of them
+const STREAMED_FRAMES_BUFFER: usize = 8;
+
+/// Streams the MPEG-TS segment while it is muxed and caches it once it is complete. PAT and PMT
+/// come after the frames are read for the PMT and the composition offsets, errors until then are
+/// returned so the segment can fall back. Later ones abort the response, with
+/// `SEGMENT_ERROR_FALLBACK` they end it early at a packet boundary instead.
+async fn stream_mpegts_segment(key: SegmentKey) -> errors::Result<Body> {
+    let started = Instant::now();
+    let (tx, mut rx) = mpsc::channel::<errors::Result<Bytes>>(STREAMED_FRAMES_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let path_to_h264_frames = key.path_to_h264_frames.clone();
+        let files = match get_frames(&path_to_h264_frames) {
+            Ok(files) => files,
+            Err(err) => {
+                let _ = tx.blocking_send(Err(err));
+                return;
+            }
+        };
+        let (_, frame_files) = select_frames(&files, &key);
+        let first_frame = key.first_frame(&files);
+        let discontinuity = key.discontinuity(&files);
+        let mut segment = Vec::new();
+        // Waiting for the client to take the chunks isn't muxing
+        let mut sending = Duration::ZERO;
+        let result = h264streams_to_mpegts_chunks(
+            &path_to_h264_frames,
+            &frame_files,
+            key.fps,
+            first_frame,
+            discontinuity,
+            key.rebase,
+            |chunk| {
+                segment.extend_from_slice(&chunk);
+                let send_started = Instant::now();
+                let sent = tx.blocking_send(Ok(Bytes::from(chunk))).is_ok();
+                sending += send_started.elapsed();
+                sent
+            },
+        );
+        match result {
+            Ok(true) => {
+                let video_type = format!("{:?}", key.video_type);
+                let elapsed = started.elapsed().saturating_sub(sending);
+                metrics::record_segment(&video_type, elapsed, segment.len(), frame_files.len());
+                SEGMENT_CACHE.insert(key, Bytes::from(segment))
+            }
+            Ok(false) => debug!("Client went away while streaming {key:?}"),
+            Err(err) if *SEGMENT_ERROR_FALLBACK && !segment.is_empty() => {
+                warn!("Failed to stream {key:?}, ending it early: {err}");
+            }
+            Err(err) => {
+                warn!("Failed to stream {key:?}: {err}");
+                // Ends the body with an error, so the client doesn't take it for a short segment
+                let _ = tx.blocking_send(Err(err));
+            }
+        }
+    });
+    let header = match rx.recv().await {
+        Some(header) => header?,
+        None => Bytes::new(),
+    };
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok(Body::from_stream(
+        stream::once(async { Ok(header) }).chain(chunks),
+    ))
+}
+
+/// Strong validator of the segment from everything its bytes depend on: the key, the name, size
+/// and modification time of its frame files and of the one before, which tells whether it
+/// follows a gap, the muxer settings and the build. Checking it takes a `stat` of the frames
+/// rather than muxing them.
+fn segment_etag(key: &SegmentKey) -> errors::Result<String> {
+    let path_to_h264_frames = key.path_to_h264_frames.clone();
+    let files = get_frames(&path_to_h264_frames)?;
+    let (offset_frames, frame_files) = select_frames(&files, key);
+    let first = offset_frames.saturating_sub(1);
+    let last = offset_frames + frame_files.len();
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    crate::APP_VERSION.hash(&mut hasher);
+    format!(
+        "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+        *VIDEO_CODEC,
+        *TS_INSERT_AUD,
+        *TS_TRANSPORT_STREAM_ID,
+        *TS_PROGRAM_NUMBER,
+        *TS_STREAM_IDS,
+        *TS_SERVICE_DESCRIPTION,
+        *TS_SEGMENT_PAD_SIZE,
+        *TS_FAULT_INJECTION
+    )
+    .hash(&mut hasher);
+    for f in &files[first..last] {
+        let metadata = fs::metadata(format!("{}/{}", path_to_h264_frames, f))?;
+        (f, metadata.len(), metadata.modified()?).hash(&mut hasher);
+    }
+    Ok(format!("\"{:016x}\"", hasher.finish()))
+}
+
+/// Whether `If-None-Match` lists the ETag, compared weakly as RFC 9110 section 13.1.2 says
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+pub async fn cached_segment(key: SegmentKey) -> errors::Result<(Bytes, CacheStatus)> {
+    let mux_key = key.clone();
+    SEGMENT_CACHE
+        .get_or_mux(key, move || mux_segment(&mux_key))
+        .await
+}
+
+const ACCEPT_RANGES: [(HeaderName, &str); 1] = [(header::ACCEPT_RANGES, "bytes")];
+
+/// Answers with the part of the segment the `Range` header asks for, or with the whole segment
+/// when there is no header or it can't be honored
+fn range_response(
+    range: Option<&str>,
+    content_type: [(HeaderName, &str); 1],
+    video_bytes: Bytes,
+) -> Response {
+    let len = video_bytes.len() as u64;
+    let ranges = match range.and_then(range::parse_range) {
+        Some(RangeRequest::Bytes(specs)) => match range::ranges_to_send(&specs, len) {
+            Some(ranges) => ranges,
+            None => {
+                debug!("Ignoring {} ranges, sending the whole segment", specs.len());
+                let content_length = content_length(video_bytes.len());
+                return (content_type, ACCEPT_RANGES, content_length, video_bytes).into_response();
+            }
+        },
+        Some(RangeRequest::UnsupportedUnit(unit)) if *REJECT_UNSUPPORTED_RANGE_UNITS => {
+            debug!("Rejecting range of unsupported unit {unit}");
+            Vec::new()
+        }
+        // Unknown units and malformed headers are ignored, RFC 9110 section 14.2
+        Some(RangeRequest::UnsupportedUnit(_)) | None => {
+            let content_length = content_length(video_bytes.len());
+            return (content_type, ACCEPT_RANGES, content_length, video_bytes).into_response();
+        }
+    };
+    match ranges.as_slice() {
+        [] => {
+            let content_range = [(header::CONTENT_RANGE, range::unsatisfied_content_range(len))];
+            (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                ACCEPT_RANGES,
+                content_range,
+            )
+                .into_response()
+        }
+        [r] => {
+            let content_range = [(header::CONTENT_RANGE, range::content_range(r, len))];
+            let part = video_bytes.slice(r.start as usize..r.end as usize);
+            (
+                StatusCode::PARTIAL_CONTENT,
+                content_type,
+                ACCEPT_RANGES,
+                content_range,
+                content_length(part.len()),
+                part,
+            )
+                .into_response()
+        }
+        ranges => {
+            let boundary = range::multipart_boundary();
+            let [(_, part_content_type)] = content_type;
+            let body =
+                range::multipart_byteranges(&video_bytes, ranges, part_content_type, &boundary);
+            let multipart_content_type = [(
+                header::CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={boundary}"),
+            )];
+            (
+                StatusCode::PARTIAL_CONTENT,
+                multipart_content_type,
+                ACCEPT_RANGES,
+                content_length(body.len()),
+                body,
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Frames of a `seek=Keyframe` segment before its `offset`, players that trim the pre-roll
+/// drop them
+pub const PREROLL_FRAMES_HEADER: HeaderName = HeaderName::from_static("x-preroll-frames");
+
+/// Size of the segment in the answers of `probe`, `204 No Content` can't have `Content-Length`,
+/// RFC 9110 section 8.6
+pub const SEGMENT_SIZE_HEADER: HeaderName = HeaderName::from_static("x-segment-size");
+
+/// Players skip over an empty segment, but may abort the whole session on an error
+fn fallback_segment(
+    log_name: &str,
+    video_type: VideoType,
+    err: errors::AppError,
+) -> errors::Result<Bytes> {
+    if *SEGMENT_ERROR_FALLBACK && matches!(video_type, VideoType::MpegTs) {
+        warn!("Failed to generate segment for {log_name}, falling back to an empty one: {err}");
+        return Ok(Bytes::from(empty_mpegts()?));
+    }
+    Err(err)
+}
+
+/// Segment selected by the `Pagination` of a request
+struct LocatedSegment {
+    key: SegmentKey,
+    /// Frames it starts before `offset` with `seek=Keyframe`
+    preroll_frames: Option<usize>,
+    etag: Option<String>,
+}
+
+/// Lists the log and may read all its frames for the keyframes, so it is for the blocking
+/// threads
+fn locate_segment(
+    log_name: &str,
+    path_to_h264_frames: String,
+    pagination: &Pagination,
+) -> errors::Result<LocatedSegment> {
+    let (offset_frames, frames) = segment_bounds(log_name, &path_to_h264_frames, pagination)?;
+    let mut key = SegmentKey {
+        log_name: log_name.to_string(),
+        path_to_h264_frames,
+        offset_frames,
+        frames,
+        video_type: pagination.video_type,
+        rebase: pagination.rebase,
+        fps: pagination.fps,
+        log_start_frame: pagination.log_start_frame,
+    };
+    check_segment_range(&key)?;
+    let mut preroll_frames = None;
+    if pagination.seek == Seek::Keyframe && pagination.by_time() {
+        let snapped = snap_to_keyframe(log_name, &key.path_to_h264_frames, offset_frames, frames)?;
+        (key.offset_frames, key.frames) = (snapped.0, snapped.1);
+        preroll_frames = Some(snapped.2);
+    }
+
+    // Like the range check, a log that can't be listed is left to the muxing, it may fall back
+    // to an empty segment
+    let etag = match segment_etag(&key) {
+        Ok(etag) => Some(etag),
+        Err(err) => {
+            debug!("Segment of {log_name} has no ETag: {err}");
+            None
+        }
+    };
+    Ok(LocatedSegment {
+        key,
+        preroll_frames,
+        etag,
+    })
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path, headers))]
+pub async fn get_segment(
+    State(base_path): State<BasePath>,
+    method: Method,
+    LogName(log_name): LogName,
+    pagination: Query<Pagination>,
+    headers: HeaderMap,
+) -> errors::Result<impl IntoResponse> {
+    check_fps(pagination.fps)?;
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    let (log, query) = (log_name.clone(), pagination.0.clone());
+    let LocatedSegment {
+        key,
+        preroll_frames,
+        etag,
+    } = tokio::task::spawn_blocking(move || locate_segment(&log, path_to_h264_frames, &query))
+        .await??;
+    let preroll = preroll_frames.map(|frames| [(PREROLL_FRAMES_HEADER, frames.to_string())]);
+    let validators = etag.as_ref().map(|etag| {
+        [
+            (header::ETAG, etag.clone()),
+            (
+                header::CACHE_CONTROL,
+                format!("max-age={}", *SEGMENT_MAX_AGE_SECS),
+            ),
+        ]
+    });
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if let (Some(etag), Some(if_none_match)) = (&etag, if_none_match) {
+        if etag_matches(if_none_match, etag) {
+            return Ok((StatusCode::NOT_MODIFIED, preroll, validators, ()).into_response());
+        }
+    }
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+
+    // `HEAD` of an MPEG-TS segment that isn't cached takes the size from the frame sizes. A
+    // segment that fails to mux is still answered with it, the fallback is for the `GET`.
+    if method == Method::HEAD
+        && range.is_none()
+        && !pagination.probe
+        && !SEGMENT_CACHE.contains(&key)
+    {
+        let size_key = key.clone();
+        match tokio::task::spawn_blocking(move || segment_size(&size_key)).await? {
+            Ok(Some(len)) => {
+                let content_length = content_length(len);
+                let head = (MP2T_CONTENT_TYPE, ACCEPT_RANGES, content_length);
+                return Ok((preroll, validators, head).into_response());
+            }
+            Ok(None) => {}
+            Err(err) => debug!("Muxing the segment of {log_name} for its size: {err}"),
+        }
+    }
+
+    // Ranges, probes and the other `HEAD` need the size, MP4 is written at once anyway. `HEAD`
+    // muxes these segments into the cache, so the `GET` after it doesn't.
+    let streamable =
+        matches!(key.video_type, VideoType::MpegTs) && !pagination.probe && method != Method::HEAD;
+    if streamable && range.is_none() && !SEGMENT_CACHE.contains(&key) {
+        // The size is known before the muxing like for `HEAD`, unless the muxing may end the
+        // segment early at an error
+        let size = match *SEGMENT_ERROR_FALLBACK {
+            true => None,
+            false => {
+                let size_key = key.clone();
+                match tokio::task::spawn_blocking(move || segment_size(&size_key)).await? {
+                    Ok(size) => size,
+                    Err(err) => {
+                        debug!("Sizing the segment of {log_name}: {err}");
+                        None
+                    }
+                }
+            }
+        };
+        let size = size.map(content_length);
+        // Fallback segments don't get the validators, they stand in for a failure
+        let response = match stream_mpegts_segment(key).await {
+            Ok(body) => {
+                let head = (MP2T_CONTENT_TYPE, ACCEPT_RANGES, size);
+                (head, preroll, validators, body).into_response()
+            }
+            Err(err) => {
+                let body = fallback_segment(&log_name, pagination.video_type, err)?;
+                let content_length = content_length(body.len());
+                (MP2T_CONTENT_TYPE, ACCEPT_RANGES, content_length, body).into_response()
+            }
+        };
+        return Ok(response);
+    }
+
+    let (video_bytes, validators) = match cached_segment(key).await {
+        Ok((video_bytes, status)) => {
+            debug!("Segment cache {status:?}");
+            (video_bytes, validators)
+        }
+        Err(err) => (
+            fallback_segment(&log_name, pagination.video_type, err)?,
+            None,
+        ),
+    };
+    let content_type = match pagination.video_type {
+        VideoType::MpegTs => MP2T_CONTENT_TYPE,
+        VideoType::Mp4 | VideoType::FragmentedMp4 => MP4_CONTENT_TYPE,
+        VideoType::Raw => H264_CONTENT_TYPE,
+    };
+
+    if pagination.probe {
+        let segment_size = [(SEGMENT_SIZE_HEADER, video_bytes.len().to_string())];
+        // axum sets `Content-Length: 0` for a body of a known size, an empty stream has none
+        let no_content = Body::from_stream(stream::empty::<std::io::Result<Bytes>>());
+        let probe = (
+            StatusCode::NO_CONTENT,
+            content_type,
+            ACCEPT_RANGES,
+            segment_size,
+            preroll,
+            validators,
+            no_content,
+        );
+        return Ok(probe.into_response());
+    }
+
+    let mut response = range_response(range, content_type, video_bytes);
+    if method == Method::HEAD {
+        // Keeps `Content-Length` of the body that would be sent
+        *response.body_mut() = Body::empty();
+    }
+    Ok((preroll, validators, response).into_response())
+}
+
+const DEFAULT_SEGMENT_CACHE_CAPACITY: usize = 32;
+
+/// The frames of a recording don't change, the last segments of one still being recorded do
+const DEFAULT_SEGMENT_MAX_AGE_SECS: u64 = 3600;
+
+lazy_static! {
+    /// Recently muxed segments, hls.js requests the same segments again on seek
+    static ref SEGMENT_CACHE: SegmentCache<SegmentKey> = {
+        let capacity = match env::var("SEGMENT_CACHE_CAPACITY").map(|v| v.parse::<usize>()) {
+            Ok(Ok(capacity)) if capacity > 0 => {
+                info!("`SEGMENT_CACHE_CAPACITY` env variable is set to {}", capacity);
+                capacity
+            }
+            Ok(_) => {
+                warn!(
+                    "`SEGMENT_CACHE_CAPACITY` env variable is ignored, use {}",
+                    DEFAULT_SEGMENT_CACHE_CAPACITY
+                );
+                DEFAULT_SEGMENT_CACHE_CAPACITY
+            }
+            Err(_) => DEFAULT_SEGMENT_CACHE_CAPACITY,
+        };
+        SegmentCache::new(capacity)
+    };
+    /// When set, a failed MPEG-TS segment generation is logged and answered with an empty
+    /// segment instead of an error. It masks errors, so it is meant for live playback only.
+    static ref SEGMENT_ERROR_FALLBACK: bool = {
+        match env::var("SEGMENT_ERROR_FALLBACK") {
+            Ok(v) => {
+                let enabled = v == "1" || v.eq_ignore_ascii_case("true");
+                info!("`SEGMENT_ERROR_FALLBACK` env variable is set to {}", enabled);
+                enabled
+            }
+            Err(_) => false,
+        }
+    };
+    /// Answer ranges of other units than bytes with `416 Range Not Satisfiable`, by default the
+    /// `Range` header is ignored for them and the whole segment is sent
+    static ref REJECT_UNSUPPORTED_RANGE_UNITS: bool = {
+        match env::var("REJECT_UNSUPPORTED_RANGE_UNITS") {
+            Ok(v) => {
+                let enabled = v == "1" || v.eq_ignore_ascii_case("true");
+                info!("`REJECT_UNSUPPORTED_RANGE_UNITS` env variable is set to {}", enabled);
+                enabled
+            }
+            Err(_) => false,
+        }
+    };
+    /// `max-age` of the segments, they are revalidated with their `ETag` after it
+    static ref SEGMENT_MAX_AGE_SECS: u64 = {
+        match env::var("SEGMENT_MAX_AGE_SECS") {
+            Ok(v) => match v.parse::<u64>() {
+                Ok(secs) => {
+                    info!("`SEGMENT_MAX_AGE_SECS` env variable is set to {}", secs);
+                    secs
+                }
+                Err(err) => {
+                    warn!("`SEGMENT_MAX_AGE_SECS` env variable is ignored: {}", err);
+                    DEFAULT_SEGMENT_MAX_AGE_SECS
+                }
+            },
+            Err(_) => DEFAULT_SEGMENT_MAX_AGE_SECS,
+        }
+    };
+}
+
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_init(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+) -> errors::Result<impl IntoResponse> {
+    check_mp4_codec()?;
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    let init = tokio::task::spawn_blocking(move || {
+        let files = get_frames(&path_to_h264_frames)?;
+        fmp4_init_segment(&path_to_h264_frames, &files)
+    })
+    .await??;
+    Ok((MP4_CONTENT_TYPE, init))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::tests::write_log;
+    use axum::body::to_bytes;
+    use axum::http::Uri;
+
+    async fn try_segment(
+        base_path: &BasePath,
+        log_name: &str,
+        query: &str,
+    ) -> errors::Result<impl IntoResponse> {
+        let uri: Uri = format!("/v1/segment/{log_name}?{query}").parse().unwrap();
+        get_segment(
+            State(base_path.clone()),
+            Method::GET,
+            LogName(log_name.to_string()),
+            Query::try_from_uri(&uri).unwrap(),
+            HeaderMap::new(),
+        )
+        .await
+    }
+
+    async fn segment(base_path: &BasePath, log_name: &str, query: &str) -> Response {
+        try_segment(base_path, log_name, query)
+            .await
+            .unwrap()
+            .into_response()
+    }
+
+    #[tokio::test]
+    async fn segments_that_select_no_frames_are_out_of_range() {
+        let base_path = write_log("out-of-range", 0..10, 100);
+        // The log lasts 500 ms at 20 fps, a frame is 50 ms. Unchecked, the milliseconds of the
+        // first two would wrap around to 0 frames.
+        for query in [
+            "offset=922337203685477581&length=1000",
+            "offset=0&length=922337203685477581",
+            "offset=500&length=1000",
+            "offset=5000&length=1000",
+            "offset=0&length=10",
+        ] {
+            let response = match try_segment(&base_path, "out-of-range", query).await {
+                Ok(_) => panic!("{query} selects frames"),
+                Err(err) => err.into_response(),
+            };
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{query}");
+            assert_eq!(
+                response.headers()[errors::ERROR_CODE_HEADER],
+                "40006",
+                "{query}"
+            );
+        }
+        // A segment that starts in the log but ends after it is cut short
+        let response = segment(&base_path, "out-of-range", "offset=450&length=1000").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_segments_have_the_content_length_of_their_body() {
+        let base_path = write_log("streamed", 0..40, 700);
+        let response = segment(&base_path, "streamed", "offset=0&length=2000").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_length = response.headers()[header::CONTENT_LENGTH].clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(content_length, body.len().to_string());
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn segments_have_the_content_type_of_their_video_type() {
+        let base_path = write_log("content-type", 0..10, 100);
+        for (video_type, content_type) in [
+            ("MpegTs", "video/MP2T"),
+            ("Mp4", "video/mp4"),
+            ("FragmentedMp4", "video/mp4"),
+            ("Raw", "video/H264"),
+        ] {
+            let query = format!("offset=0&length=500&video_type={video_type}");
+            let response = segment(&base_path, "content-type", &query).await;
+            assert_eq!(response.status(), StatusCode::OK, "{video_type}");
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                content_type,
+                "{video_type}"
+            );
+        }
+        // Raw is the frames one after another, without a container
+        let response = segment(
+            &base_path,
+            "content-type",
+            "offset=0&length=100&video_type=Raw",
+        );
+        let body = to_bytes(response.await.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let dir = base_path.log_path("content-type");
+        let frames = [
+            fs::read(format!("{dir}/0.ts")),
+            fs::read(format!("{dir}/1.ts")),
+        ];
+        assert_eq!(body, frames.map(Result::unwrap).concat());
+        fs::remove_dir_all(base_path.get()).unwrap();
+    }
+
+    const MPEGTS: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "video/mp2t")];
+
+    fn body_of_len(len: usize) -> Bytes {
+        (0..len).map(|idx| idx as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn multiple_ranges_are_sent_as_multipart_byteranges() {
+        let body = body_of_len(1000);
+        let response = range_response(Some("bytes=0-10,20-30"), MPEGTS, body.clone());
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let multipart = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            multipart,
+            range::multipart_byteranges(&body, &[0..11, 20..31], "video/mp2t", &boundary)
+        );
+    }
+
+    #[tokio::test]
+    async fn suffix_ranges_are_the_end_of_the_segment() {
+        let body = body_of_len(2000);
+        let response = range_response(Some("bytes=-500"), MPEGTS, body.clone());
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            "bytes 1500-1999/2000"
+        );
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "500");
+        let part = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(part, body.slice(1500..));
+    }
+
+    #[tokio::test]
+    async fn ranges_of_other_units_get_the_whole_segment() {
+        let body = body_of_len(100);
+        let response = range_response(Some("items=0-10"), MPEGTS, body.clone());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert!(response.headers().get(header::CONTENT_RANGE).is_none());
+        let whole = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(whole, body);
+    }
+}
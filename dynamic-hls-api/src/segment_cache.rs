@@ -0,0 +1,133 @@
+// In-memory LRU cache of muxed segments, concurrent requests of the same segment mux it only once
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use axum_prometheus::metrics::counter;
+use bytes::Bytes;
+use tokio::sync::OnceCell;
+
+use crate::errors;
+
+const CACHE_HITS_METRIC: &str = "segment_cache_hits_total";
+const CACHE_MISSES_METRIC: &str = "segment_cache_misses_total";
+
+/// Whether `SegmentCache::get_or_mux` found the segment or had to mux it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+struct Entry {
+    cell: Arc<OnceCell<Bytes>>,
+    /// Value of `Entries::clock` when the segment was requested last
+    last_used: u64,
+}
+
+struct Entries<K> {
+    entries: HashMap<K, Entry>,
+    clock: u64,
+}
+
+pub struct SegmentCache<K> {
+    capacity: usize,
+    entries: Mutex<Entries<K>>,
+}
+
+impl<K: Hash + Eq + Clone> SegmentCache<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(Entries {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Returns the cached segment or muxes it with `mux` on a blocking thread. Callers racing
+    /// for the same key wait for the first one instead of muxing the segment again. Failures
+    /// are not cached. Hits and misses are counted in the Prometheus metrics.
+    pub async fn get_or_mux<F>(&self, key: K, mux: F) -> errors::Result<(Bytes, CacheStatus)>
+    where
+        F: FnOnce() -> errors::Result<Vec<u8>> + Send + 'static,
+    {
+        let cell = self.cell(&key);
+        let mut status = CacheStatus::Hit;
+        let result = cell
+            .get_or_try_init(|| {
+                status = CacheStatus::Miss;
+                async move {
+                    let bytes = tokio::task::spawn_blocking(mux).await??;
+                    Ok::<_, errors::AppError>(Bytes::from(bytes))
+                }
+            })
+            .await;
+        match status {
+            CacheStatus::Hit => counter!(CACHE_HITS_METRIC).increment(1),
+            CacheStatus::Miss => counter!(CACHE_MISSES_METRIC).increment(1),
+        }
+        match result {
+            Ok(bytes) => Ok((bytes.clone(), status)),
+            Err(err) => {
+                self.remove(&key, &cell);
+                Err(err)
+            }
+        }
+    }
+
+    /// Whether the segment is cached or being muxed by `get_or_mux`
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries.lock().unwrap().entries.contains_key(key)
+    }
+
+    /// Caches a segment muxed without `get_or_mux`, it counts as a miss
+    pub fn insert(&self, key: K, bytes: Bytes) {
+        counter!(CACHE_MISSES_METRIC).increment(1);
+        // Fails when another request cached the same segment in the meantime
+        let _ = self.cell(&key).set(bytes);
+    }
+
+    fn cell(&self, key: &K) -> Arc<OnceCell<Bytes>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some(entry) = entries.entries.get_mut(key) {
+            entry.last_used = clock;
+            return entry.cell.clone();
+        }
+        // The capacity is small, a scan is cheaper than keeping the entries ordered
+        while entries.entries.len() >= self.capacity {
+            let least_recently_used = entries
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(k) = least_recently_used {
+                entries.entries.remove(&k);
+            }
+        }
+        let cell = Arc::new(OnceCell::new());
+        entries.entries.insert(
+            key.clone(),
+            Entry {
+                cell: cell.clone(),
+                last_used: clock,
+            },
+        );
+        cell
+    }
+
+    /// Forgets the failed segment, unless it was evicted and muxed again in the meantime
+    fn remove(&self, key: &K, cell: &Arc<OnceCell<Bytes>>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .entries
+            .get(key)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.cell, cell))
+        {
+            entries.entries.remove(key);
+        }
+    }
+}
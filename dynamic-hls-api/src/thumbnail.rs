@@ -0,0 +1,99 @@
+// JPEG stills of a log for scrubbing, ffmpeg decodes a keyframe and encodes the JPEG. ffmpeg
+// must be installed where the service runs, see `ffmpeg.rs`.
+use crate::errors;
+use crate::ffmpeg;
+use crate::lookups::get_keyframes;
+use crate::mux;
+use crate::routes::{
+    check_fps, content_length, default_fps, get_frames, ms_to_frames, BasePath, LogName,
+};
+use axum::debug_handler;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderName};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use tracing::debug;
+
+/// Widest thumbnail `width` asks for
+pub const MAX_THUMBNAIL_WIDTH: u32 = 3840;
+
+/// Muxes the keyframe to MPEG-TS for ffmpeg and returns the JPEG it makes of it. It is `width`
+/// wide with the aspect ratio kept, or of the size of the frame without `width`.
+pub async fn thumbnail(
+    path_to_h264_frames: String,
+    keyframe_file: String,
+    fps: u32,
+    width: Option<u32>,
+) -> errors::Result<Vec<u8>> {
+    let ts = tokio::task::spawn_blocking(move || {
+        mux::h264streams_to_mpegts(&path_to_h264_frames, &[keyframe_file], fps, 0, false, true)
+    })
+    .await??;
+
+    let scale = width.map(|width| format!("scale={width}:-2:flags=lanczos"));
+    let mut args = vec!["-frames:v", "1"];
+    if let Some(scale) = &scale {
+        args.extend(["-vf", scale.as_str()]);
+    }
+    args.extend(["-f", "mjpeg"]);
+    let jpeg = ffmpeg::run(ts, &args).await?;
+    debug!("Made a JPEG of {} bytes", jpeg.len());
+    Ok(jpeg)
+}
+
+const JPEG_CONTENT_TYPE: [(HeaderName, &str); 1] = [(header::CONTENT_TYPE, "image/jpeg")];
+
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailQuery {
+    #[serde(rename = "offset")]
+    offset_ms: usize,
+    /// Width of the JPEG up to `MAX_THUMBNAIL_WIDTH`, the one of the frames when absent
+    width: Option<u32>,
+    /// Frame rate of the camera, see `Pagination::fps`
+    #[serde(default = "default_fps")]
+    fps: u32,
+}
+
+/// Still of the log at `offset`, of the keyframe at or before it, for a scrubbing UI. It needs
+/// ffmpeg at runtime.
+#[debug_handler]
+#[tracing::instrument(level = "INFO", skip(base_path))]
+pub async fn get_thumbnail(
+    State(base_path): State<BasePath>,
+    LogName(log_name): LogName,
+    Query(query): Query<ThumbnailQuery>,
+) -> errors::Result<impl IntoResponse> {
+    check_fps(query.fps)?;
+    if query
+        .width
+        .is_some_and(|width| !(1..=MAX_THUMBNAIL_WIDTH).contains(&width))
+    {
+        Err(errors::ErrorKind::BadRequestError(format!(
+            "`width` must be between 1 and {}, got {}",
+            MAX_THUMBNAIL_WIDTH,
+            query.width.unwrap_or_default()
+        )))?
+    }
+    let offset_frames = ms_to_frames(query.offset_ms, query.fps)?;
+    let path_to_h264_frames = base_path.log_path(&log_name);
+    let path = path_to_h264_frames.clone();
+    let (files, keyframes) = tokio::task::spawn_blocking(move || {
+        Ok::<_, errors::AppError>((get_frames(&path)?, get_keyframes(&path)?))
+    })
+    .await??;
+    if offset_frames >= files.len() {
+        Err(errors::ErrorKind::SegmentOutOfRangeError(format!(
+            "Thumbnail is at frame {offset_frames}, {log_name} has {} frames",
+            files.len()
+        )))?
+    }
+    let Some(keyframe) = keyframes.iter().rev().find(|k| **k <= offset_frames) else {
+        Err(errors::ErrorKind::NotFoundError(format!(
+            "{log_name} has no keyframe at or before {} ms",
+            query.offset_ms
+        )))?
+    };
+    let keyframe_file = files[*keyframe].clone();
+    let jpeg = thumbnail(path_to_h264_frames, keyframe_file, query.fps, query.width).await?;
+    Ok((JPEG_CONTENT_TYPE, content_length(jpeg.len()), jpeg))
+}
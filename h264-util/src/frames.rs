@@ -0,0 +1,119 @@
+// A camera log is a directory with a file per frame, named after the frame index
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+/// Frame files are named after the frame index, i.e. `42.ts` is the frame 42
+pub fn frame_index(file_name: &str) -> Option<i64> {
+    file_name.strip_suffix(".ts")?.parse::<i64>().ok()
+}
+
+/// Frame files in `path` ordered by their index. Files that aren't named after a frame index are
+/// skipped, an empty directory has no frames.
+pub fn list_frames(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let mut frames: Vec<(i64, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        match frame_index(file_name) {
+            Some(index) => frames.push((index, entry.path())),
+            None if file_name.ends_with(".ts") => {
+                debug!(
+                    "Skipping {file_name} in {}, it is not a frame index",
+                    path.display()
+                )
+            }
+            None => {}
+        }
+    }
+    frames.sort_by_key(|(index, _)| *index);
+    Ok(frames.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Empty files with the names in a directory of their own
+    fn write_files(name: &str, file_names: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("h264-util-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for file_name in file_names {
+            fs::write(dir.join(file_name), []).unwrap();
+        }
+        dir
+    }
+
+    fn file_names(frames: &[PathBuf]) -> Vec<&str> {
+        frames
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn frame_index_is_the_number_of_the_ts_files() {
+        assert_eq!(frame_index("42.ts"), Some(42));
+        assert_eq!(frame_index("0.ts"), Some(0));
+        assert_eq!(frame_index("-1.ts"), Some(-1));
+        assert_eq!(frame_index("42.h264"), None);
+        assert_eq!(frame_index("42"), None);
+        assert_eq!(frame_index("frame.ts"), None);
+        assert_eq!(frame_index(".ts"), None);
+    }
+
+    #[test]
+    fn frames_are_sorted_by_index_not_by_name() {
+        let dir = write_files(
+            "sorted",
+            &["10.ts", "9.ts", "100.ts", "0.ts", "2.ts", "11.ts"],
+        );
+        let frames = list_frames(&dir).unwrap();
+        assert_eq!(
+            file_names(&frames),
+            ["0.ts", "2.ts", "9.ts", "10.ts", "11.ts", "100.ts"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_not_named_after_a_frame_index_are_skipped() {
+        let dir = write_files(
+            "skipped",
+            &[
+                "1.ts",
+                "playlist.m3u8",
+                "junk.ts",
+                "3.aac",
+                "0.ts",
+                ".DS_Store",
+            ],
+        );
+        let frames = list_frames(&dir).unwrap();
+        assert_eq!(file_names(&frames), ["0.ts", "1.ts"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_empty_directory_has_no_frames() {
+        let dir = write_files("empty", &[]);
+        assert!(list_frames(&dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_directory_is_an_error() {
+        let dir = env::temp_dir().join(format!("h264-util-{}-missing", std::process::id()));
+        assert_eq!(
+            list_frames(dir).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,3 @@
+// Helpers shared by `dynamic-hls-api` and `webrtc-example`, both read the same camera logs
+pub mod frames;
+pub mod nal;
@@ -0,0 +1,194 @@
+// Splitting H264 Annex B byte stream into NAL units, ITU-T H.264 Annex B
+use std::str::FromStr;
+
+/// NAL unit types, ITU-T H.264 Table 7-1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalUnitType {
+    NonIdrSlice,
+    IdrSlice,
+    Sei,
+    Sps,
+    Pps,
+    Aud,
+    Other(u8),
+}
+
+impl From<u8> for NalUnitType {
+    fn from(nal_unit_type: u8) -> Self {
+        match nal_unit_type {
+            1 => NalUnitType::NonIdrSlice,
+            5 => NalUnitType::IdrSlice,
+            6 => NalUnitType::Sei,
+            7 => NalUnitType::Sps,
+            8 => NalUnitType::Pps,
+            9 => NalUnitType::Aud,
+            x => NalUnitType::Other(x),
+        }
+    }
+}
+
+/// Type of the NAL unit, `nal` starts with the NAL header byte
+pub fn nal_unit_type(nal: &[u8]) -> NalUnitType {
+    NalUnitType::from(nal.first().map_or(0, |b| b & 0x1F))
+}
+
+/// Codec of the frames, the byte stream of both is split the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    /// H.265, ITU-T H.265
+    Hevc,
+}
+
+impl FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "h264" | "avc" => Ok(VideoCodec::H264),
+            "h265" | "hevc" => Ok(VideoCodec::Hevc),
+            _ => Err(format!("Unknown video codec {s}, expected h264 or hevc")),
+        }
+    }
+}
+
+impl VideoCodec {
+    /// Size of the NAL unit header, the payload starts after it
+    pub fn nal_header_size(self) -> usize {
+        match self {
+            VideoCodec::H264 => 1,
+            VideoCodec::Hevc => 2,
+        }
+    }
+
+    /// Type of the NAL unit, `nal` starts with the NAL header. HEVC types are mapped to the H264
+    /// ones they play the part of, the IRAP pictures (BLA, IDR and CRA, ITU-T H.265 Table 7-1)
+    /// are `IdrSlice` as decoding can start from them. Other types keep the number of their codec.
+    pub fn nal_unit_type(self, nal: &[u8]) -> NalUnitType {
+        match self {
+            VideoCodec::H264 => nal_unit_type(nal),
+            // forbidden_zero_bit, 6 bits of nal_unit_type, then the layer and temporal ids
+            VideoCodec::Hevc => match nal.first().map_or(0, |b| (b >> 1) & 0x3F) {
+                0..=9 => NalUnitType::NonIdrSlice,
+                16..=23 => NalUnitType::IdrSlice,
+                33 => NalUnitType::Sps,
+                34 => NalUnitType::Pps,
+                35 => NalUnitType::Aud,
+                39 | 40 => NalUnitType::Sei,
+                x => NalUnitType::Other(x),
+            },
+        }
+    }
+
+    /// Whether the Annex B frame has a picture decoding can start from
+    pub fn is_keyframe(self, frame: &[u8]) -> bool {
+        nal_units(frame)
+            .into_iter()
+            .any(|nal| self.nal_unit_type(nal) == NalUnitType::IdrSlice)
+    }
+
+    /// Access unit delimiter NAL unit with a 4 bytes start code, it allows any slice type
+    pub fn access_unit_delimiter(self) -> &'static [u8] {
+        match self {
+            // primary_pic_type 7 and the stop bit
+            VideoCodec::H264 => &[0, 0, 0, 1, 0x09, 0xF0],
+            // nuh_layer_id 0, nuh_temporal_id_plus1 1, pic_type 2 and the stop bit
+            VideoCodec::Hevc => &[0, 0, 0, 1, 0x46, 0x01, 0x50],
+        }
+    }
+
+    /// Whether the first NAL unit of the Annex B frame is an access unit delimiter, only the
+    /// bytes up to its header are looked at
+    pub fn starts_with_aud(self, frame: &[u8]) -> bool {
+        frame
+            .windows(3)
+            .position(|w| w == [0, 0, 1])
+            .is_some_and(|start| self.nal_unit_type(&frame[start + 3..]) == NalUnitType::Aud)
+    }
+}
+
+/// Splits Annex B byte stream into NAL units, without start codes
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut nal_start: Option<usize> = None;
+    let mut i = 0;
+    while i + 2 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = nal_start {
+                nals.push(trim_trailing_zeros(&data[start..i]));
+            }
+            i += 3;
+            nal_start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(start) = nal_start {
+        nals.push(trim_trailing_zeros(&data[start..]));
+    }
+    nals.retain(|nal| !nal.is_empty());
+    nals
+}
+
+/// Zero bytes before a start code belong either to the 4 bytes start code or to trailing_zero_8bits
+fn trim_trailing_zeros(nal: &[u8]) -> &[u8] {
+    let end = nal.iter().rposition(|b| *b != 0).map_or(0, |pos| pos + 1);
+    &nal[..end]
+}
+
+/// Removes emulation prevention bytes, the `03` in `00 00 03`, ITU-T H.264 section 7.4.1
+pub fn to_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Annex B frame of the NAL units, with 4 bytes start codes
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+            .collect()
+    }
+
+    #[test]
+    fn idr_frames_are_keyframes() {
+        let sps = [0x67, 0x42, 0xC0, 0x1F, 0xDA];
+        let pps = [0x68, 0xCE, 0x38, 0x80];
+        let idr = annex_b(&[&[0x09, 0xF0], &sps, &pps, &[0x65, 0x88, 0x84]]);
+        assert!(VideoCodec::H264.is_keyframe(&idr));
+        // nal_ref_idc doesn't matter
+        assert!(VideoCodec::H264.is_keyframe(&annex_b(&[&[0x25, 0x88]])));
+
+        let non_idr = annex_b(&[&[0x09, 0x30], &[0x41, 0x9A, 0x02]]);
+        assert!(!VideoCodec::H264.is_keyframe(&non_idr));
+        // Parameter sets alone don't start a picture
+        assert!(!VideoCodec::H264.is_keyframe(&annex_b(&[&sps, &pps])));
+        assert!(!VideoCodec::H264.is_keyframe(&[]));
+    }
+
+    #[test]
+    fn irap_pictures_are_hevc_keyframes() {
+        // IDR_W_RADL, CRA_NUT and BLA_W_LP, the type is in bits 1 to 6 of the first byte
+        for nal_unit_type in [19, 21, 16] {
+            let frame = annex_b(&[&[nal_unit_type << 1, 0x01, 0xAF]]);
+            assert!(VideoCodec::Hevc.is_keyframe(&frame), "{nal_unit_type}");
+        }
+        // TRAIL_R
+        assert!(!VideoCodec::Hevc.is_keyframe(&annex_b(&[&[0x02, 0x01, 0xAF]])));
+        // The H264 IDR header byte is a TRAIL_R of HEVC
+        assert!(!VideoCodec::Hevc.is_keyframe(&annex_b(&[&[0x65, 0x88]])));
+    }
+}
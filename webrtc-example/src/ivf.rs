@@ -0,0 +1,63 @@
+// VP8 and VP9 frames out of IVF files, the container libvpx and ffmpeg write them to
+use std::fs::File;
+use std::io::{BufReader, ErrorKind as ErrorKindIo};
+
+use bytes::Bytes;
+use tracing::info;
+use webrtc::media::io::ivf_reader::IVFReader;
+use webrtc::media::Error as WebrtcMediaError;
+
+use crate::{Codec, ErrorKind, Result};
+
+/// Reads all the frames of the IVF file, it has to be of `codec`
+pub fn read_frames(path: &str, codec: Codec) -> Result<Vec<Bytes>> {
+    let (mut reader, header) = IVFReader::new(BufReader::new(File::open(path)?))?;
+    let four_cc = codec.ivf_four_cc();
+    if &header.four_cc != four_cc {
+        Err(ErrorKind::IvfError(format!(
+            "{path} is {}, expected {}",
+            String::from_utf8_lossy(&header.four_cc),
+            String::from_utf8_lossy(four_cc)
+        )))?
+    }
+    let mut frames = Vec::new();
+    loop {
+        match reader.parse_next_frame() {
+            Ok((frame, _)) => frames.push(frame.freeze()),
+            // The reader has no EOF of its own, a frame header cut off ends the file as well
+            Err(WebrtcMediaError::Io(err)) if err.0.kind() == ErrorKindIo::UnexpectedEof => break,
+            Err(err) => Err(err)?,
+        }
+    }
+    info!(
+        "There are {} {:?} frames of {}x{} in {}",
+        frames.len(),
+        codec,
+        header.width,
+        header.height,
+        path
+    );
+    Ok(frames)
+}
+
+/// Whether decoding can start at the frame, from the frame type of its uncompressed header,
+/// RFC 6386 section 9.1 for VP8 and section 6.2 of the VP9 bitstream specification
+pub fn is_keyframe(codec: Codec, frame: &[u8]) -> bool {
+    let Some(&first) = frame.first() else {
+        return false;
+    };
+    match codec {
+        // frame_type is the lowest bit, 0 for key frames
+        Codec::Vp8 => first & 1 == 0,
+        Codec::Vp9 => {
+            // frame_marker, profile_low_bit and profile_high_bit
+            let profile = ((first >> 4) & 1) << 1 | (first >> 5) & 1;
+            // Profile 3 has a reserved bit before show_existing_frame
+            let show_existing_frame_bit = if profile == 3 { 2 } else { 3 };
+            let show_existing_frame = (first >> show_existing_frame_bit) & 1 == 1;
+            let frame_type = (first >> (show_existing_frame_bit - 1)) & 1;
+            first >> 6 == 0b10 && !show_existing_frame && frame_type == 0
+        }
+        Codec::H264 => h264_util::nal::VideoCodec::H264.is_keyframe(frame),
+    }
+}
@@ -1,24 +1,44 @@
+mod ivf;
+mod pacing;
+mod whep;
+
 use std::{env, fs};
 
 use base64::Engine;
-use clap::Parser;
+use bytes::Bytes;
+use clap::{Parser, ValueEnum};
+use h264_util::frames::list_frames;
+use pacing::{FrameKind, Pacer, RembBitrate};
+use serde::Deserialize;
 use std::fs::File;
-use std::io::BufReader;
-use std::sync::Arc;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, BufReader as AsyncBufReader};
 use tokio::sync::Notify;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::EnvFilter;
 use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
 use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::ice::url::{SchemeType, Url};
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
-use webrtc::media::io::h264_reader::H264Reader;
+use webrtc::media::io::h264_reader::{H264Reader, NalUnitType};
 use webrtc::media::Sample;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::packet::Packet as RtcpPacket;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc::track::track_local::TrackLocal;
@@ -53,6 +73,14 @@ pub enum ErrorKind {
     IoError(#[from] std::io::Error),
     #[error("WebRTCError: {0}")]
     WebRTCError(#[from] webrtc::Error),
+    #[error("IceServerError: {0}")]
+    IceServerError(String),
+    #[error("WhepError: {0}")]
+    WhepError(String),
+    #[error("MediaError: {0}")]
+    MediaError(#[from] webrtc::media::Error),
+    #[error("IvfError: {0}")]
+    IvfError(String),
 }
 
 impl<E> From<E> for AppError
@@ -69,15 +97,319 @@ pub type Result<T> = std::result::Result<T, AppError>;
 #[derive(Parser, Debug, Clone)]
 #[clap(author, about, long_version = APP_VERSION)]
 struct AppArgs {
-    /// Path to H264 frames
-    #[clap(long)]
-    path_to_h264_frames: String,
+    /// Path to H264 frames, either a directory with a numbered `.ts` file per frame or a single
+    /// Annex B `.h264` file. A file is streamed as one frame, so the viewer asking for a keyframe
+    /// waits for the next one in the stream instead of going back. VP8 and VP9 frames are read
+    /// from an `.ivf` file. Repeatable, for the same recording at other resolutions or bitrates,
+    /// the viewer switches between them with the `layer` control message. The first one is sent
+    /// first.
+    #[clap(long, required = true)]
+    path_to_h264_frames: Vec<String>,
+    /// Codec of the frames, the track is offered with it only
+    #[clap(long, value_enum, default_value_t = Codec::H264)]
+    codec: Codec,
     /// Path to JSON encoded local RTCSessionDescription https://developer.mozilla.org/en-US/docs/Web/API/RTCPeerConnection/localDescription
+    #[clap(long, required_unless_present = "whep_listen")]
+    path_local_description_json: Option<String>,
+    /// Serve WHEP on this address, e.g. `127.0.0.1:8080`, instead of answering a single offer.
+    /// Players POST their offer to `/whep`, each one gets its own stream.
+    #[clap(long)]
+    whep_listen: Option<String>,
+    /// Tear the peer connection down when no RTCP is received for that many seconds after
+    /// connecting, 0 disables it
+    #[clap(long, default_value_t = 30)]
+    idle_timeout_secs: u64,
+    /// Print the answer right away and the local ICE candidates as they are gathered, one base64
+    /// encoded line each, instead of waiting for the gathering to complete. Remote candidates
+    /// are read from stdin the same way.
+    #[clap(long)]
+    trickle: bool,
+    /// Start over from the first frame after the last one instead of ending the stream
+    #[clap(long = "loop")]
+    looping: bool,
+    /// Frame rate of the recording, the `seek` control messages are converted to frames with it.
+    /// VP8 and VP9 frames are sent at this rate.
+    #[clap(
+        long,
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u64).range(1..=1000)
+    )]
+    fps: u64,
+    /// STUN or TURN server URL, e.g. `turn:turn.example.com:3478?transport=tcp`, repeatable.
+    /// Google's public STUN server is used when none is given.
+    #[clap(long = "ice-server")]
+    ice_servers: Vec<String>,
+    /// Username for the TURN servers
+    #[clap(long)]
+    turn_username: Option<String>,
+    /// Credential for the TURN servers
     #[clap(long)]
-    path_local_description_json: String,
+    turn_credential: Option<String>,
+    /// Bitrate in bits/s to stay under, frames are dropped beyond it. The REMB of the viewer
+    /// lowers it further when its estimate is below it. Without it only REMB limits the stream.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    target_bitrate: Option<u64>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    Vp8,
+    Vp9,
+}
+
+impl Codec {
+    fn mime_type(self) -> &'static str {
+        match self {
+            Codec::H264 => MIME_TYPE_H264,
+            Codec::Vp8 => MIME_TYPE_VP8,
+            Codec::Vp9 => MIME_TYPE_VP9,
+        }
+    }
+
+    /// FourCC of the IVF file header
+    pub fn ivf_four_cc(self) -> &'static [u8; 4] {
+        match self {
+            Codec::H264 => b"H264",
+            Codec::Vp8 => b"VP80",
+            Codec::Vp9 => b"VP90",
+        }
+    }
+}
+
+/// Frames the sender goes through, the H264 ones are read from their files as they are sent
+enum Frames {
+    H264(Vec<String>),
+    Ivf(Vec<Bytes>),
+}
+
+impl Frames {
+    fn len(&self) -> usize {
+        match self {
+            Frames::H264(files) => files.len(),
+            Frames::Ivf(frames) => frames.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Frames of one of the `--path-to-h264-frames`, with what the track needs to know about them
+struct Layer {
+    path: String,
+    frames: Frames,
+    /// Positions of the frames decoding can start at
+    keyframes: Vec<usize>,
+    /// Format parameters of the codec, the track is offered with the ones of the first layer
+    sdp_fmtp_line: String,
+}
+
+/// Wait between the NAL units of the H264 frames
+const NAL_INTERVAL: Duration = Duration::from_millis(25);
+
+const DEFAULT_ICE_SERVER: &str = "stun:stun.l.google.com:19302";
+
+/// ICE servers of the arguments, the TURN ones get the credentials. Checked here so that a typo
+/// is reported with the URL instead of failing the peer connection.
+fn ice_servers(args: &AppArgs) -> Result<Vec<RTCIceServer>> {
+    if args.ice_servers.is_empty() {
+        return Ok(vec![RTCIceServer {
+            urls: vec![DEFAULT_ICE_SERVER.to_owned()],
+            ..Default::default()
+        }]);
+    }
+    let mut servers = Vec::with_capacity(args.ice_servers.len());
+    for url in &args.ice_servers {
+        let parsed = Url::parse_url(url).map_err(|err| {
+            ErrorKind::IceServerError(format!("Malformed ICE server URL `{url}`: {err}"))
+        })?;
+        let server = match parsed.scheme {
+            SchemeType::Turn | SchemeType::Turns => {
+                let (Some(username), Some(credential)) =
+                    (&args.turn_username, &args.turn_credential)
+                else {
+                    Err(ErrorKind::IceServerError(format!(
+                        "TURN server `{url}` needs --turn-username and --turn-credential"
+                    )))?
+                };
+                RTCIceServer {
+                    urls: vec![url.clone()],
+                    username: username.clone(),
+                    credential: credential.clone(),
+                    credential_type: RTCIceCredentialType::Password,
+                }
+            }
+            _ => RTCIceServer {
+                urls: vec![url.clone()],
+                ..Default::default()
+            },
+        };
+        servers.push(server);
+    }
+    Ok(servers)
+}
+
+/// Used when the frames don't contain an SPS, Constrained Baseline profile, level 3.1
+const DEFAULT_PROFILE_LEVEL_ID: &str = "42e01f";
+
+/// Finds the first SPS in the frames and returns its profile_idc, constraint flags and level_idc
+/// formatted as `profile-level-id` (RFC 6184, section 8.1)
+fn find_profile_level_id(files: &[String]) -> Option<String> {
+    for file in files {
+        let f = File::open(file).ok()?;
+        let mut h264 = H264Reader::new(BufReader::new(f), 400 * 1024);
+        while let Ok(nal) = h264.next_nal() {
+            if nal.unit_type == NalUnitType::SPS && nal.data.len() >= 4 {
+                let sps = &nal.data;
+                return Some(format!("{:02x}{:02x}{:02x}", sps[1], sps[2], sps[3]));
+            }
+        }
+    }
+    None
 }
 
-async fn run(session_desc: RTCSessionDescription, path_to_h264_frames: &str) -> Result<()> {
+/// Reads the frames of `path_to_h264_frames`, the H264 ones are only listed
+fn read_layer(path_to_h264_frames: &str, codec: Codec) -> Result<Layer> {
+    // Paths of the frame files, a single Annex B file is read as one frame
+    let files: Vec<String> = if codec != Codec::H264 {
+        Vec::new()
+    } else if Path::new(path_to_h264_frames).is_file() {
+        info!("Streaming the Annex B file {}", path_to_h264_frames);
+        vec![path_to_h264_frames.to_string()]
+    } else {
+        let files: Vec<String> = list_frames(path_to_h264_frames)?
+            .iter()
+            .filter_map(|path| path.to_str().map(str::to_string))
+            .collect();
+        info!(
+            "There are {} H264 frames in {} folder",
+            files.len(),
+            path_to_h264_frames
+        );
+        files
+    };
+
+    let (frames, sdp_fmtp_line) = match codec {
+        Codec::H264 => {
+            let profile_level_id = find_profile_level_id(&files).unwrap_or_else(|| {
+                warn!(
+                    "Could not find SPS in {}, use profile-level-id {}",
+                    path_to_h264_frames, DEFAULT_PROFILE_LEVEL_ID
+                );
+                DEFAULT_PROFILE_LEVEL_ID.to_string()
+            });
+            info!("H264 profile-level-id is {}", profile_level_id);
+            // Browsers silently reject the track when these don't match the stream
+            let sdp_fmtp_line = format!(
+                "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={profile_level_id}"
+            );
+            (Frames::H264(files), sdp_fmtp_line)
+        }
+        Codec::Vp8 => (
+            Frames::Ivf(ivf::read_frames(path_to_h264_frames, codec)?),
+            String::new(),
+        ),
+        // Profile 0 is the one every browser decodes
+        Codec::Vp9 => (
+            Frames::Ivf(ivf::read_frames(path_to_h264_frames, codec)?),
+            "profile-id=0".to_string(),
+        ),
+    };
+
+    let keyframes = find_keyframes(&frames, codec);
+    if keyframes.is_empty() {
+        warn!(
+            "No keyframes in {}, the viewer may not decode the stream",
+            path_to_h264_frames
+        );
+    }
+    Ok(Layer {
+        path: path_to_h264_frames.to_string(),
+        frames,
+        keyframes,
+        sdp_fmtp_line,
+    })
+}
+
+/// Positions of the frames decoding can start at, the ones with an IDR slice for H264
+fn find_keyframes(frames: &Frames, codec: Codec) -> Vec<usize> {
+    let mut keyframes = Vec::new();
+    for idx in 0..frames.len() {
+        let keyframe = match frames {
+            Frames::H264(files) => {
+                fs::read(&files[idx]).is_ok_and(|bytes| ivf::is_keyframe(codec, &bytes))
+            }
+            Frames::Ivf(ivf_frames) => ivf::is_keyframe(codec, &ivf_frames[idx]),
+        };
+        if keyframe {
+            keyframes.push(idx);
+        }
+    }
+    keyframes
+}
+
+/// The keyframe at or before `idx`, the first one when there is none before
+fn preceding_keyframe(keyframes: &[usize], idx: usize) -> usize {
+    match keyframes.partition_point(|&k| k <= idx) {
+        0 => keyframes.first().copied().unwrap_or(0),
+        n => keyframes[n - 1],
+    }
+}
+
+/// Frame at `offset_ms` of a seek, `offset_ms` comes from the viewer and may be anything
+fn seek_frame(offset_ms: u64, fps: u64) -> usize {
+    usize::try_from(offset_ms.saturating_mul(fps) / 1000).unwrap_or(usize::MAX)
+}
+
+/// Label and id of the data channel the viewer controls the stream with. It is negotiated by the
+/// application rather than announced in-band, so the viewer creates it too, with
+/// `createDataChannel("control", {negotiated: true, id: 0})` before making its offer.
+const CONTROL_CHANNEL_LABEL: &str = "control";
+const CONTROL_CHANNEL_ID: u16 = 0;
+
+/// Messages of the control data channel, JSON objects with the command in `cmd`:
+///
+/// - `{"cmd":"pause"}` stops sending frames
+/// - `{"cmd":"play"}` sends them again after a pause
+/// - `{"cmd":"seek","offset_ms":5000}` goes on from the keyframe at or before that time of the
+///   recording, the time is converted to a frame at `--fps`. Seeking keeps the stream paused
+///   when it is.
+/// - `{"cmd":"layer","layer":1}` goes on with the frames of the second `--path-to-h264-frames`
+///   from its next keyframe, the frames of the layers are expected to be of the same times
+///
+/// Malformed messages are logged and ignored, nothing is sent back.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum ControlMessage {
+    Pause,
+    Play,
+    Seek { offset_ms: u64 },
+    Layer { layer: usize },
+}
+
+/// Whether the RTCP compound packet has a Picture Loss Indication or a Full Intra Request
+fn requests_keyframe(packets: &[Box<dyn RtcpPacket + Send + Sync>]) -> bool {
+    packets.iter().any(|packet| {
+        let packet = packet.as_any();
+        packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>()
+    })
+}
+
+/// Peer connection streaming the frames to a viewer, the answer isn't set as the local
+/// description yet. Sending to `done_tx` ends the session, `done_rx` gets it and whatever else
+/// ends the stream. Whoever gets it cancels `cancel` to stop the tasks of the session, closing
+/// the peer connection doesn't stop them.
+struct Session {
+    peer_connection: Arc<RTCPeerConnection>,
+    answer: RTCSessionDescription,
+    done_tx: tokio::sync::mpsc::Sender<()>,
+    done_rx: tokio::sync::mpsc::Receiver<()>,
+    cancel: CancellationToken,
+}
+
+async fn connect(session_desc: RTCSessionDescription, args: &AppArgs) -> Result<Session> {
     // Create a MediaEngine object to configure the supported codec
     let mut m = MediaEngine::default();
     m.register_default_codecs()?;
@@ -98,26 +430,56 @@ async fn run(session_desc: RTCSessionDescription, path_to_h264_frames: &str) ->
 
     // Prepare the configuration
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
-        }],
+        ice_servers: ice_servers(args)?,
         ..Default::default()
     };
 
     // Create a new RTCPeerConnection
     let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
+    let looping = args.looping;
+    let fps = args.fps;
     let notify_tx = Arc::new(Notify::new());
     let notify_video = notify_tx.clone();
+    let notify_idle = notify_tx.clone();
 
-    let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (done_tx, done_rx) = tokio::sync::mpsc::channel::<()>(1);
     let video_done_tx = done_tx.clone();
+    let idle_done_tx = done_tx.clone();
+    let session_done_tx = done_tx.clone();
+    let cancel = CancellationToken::new();
+
+    // Browsers send RTCP receiver reports every few seconds, so it doubles as a keepalive
+    let last_rtcp_at = Arc::new(Mutex::new(Instant::now()));
+    let rtcp_last_rtcp_at = last_rtcp_at.clone();
+    let remb = RembBitrate::default();
+    let rtcp_remb = remb.clone();
+
+    let codec = args.codec;
+    let layers = args
+        .path_to_h264_frames
+        .iter()
+        .map(|path| read_layer(path, codec))
+        .collect::<Result<Vec<_>>>()?;
+    for layer in &layers[1..] {
+        if layer.frames.len() != layers[0].frames.len() {
+            warn!(
+                "{} has {} frames and {} has {}, the layers may not be of the same recording",
+                layer.path,
+                layer.frames.len(),
+                layers[0].path,
+                layers[0].frames.len()
+            );
+        }
+    }
+    // The viewer needs a keyframe to decode from whenever it (re)connects
+    let (keyframe_tx, mut keyframe_rx) = tokio::sync::mpsc::channel::<()>(1);
 
     // Create a video track
     let video_track = Arc::new(TrackLocalStaticSample::new(
         RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_H264.to_owned(),
+            mime_type: codec.mime_type().to_owned(),
+            sdp_fmtp_line: layers[0].sdp_fmtp_line.clone(),
             ..Default::default()
         },
         "video".to_owned(),
@@ -129,75 +491,258 @@ async fn run(session_desc: RTCSessionDescription, path_to_h264_frames: &str) ->
         .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
         .await?;
 
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::unbounded_channel::<ControlMessage>();
+    let control_channel = peer_connection
+        .create_data_channel(
+            CONTROL_CHANNEL_LABEL,
+            Some(RTCDataChannelInit {
+                negotiated: Some(CONTROL_CHANNEL_ID),
+                ..Default::default()
+            }),
+        )
+        .await?;
+    control_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        match serde_json::from_slice::<ControlMessage>(&msg.data) {
+            Ok(control) => {
+                info!("Viewer sent {:?}", control);
+                let _ = control_tx.send(control);
+            }
+            Err(err) => warn!("Ignoring malformed control message: {}", err),
+        }
+        Box::pin(async {})
+    }));
+
     // Read incoming RTCP packets
     // Before these packets are returned they are processed by interceptors. For things
     // like NACK this needs to be called.
+    // A viewer that lost a keyframe asks for a new one with PLI or FIR, the frames can't be
+    // encoded again, so the sender goes back to the last keyframe instead.
+    let rtcp_keyframe_tx = keyframe_tx.clone();
+    let rtcp_cancel = cancel.clone();
     tokio::spawn(async move {
         let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+        let read_rtcp = async move {
+            while let Ok((packets, _)) = rtp_sender.read(&mut rtcp_buf).await {
+                *rtcp_last_rtcp_at.lock().unwrap() = Instant::now();
+                rtcp_remb.update(&packets);
+                if requests_keyframe(&packets) {
+                    info!("Viewer requested a keyframe");
+                    let _ = rtcp_keyframe_tx.try_send(());
+                }
+            }
+        };
+        tokio::select! {
+            _ = read_rtcp => {}
+            _ = rtcp_cancel.cancelled() => {}
+        }
         Result::Ok(())
     });
 
-    let path_to_h264_frames: String = path_to_h264_frames.to_string();
-    let paths = fs::read_dir(path_to_h264_frames.clone())?;
-    let mut files: Vec<String> = paths
-        .map(|x| {
-            x.unwrap()
-                .path()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
-        })
-        .filter(|x| x.ends_with(".ts"))
-        .collect();
-    files.sort_by(|a, b| {
-        let f0: i64 = a.replace(".ts", "").parse::<i64>().unwrap();
-        let f1: i64 = b.replace(".ts", "").parse::<i64>().unwrap();
-        f0.cmp(&f1)
-    });
-    info!(
-        "There are {} H264 frames in {} folder",
-        files.len(),
-        &path_to_h264_frames
-    );
+    // A viewer may die silently without ICE ever reporting a disconnect
+    if args.idle_timeout_secs > 0 {
+        let idle_timeout = Duration::from_secs(args.idle_timeout_secs);
+        let idle_cancel = cancel.clone();
+        tokio::spawn(async move {
+            // A viewer that never connects is never notified
+            tokio::select! {
+                _ = notify_idle.notified() => {}
+                _ = idle_cancel.cancelled() => return,
+            }
+            *last_rtcp_at.lock().unwrap() = Instant::now();
+
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = idle_cancel.cancelled() => break,
+                }
+                let idle_for = last_rtcp_at.lock().unwrap().elapsed();
+                if idle_for > idle_timeout {
+                    warn!(
+                        "No RTCP activity for {:?}, tearing down the peer connection",
+                        idle_for
+                    );
+                    let _ = idle_done_tx.try_send(());
+                    break;
+                }
+            }
+        });
+    }
 
+    let target_bitrate = args.target_bitrate;
+    let video_cancel = cancel.clone();
     tokio::spawn(async move {
         // Wait for connection established
-        let _ = notify_video.notified().await;
-
-        for file in files {
-            // Open a H264 file and start reading using our H264Reader
-            let path = format!("{path_to_h264_frames}/{file}");
-            let file = File::open(path.clone())?;
-            let reader = BufReader::new(file);
-            let mut h264 = H264Reader::new(reader, 400 * 1024);
-
-            // It is important to use a time.Ticker instead of time.Sleep because
-            // * avoids accumulating skew, just calling time.Sleep didn't compensate for the time spent parsing the data
-            // * works around latency issues with Sleep
-            let mut ticker = tokio::time::interval(Duration::from_millis(25));
+        tokio::select! {
+            _ = notify_video.notified() => {}
+            _ = video_cancel.cancelled() => return Result::Ok(()),
+        }
+        // The track takes the samples without a viewer once the peer connection is closed, the
+        // frames would be paced into nothing until the recording ends
+        let stream = async move {
+            let mut pacer = Pacer::new(target_bitrate, remb);
+
+            // The same track goes on with the first frame, its timestamps keep increasing
+            let mut paused = false;
+            let frame_duration = Duration::from_millis(1000 / fps);
+            let mut frame_ticker = tokio::time::interval(frame_duration);
+            // Frames dropped since the last one sent, the next one lasts for them too
+            let mut dropped_frames = 0;
+            let mut layer = 0;
+            // Layer the viewer switches to at its next keyframe
+            let mut next_layer: Option<usize> = None;
             loop {
-                let nal = match h264.next_nal() {
-                    Ok(nal) => nal,
-                    Err(_err) => {
+                // Frames with a malformed NAL, what follows it in the file is not sent
+                let mut failed_frames = 0;
+                // Frames before the first keyframe can't be decoded
+                let mut idx = preceding_keyframe(&layers[layer].keyframes, 0);
+                while idx < layers[layer].frames.len() {
+                    // While paused it waits for the next message, it goes on when the viewer is
+                    // gone
+                    loop {
+                        let control = if paused {
+                            control_rx.recv().await
+                        } else {
+                            control_rx.try_recv().ok()
+                        };
+                        match control {
+                            Some(ControlMessage::Pause) => paused = true,
+                            Some(ControlMessage::Play) => paused = false,
+                            Some(ControlMessage::Seek { offset_ms }) => {
+                                let frame = seek_frame(offset_ms, fps);
+                                // Seeking is on the layer it switches to, no need to wait then
+                                if let Some(next) = next_layer.take() {
+                                    layer = next;
+                                }
+                                let frame = frame.min(layers[layer].frames.len() - 1);
+                                idx = preceding_keyframe(&layers[layer].keyframes, frame);
+                                info!("Seeking to {} ms, keyframe {}", offset_ms, idx);
+                            }
+                            Some(ControlMessage::Layer { layer: next }) if next < layers.len() => {
+                                next_layer = (next != layer).then_some(next);
+                            }
+                            Some(ControlMessage::Layer { layer: next }) => {
+                                warn!("Ignoring layer {}, there are {}", next, layers.len());
+                            }
+                            None => break,
+                        }
+                    }
+                    if let Some(next) = next_layer {
+                        if layers[next].keyframes.binary_search(&idx).is_ok() {
+                            info!(
+                                "Switching from {} to {} at keyframe {}",
+                                layers[layer].path, layers[next].path, idx
+                            );
+                            layer = next;
+                            next_layer = None;
+                        }
+                    }
+                    let Layer {
+                        frames, keyframes, ..
+                    } = &layers[layer];
+                    if idx >= frames.len() {
                         break;
                     }
-                };
-                video_track
-                    .write_sample(&Sample {
-                        data: nal.data.freeze(),
-                        duration: Duration::from_secs(1),
-                        ..Default::default()
-                    })
-                    .await?;
-                let _ = ticker.tick().await;
+                    if keyframe_rx.try_recv().is_ok() {
+                        let keyframe = preceding_keyframe(keyframes, idx);
+                        if keyframe != idx {
+                            info!("Going back from frame {} to keyframe {}", idx, keyframe);
+                            idx = keyframe;
+                        }
+                    }
+                    let frame = idx;
+                    idx += 1;
+                    let files = match &frames {
+                        Frames::H264(files) => files,
+                        Frames::Ivf(ivf_frames) => {
+                            let data = &ivf_frames[frame];
+                            let kind = match ivf::is_keyframe(codec, data) {
+                                true => FrameKind::Key,
+                                false => FrameKind::Reference,
+                            };
+                            if pacer.admit(data.len(), kind) {
+                                video_track
+                                    .write_sample(&Sample {
+                                        data: data.clone(),
+                                        duration: frame_duration * (1 + dropped_frames),
+                                        ..Default::default()
+                                    })
+                                    .await?;
+                                dropped_frames = 0;
+                            } else {
+                                dropped_frames += 1;
+                            }
+                            let _ = frame_ticker.tick().await;
+                            continue;
+                        }
+                    };
+                    let path = &files[frame];
+
+                    let bytes = fs::read(path)?;
+                    if !pacer.admit(bytes.len(), pacing::h264_frame_kind(&bytes)) {
+                        // Takes the time of a NAL, so the stream goes on at about its pace
+                        tokio::time::sleep(NAL_INTERVAL).await;
+                        continue;
+                    }
+                    // Start reading the H264 frame using our H264Reader
+                    let mut h264 = H264Reader::new(Cursor::new(bytes), 400 * 1024);
+
+                    // It is important to use a time.Ticker instead of time.Sleep because
+                    // * avoids accumulating skew, just calling time.Sleep didn't compensate for the time spent parsing the data
+                    // * works around latency issues with Sleep
+                    let mut ticker = tokio::time::interval(NAL_INTERVAL);
+                    loop {
+                        let nal = match h264.next_nal() {
+                            Ok(nal) => nal,
+                            Err(webrtc::media::Error::ErrIoEOF) => break,
+                            // The reader can't find the start of the next NAL after it
+                            Err(err) => {
+                                warn!("Skipping the rest of {}: {}", path, err);
+                                failed_frames += 1;
+                                break;
+                            }
+                        };
+                        let size = nal.data.len();
+                        video_track
+                            .write_sample(&Sample {
+                                data: nal.data.freeze(),
+                                duration: Duration::from_secs(1),
+                                ..Default::default()
+                            })
+                            .await?;
+                        // Large NALs wait longer under a bitrate limit, they would fill the queues
+                        let paced = pacer.interval(size, NAL_INTERVAL) - NAL_INTERVAL;
+                        if !paced.is_zero() {
+                            tokio::time::sleep(paced).await;
+                        }
+                        let _ = ticker.tick().await;
+                    }
+                }
+                if failed_frames > 0 {
+                    warn!(
+                        "{} of {} frames were not sent in full, they are malformed",
+                        failed_frames,
+                        layers[layer].frames.len()
+                    );
+                }
+
+                // Without frames it would spin without ever awaiting
+                if !looping || layers[layer].frames.is_empty() {
+                    break;
+                }
+                info!(
+                    "Played all {} frames, starting over",
+                    layers[layer].frames.len()
+                );
             }
-        }
 
-        let _ = video_done_tx.try_send(());
-        Result::Ok(())
+            let _ = video_done_tx.try_send(());
+            Result::Ok(())
+        };
+        tokio::select! {
+            result = stream => result,
+            _ = video_cancel.cancelled() => Result::Ok(()),
+        }
     });
 
     // Set the handler for ICE connection state
@@ -207,6 +752,8 @@ async fn run(session_desc: RTCSessionDescription, path_to_h264_frames: &str) ->
             info!("Connection State has changed {}", connection_state);
             if connection_state == RTCIceConnectionState::Connected {
                 notify_tx.notify_waiters();
+                // Frames got lost while the connection was down
+                let _ = keyframe_tx.try_send(());
             }
             Box::pin(async {})
         },
@@ -234,25 +781,39 @@ async fn run(session_desc: RTCSessionDescription, path_to_h264_frames: &str) ->
     // Create an answer
     let answer = peer_connection.create_answer(None).await?;
 
-    // Create channel that is blocked until ICE Gathering is complete
-    let mut gather_complete = peer_connection.gathering_complete_promise().await;
+    Ok(Session {
+        peer_connection,
+        answer,
+        done_tx: session_done_tx,
+        done_rx,
+        cancel,
+    })
+}
 
-    // Sets the LocalDescription, and starts our UDP listeners
-    peer_connection.set_local_description(answer).await?;
+async fn run(session_desc: RTCSessionDescription, args: &AppArgs) -> Result<()> {
+    let Session {
+        peer_connection,
+        answer,
+        mut done_rx,
+        cancel,
+        ..
+    } = connect(session_desc, args).await?;
+
+    if args.trickle {
+        trickle_ice(&peer_connection, answer).await?;
+    } else {
+        // Create channel that is blocked until ICE Gathering is complete
+        let mut gather_complete = peer_connection.gathering_complete_promise().await;
 
-    // Block until ICE Gathering is complete, disabling trickle ICE
-    // we do this because we only can exchange one signaling message
-    // in a production application you should exchange ICE Candidates via OnICECandidate
-    let _ = gather_complete.recv().await;
+        // Sets the LocalDescription, and starts our UDP listeners
+        peer_connection.set_local_description(answer).await?;
 
-    // Output the answer in base64 so we can paste it in browser
-    if let Some(local_desc) = peer_connection.local_description().await {
-        let json_str = serde_json::to_string(&local_desc)?;
-        let b64 = base64::engine::general_purpose::STANDARD.encode(json_str);
-        info!("Paste below base64 encoded string to `WebRTC base64 Session Description` text area");
-        println!("{}", b64);
-    } else {
-        println!("generate local_description failed!");
+        // Block until ICE Gathering is complete, disabling trickle ICE
+        // we do this because we only can exchange one signaling message
+        // in a production application you should exchange ICE Candidates via OnICECandidate
+        let _ = gather_complete.recv().await;
+
+        print_local_description(&peer_connection).await?;
     }
 
     println!("Press ctrl-c to stop");
@@ -264,11 +825,85 @@ async fn run(session_desc: RTCSessionDescription, path_to_h264_frames: &str) ->
         }
     };
 
+    cancel.cancel();
     peer_connection.close().await?;
 
     Result::Ok(())
 }
 
+async fn print_local_description(peer_connection: &RTCPeerConnection) -> Result<()> {
+    // Output the answer in base64 so we can paste it in browser
+    if let Some(local_desc) = peer_connection.local_description().await {
+        let json_str = serde_json::to_string(&local_desc)?;
+        let b64 = base64::engine::general_purpose::STANDARD.encode(json_str);
+        info!("Paste below base64 encoded string to `WebRTC base64 Session Description` text area");
+        println!("{}", b64);
+    } else {
+        println!("generate local_description failed!");
+    }
+    Ok(())
+}
+
+/// Signals over stdin and stdout: the answer goes first, then every local candidate as soon as
+/// it is gathered. Each line of stdin is a remote candidate.
+async fn trickle_ice(
+    peer_connection: &Arc<RTCPeerConnection>,
+    answer: RTCSessionDescription,
+) -> Result<()> {
+    // Candidates may be gathered before the answer is printed, they wait in the channel
+    let (candidate_tx, mut candidate_rx) =
+        tokio::sync::mpsc::unbounded_channel::<Option<RTCIceCandidate>>();
+    peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+        let _ = candidate_tx.send(candidate);
+        Box::pin(async {})
+    }));
+
+    // Sets the LocalDescription, and starts our UDP listeners
+    peer_connection.set_local_description(answer).await?;
+    print_local_description(peer_connection).await?;
+
+    tokio::spawn(async move {
+        info!("Paste below base64 encoded candidates to `WebRTC base64 ICE Candidates` text area");
+        // `None` marks the end of the gathering
+        while let Some(Some(candidate)) = candidate_rx.recv().await {
+            let json_str = serde_json::to_string(&candidate.to_json()?)?;
+            println!(
+                "{}",
+                base64::engine::general_purpose::STANDARD.encode(json_str)
+            );
+        }
+        info!("ICE gathering is complete");
+        Result::Ok(())
+    });
+
+    let pc = peer_connection.clone();
+    tokio::spawn(async move {
+        let mut lines = AsyncBufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let candidate = base64::engine::general_purpose::STANDARD
+                .decode(line)
+                .map_err(|err| err.to_string())
+                .and_then(|json| {
+                    serde_json::from_slice::<RTCIceCandidateInit>(&json)
+                        .map_err(|err| err.to_string())
+                });
+            match candidate {
+                Ok(candidate) => {
+                    if let Err(err) = pc.add_ice_candidate(candidate).await {
+                        warn!("Failed to add remote ICE candidate: {}", err);
+                    }
+                }
+                Err(err) => warn!("Ignoring malformed remote ICE candidate: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     if env::var_os("RUST_LOG").is_none() {
@@ -285,9 +920,85 @@ async fn main() -> Result<()> {
 
     let args = AppArgs::parse();
 
-    let f = File::open(args.path_local_description_json)?;
+    if let Some(addr) = args.whep_listen.clone() {
+        return whep::serve(args, &addr).await;
+    }
+    // Required by clap without `--whep-listen`
+    let path_local_description_json = args.path_local_description_json.as_deref().unwrap();
+    let f = File::open(path_local_description_json)?;
     let session_desc: RTCSessionDescription = serde_json::from_reader(BufReader::new(f))?;
 
-    run(session_desc, args.path_to_h264_frames.as_str()).await?;
+    run(session_desc, &args).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtcp::payload_feedbacks::full_intra_request::FirEntry;
+    use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+    use webrtc::rtcp::receiver_report::ReceiverReport;
+
+    /// Compound packet of `packets` as the viewer sends it, parsed back like `rtp_sender.read`
+    fn compound(
+        packets: Vec<Box<dyn RtcpPacket + Send + Sync>>,
+    ) -> Vec<Box<dyn RtcpPacket + Send + Sync>> {
+        let mut raw = webrtc::rtcp::packet::marshal(&packets).unwrap();
+        webrtc::rtcp::packet::unmarshal(&mut raw).unwrap()
+    }
+
+    fn receiver_report() -> Box<dyn RtcpPacket + Send + Sync> {
+        Box::new(ReceiverReport {
+            ssrc: 1,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn seeks_past_the_end_dont_overflow() {
+        assert_eq!(seek_frame(1500, 30), 45);
+        assert_eq!(seek_frame(u64::MAX, 30), (u64::MAX / 1000) as usize);
+    }
+
+    #[test]
+    fn picture_loss_indications_request_a_keyframe() {
+        let packets = compound(vec![
+            receiver_report(),
+            Box::new(PictureLossIndication {
+                sender_ssrc: 1,
+                media_ssrc: 2,
+            }),
+        ]);
+        assert!(requests_keyframe(&packets));
+    }
+
+    #[test]
+    fn full_intra_requests_request_a_keyframe() {
+        let packets = compound(vec![
+            receiver_report(),
+            Box::new(FullIntraRequest {
+                sender_ssrc: 1,
+                media_ssrc: 2,
+                fir: vec![FirEntry {
+                    ssrc: 2,
+                    sequence_number: 1,
+                }],
+            }),
+        ]);
+        assert!(requests_keyframe(&packets));
+    }
+
+    #[test]
+    fn other_feedback_doesnt_request_a_keyframe() {
+        let packets = compound(vec![
+            receiver_report(),
+            Box::new(ReceiverEstimatedMaximumBitrate {
+                sender_ssrc: 1,
+                bitrate: 1_000_000.0,
+                ssrcs: vec![2],
+            }),
+        ]);
+        assert!(!requests_keyframe(&packets));
+        assert!(!requests_keyframe(&[]));
+    }
+}
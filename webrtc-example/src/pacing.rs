@@ -0,0 +1,176 @@
+// Keeps the stream under the bitrate the viewer can take, the lower of `--target-bitrate` and the
+// REMB the viewer sends (draft-alvestrand-rmcat-remb)
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info};
+use webrtc::rtcp::packet::Packet as RtcpPacket;
+use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+
+/// The frames of the last second make the bitrate of the stream
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+/// Unused budget is kept for that long, so a keyframe after small frames isn't dropped
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Bitrate of the latest REMB in bits/s, 0 until there is one
+#[derive(Debug, Clone, Default)]
+pub struct RembBitrate(Arc<AtomicU64>);
+
+impl RembBitrate {
+    /// Takes the estimate of the REMB in the RTCP compound packet, if there is one
+    pub fn update(&self, packets: &[Box<dyn RtcpPacket + Send + Sync>]) {
+        for packet in packets {
+            if let Some(remb) = packet
+                .as_any()
+                .downcast_ref::<ReceiverEstimatedMaximumBitrate>()
+            {
+                debug!("Viewer estimates {} bits/s", remb.bitrate);
+                self.0.store(remb.bitrate as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn get(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|bitrate| *bitrate > 0)
+    }
+}
+
+/// What the frame does for the frames after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Decoding can start at it
+    Key,
+    /// Later frames are predicted from it, without it they can't be decoded up to the next
+    /// keyframe
+    Reference,
+    /// No frame is predicted from it, it can be dropped on its own
+    NonReference,
+}
+
+/// H264 frames are non-reference when all their NAL units have `nal_ref_idc` 0, ITU-T H.264
+/// section 7.4.1
+pub fn h264_frame_kind(frame: &[u8]) -> FrameKind {
+    let nals = h264_util::nal::nal_units(frame);
+    if h264_util::nal::VideoCodec::H264.is_keyframe(frame) {
+        FrameKind::Key
+    } else if nals.iter().all(|nal| nal[0] & 0x60 == 0) {
+        FrameKind::NonReference
+    } else {
+        FrameKind::Reference
+    }
+}
+
+/// Token bucket of the bitrate limit. Frames that don't fit in it are dropped, the
+/// non-reference ones alone and the reference ones with all the frames up to the next keyframe.
+/// Keyframes are always sent, the frames after them pay for them.
+pub struct Pacer {
+    target_bitrate: Option<u64>,
+    remb: RembBitrate,
+    /// Bytes that can be sent, negative after a keyframe over the budget. It starts full when
+    /// there is a limit first.
+    budget: Option<f64>,
+    refilled_at: Instant,
+    skipping_to_keyframe: bool,
+    /// Sizes of the recent frames, sent or not
+    frames: VecDeque<(Instant, usize)>,
+    /// Frames dropped since `reported_at`, they are logged once a second
+    dropped_frames: usize,
+    reported_at: Instant,
+}
+
+impl Pacer {
+    pub fn new(target_bitrate: Option<u64>, remb: RembBitrate) -> Self {
+        Self {
+            target_bitrate,
+            remb,
+            budget: None,
+            refilled_at: Instant::now(),
+            skipping_to_keyframe: false,
+            frames: VecDeque::new(),
+            dropped_frames: 0,
+            reported_at: Instant::now(),
+        }
+    }
+
+    /// Limit in bits/s, there is none without `--target-bitrate` and REMB
+    pub fn limit(&self) -> Option<u64> {
+        match (self.target_bitrate, self.remb.get()) {
+            (Some(target), Some(remb)) => Some(target.min(remb)),
+            (target, remb) => target.or(remb),
+        }
+    }
+
+    /// Bitrate of the frames in bits/s, the dropped ones included
+    fn stream_bitrate(&self) -> u64 {
+        let bytes: usize = self.frames.iter().map(|(_, size)| size).sum();
+        bytes as u64 * 8 * 1000 / BITRATE_WINDOW.as_millis() as u64
+    }
+
+    /// Whether the frame of `size` bytes is to be sent
+    pub fn admit(&mut self, size: usize, kind: FrameKind) -> bool {
+        let now = Instant::now();
+        self.frames.push_back((now, size));
+        while self
+            .frames
+            .front()
+            .is_some_and(|(at, _)| now - *at > BITRATE_WINDOW)
+        {
+            self.frames.pop_front();
+        }
+        let elapsed = now - self.refilled_at;
+        self.refilled_at = now;
+        let Some(limit) = self.limit() else {
+            self.budget = None;
+            self.skipping_to_keyframe = false;
+            return true;
+        };
+        let bytes_per_sec = limit as f64 / 8.0;
+        let max_budget = bytes_per_sec * MAX_BURST.as_secs_f64();
+        let budget = match self.budget {
+            Some(budget) => (budget + bytes_per_sec * elapsed.as_secs_f64()).min(max_budget),
+            None => max_budget,
+        };
+
+        let admitted = match kind {
+            FrameKind::Key => {
+                self.skipping_to_keyframe = false;
+                true
+            }
+            _ if self.skipping_to_keyframe => false,
+            _ if budget >= size as f64 => true,
+            FrameKind::Reference => {
+                self.skipping_to_keyframe = true;
+                false
+            }
+            FrameKind::NonReference => false,
+        };
+        self.budget = Some(match admitted {
+            true => budget - size as f64,
+            false => budget,
+        });
+        if !admitted {
+            self.dropped_frames += 1;
+        }
+        if self.dropped_frames > 0 && now - self.reported_at >= BITRATE_WINDOW {
+            info!(
+                "Dropped {} frames, the stream is at {} kbit/s with a limit of {} kbit/s",
+                self.dropped_frames,
+                self.stream_bitrate() / 1000,
+                limit / 1000
+            );
+            self.dropped_frames = 0;
+            self.reported_at = now;
+        }
+        admitted
+    }
+
+    /// Time to wait after sending `size` bytes to stay at the limit, at least `min`
+    pub fn interval(&self, size: usize, min: Duration) -> Duration {
+        match self.limit() {
+            Some(limit) => min.max(Duration::from_secs_f64(size as f64 * 8.0 / limit as f64)),
+            None => min,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// WHEP (WebRTC-HTTP Egress Protocol, RFC 9725) signaling: a player POSTs its SDP offer and gets
+// the answer back, the session is ended with a DELETE of the URL in `Location`
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post};
+use axum::Router;
+use tokio::sync::mpsc;
+use tower_http::cors::CorsLayer;
+use tracing::{info, warn};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+use crate::{connect, AppArgs, ErrorKind, Result, Session};
+
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+
+#[derive(Clone)]
+struct WhepState {
+    args: Arc<AppArgs>,
+    /// `done_tx` of the sessions by their id
+    sessions: Arc<Mutex<HashMap<u64, mpsc::Sender<()>>>>,
+    next_session_id: Arc<AtomicU64>,
+}
+
+pub async fn serve(args: AppArgs, addr: &str) -> Result<()> {
+    let state = WhepState {
+        args: Arc::new(args),
+        sessions: Arc::new(Mutex::new(HashMap::new())),
+        next_session_id: Arc::new(AtomicU64::new(0)),
+    };
+    let app = Router::new()
+        .route("/whep", post(post_offer))
+        .route("/whep/:session_id", delete(delete_session))
+        // Players on other origins need `Location`, permissive exposes all the headers
+        .layer(CorsLayer::permissive())
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("WHEP endpoint is http://{}/whep", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn post_offer(State(state): State<WhepState>, headers: HeaderMap, offer: String) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if !content_type.is_some_and(|v| v.starts_with(SDP_CONTENT_TYPE)) {
+        let message = format!("Expected the offer as {SDP_CONTENT_TYPE}");
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, message).into_response();
+    }
+    let offer = match RTCSessionDescription::offer(offer) {
+        Ok(offer) => offer,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    match answer_offer(&state, offer).await {
+        Ok((session_id, answer)) => {
+            info!("Started WHEP session {}", session_id);
+            let headers = [
+                (header::CONTENT_TYPE, SDP_CONTENT_TYPE.to_string()),
+                (header::LOCATION, format!("/whep/{session_id}")),
+            ];
+            (StatusCode::CREATED, headers, answer).into_response()
+        }
+        Err(err) => {
+            warn!("Failed to answer WHEP offer: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+/// Starts streaming to the player, the answer has all the local candidates in it since WHEP
+/// has a single answer
+async fn answer_offer(state: &WhepState, offer: RTCSessionDescription) -> Result<(u64, String)> {
+    let Session {
+        peer_connection,
+        answer,
+        done_tx,
+        mut done_rx,
+        cancel,
+    } = connect(offer, &state.args).await?;
+    // The session's tasks stop if it fails before its done handler takes over
+    let cancel_on_error = cancel.clone().drop_guard();
+
+    let mut gather_complete = peer_connection.gathering_complete_promise().await;
+    peer_connection.set_local_description(answer).await?;
+    let _ = gather_complete.recv().await;
+    let Some(answer) = peer_connection.local_description().await else {
+        peer_connection.close().await?;
+        Err(ErrorKind::WhepError("No local description".to_string()))?
+    };
+
+    let session_id = state.next_session_id.fetch_add(1, Ordering::Relaxed);
+    state.sessions.lock().unwrap().insert(session_id, done_tx);
+    let sessions = state.sessions.clone();
+    cancel_on_error.disarm();
+    tokio::spawn(async move {
+        let _ = done_rx.recv().await;
+        sessions.lock().unwrap().remove(&session_id);
+        info!("Closing WHEP session {}", session_id);
+        cancel.cancel();
+        if let Err(err) = peer_connection.close().await {
+            warn!("Failed to close WHEP session {}: {}", session_id, err);
+        }
+    });
+    Ok((session_id, answer.sdp))
+}
+
+async fn delete_session(State(state): State<WhepState>, Path(session_id): Path<u64>) -> StatusCode {
+    let done_tx = state.sessions.lock().unwrap().remove(&session_id);
+    match done_tx {
+        Some(done_tx) => {
+            // Full when the session is ending already
+            let _ = done_tx.try_send(());
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}